@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ptouch_decode::capture::{extract_streams, RAW_PRINTING_PORT};
+
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(streams) = extract_streams(data, RAW_PRINTING_PORT) {
+        let total: usize = streams.iter()
+            .map(|stream| stream.to_printer.len() + stream.from_printer.len())
+            .sum();
+        assert!(total <= data.len());
+    }
+});
@@ -0,0 +1,45 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ptouch_decode::command::CommandReader;
+use ptouch_decode::error::DecodeError;
+use ptouch_decode::job::{JobState, Limits};
+
+
+const LIMITS: Limits = Limits {
+    max_width: 560,
+    max_rows_per_page: 10_000,
+    max_pages: 100,
+    max_decompressed_bytes: 1024 * 1024,
+};
+
+
+fn decode(data: &[u8]) -> Result<JobState, DecodeError> {
+    let mut job = JobState::new(LIMITS);
+    for command in CommandReader::new(data)?.with_limits(LIMITS) {
+        job.apply(&command?)?;
+    }
+    Ok(job)
+}
+
+fn decode_leniently(data: &[u8]) -> Result<JobState, DecodeError> {
+    let mut job = JobState::new_lenient(LIMITS);
+    for command in CommandReader::new_lenient(data)?.with_limits(LIMITS) {
+        job.apply(&command?)?;
+    }
+    Ok(job)
+}
+
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(job) = decode(data) {
+        assert!(job.pixel_data_width() <= LIMITS.max_width);
+    }
+
+    // lenient decoding only gives up when a limit is exceeded
+    match decode_leniently(data) {
+        Ok(job) => assert!(job.pixel_data_width() <= LIMITS.max_width),
+        Err(DecodeError::LineTooWide { .. }|DecodeError::TooManyRows { .. }|DecodeError::TooManyPages { .. }|DecodeError::DataTooLarge { .. }) => {},
+        Err(e) => panic!("lenient decoding failed: {}", e),
+    }
+});
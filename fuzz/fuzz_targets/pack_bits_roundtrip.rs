@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ptouch_decode::command::unpack_bits;
+use ptouch_encode::pack_bits::pack_bits;
+
+
+fuzz_target!(|data: &[u8]| {
+    // decompressing arbitrary data must not panic
+    let _ = unpack_bits(data);
+
+    // compressing and decompressing must return the original data
+    let packed = pack_bits(data);
+    let unpacked = unpack_bits(&packed)
+        .expect("failed to decompress compressed data");
+    assert_eq!(unpacked, data);
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ptouch_decode::status::{StatusReply, STATUS_REPLY_LENGTH};
+
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(reply) = StatusReply::parse(data) {
+        assert_eq!(data.len(), STATUS_REPLY_LENGTH);
+        let _ = reply.errors();
+    }
+});
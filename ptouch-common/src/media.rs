@@ -0,0 +1,118 @@
+use std::fmt;
+
+
+/// The type of media in the printer, as given in print information commands and status replies.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum MediaType {
+    NoMedia,
+    LaminatedTape,
+    NonLaminatedTape,
+    ContinuousTape,
+    DieCutLabels,
+    HeatShrinkTube2To1,
+    HeatShrinkTube3To1,
+    Incompatible,
+    Other(u8),
+}
+impl MediaType {
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            0x00 => Self::NoMedia,
+            0x01 => Self::LaminatedTape,
+            0x03 => Self::NonLaminatedTape,
+            0x0A => Self::ContinuousTape,
+            0x0B => Self::DieCutLabels,
+            0x11 => Self::HeatShrinkTube2To1,
+            0x17 => Self::HeatShrinkTube3To1,
+            0xFF => Self::Incompatible,
+            other => Self::Other(other),
+        }
+    }
+
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            Self::NoMedia => 0x00,
+            Self::LaminatedTape => 0x01,
+            Self::NonLaminatedTape => 0x03,
+            Self::ContinuousTape => 0x0A,
+            Self::DieCutLabels => 0x0B,
+            Self::HeatShrinkTube2To1 => 0x11,
+            Self::HeatShrinkTube3To1 => 0x17,
+            Self::Incompatible => 0xFF,
+            Self::Other(other) => *other,
+        }
+    }
+
+    pub fn is_tube(&self) -> bool {
+        matches!(self, Self::HeatShrinkTube2To1 | Self::HeatShrinkTube3To1)
+    }
+}
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoMedia => write!(f, "no media"),
+            Self::LaminatedTape => write!(f, "laminated tape"),
+            Self::NonLaminatedTape => write!(f, "non-laminated tape"),
+            Self::ContinuousTape => write!(f, "continuous-length tape"),
+            Self::DieCutLabels => write!(f, "die-cut labels"),
+            Self::HeatShrinkTube2To1 => write!(f, "heat-shrink tube (2:1)"),
+            Self::HeatShrinkTube3To1 => write!(f, "heat-shrink tube (3:1)"),
+            Self::Incompatible => write!(f, "incompatible media"),
+            Self::Other(other) => write!(f, "unknown media type {:#04X}", other),
+        }
+    }
+}
+
+
+/// The dimensions of a tape width on a specific printer model.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TapeGeometry {
+    /// The width of the tape in millimeters.
+    pub width_mm: u8,
+
+    /// The number of pins that can print onto the tape.
+    pub printable_pins: u16,
+
+    /// The number of unused pins before the first printable pin.
+    pub margin_pins: u16,
+}
+
+
+/// The dimensions of a heat-shrink tube size on a specific printer model.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TubeGeometry {
+    /// The diameter of the tube as given on the cassette, e.g. `"8.8"` for HSe 8.8 mm tube.
+    pub diameter: &'static str,
+
+    /// The media width reported by the printer and given in print information commands.
+    pub width_mm: u8,
+
+    /// The number of pins that can print onto the tube.
+    pub printable_pins: u16,
+
+    /// The number of unused pins before the first printable pin.
+    pub margin_pins: u16,
+
+    /// The shortest length of tube that can be printed.
+    pub min_length_mm: u8,
+}
+
+
+/// The dimensions of a size of die-cut labels on a specific printer model.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct LabelGeometry {
+    /// The width of the labels in millimeters, as given in print information commands.
+    pub width_mm: u8,
+
+    /// The length of each label in millimeters, as given in print information commands.
+    pub length_mm: u8,
+
+    /// The number of pins that can print onto a label.
+    pub printable_pins: u16,
+
+    /// The number of unused pins before the first printable pin.
+    pub margin_pins: u16,
+
+    /// The number of raster lines that fit onto a label at normal resolution.
+    pub printable_lines: u16,
+}
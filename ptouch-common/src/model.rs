@@ -0,0 +1,378 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::media::{LabelGeometry, TapeGeometry, TubeGeometry};
+
+
+const MM_PER_INCH: f64 = 25.4;
+
+
+/// A printer model.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Model {
+    PtE500,
+    PtE550W,
+    PtP700,
+    PtP710Bt,
+    PtP750W,
+    PtP900W,
+    PtP950Nw,
+    Ql800,
+    Ql810W,
+    Ql820Nwb,
+}
+impl Model {
+    pub const ALL: [Model; 10] = [
+        Self::PtE500,
+        Self::PtE550W,
+        Self::PtP700,
+        Self::PtP710Bt,
+        Self::PtP750W,
+        Self::PtP900W,
+        Self::PtP950Nw,
+        Self::Ql800,
+        Self::Ql810W,
+        Self::Ql820Nwb,
+    ];
+
+    pub fn profile(&self) -> &'static ModelProfile {
+        match self {
+            Self::PtE500 => &PT_E500,
+            Self::PtE550W => &PT_E550W,
+            Self::PtP700 => &PT_P700,
+            Self::PtP710Bt => &PT_P710BT,
+            Self::PtP750W => &PT_P750W,
+            Self::PtP900W => &PT_P900W,
+            Self::PtP950Nw => &PT_P950NW,
+            Self::Ql800 => &QL_800,
+            Self::Ql810W => &QL_810W,
+            Self::Ql820Nwb => &QL_820NWB,
+        }
+    }
+}
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.profile().name)
+    }
+}
+impl FromStr for Model {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // accept "PT-E550W", "pt-e550w", "pte550w" and "E550W" (but only "QL-800", "ql800" etc.)
+        fn simplify(name: &str) -> String {
+            let lower = name.to_ascii_lowercase().replace('-', "");
+            match lower.strip_prefix("pt") {
+                Some(rest) => rest.to_owned(),
+                None => lower,
+            }
+        }
+
+        let wanted = simplify(s);
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|model| simplify(model.profile().name) == wanted)
+            .ok_or_else(|| format!("unknown printer model {:?}", s))
+    }
+}
+
+
+/// The dialect of the raster command language that a printer model takes.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum CommandSet {
+    /// P-touch models: raster lines are sent using `G` with a little-endian length and may be
+    /// narrower than the print head; blank lines may be sent using `Z`. The feed margin (`ESC i d`)
+    /// can be chosen freely.
+    PTouch,
+
+    /// QL models: raster lines are sent using `g` with a big-endian length and always span the
+    /// whole print head. Continuous rolls and die-cut labels are announced with their own media
+    /// types; continuous rolls take a fixed margin and die-cut labels none, since the printer finds
+    /// the start of each label by itself.
+    Ql,
+}
+
+
+/// The properties of a printer model that are relevant for generating and checking print jobs.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ModelProfile {
+    pub name: &'static str,
+
+    /// The number of pins on the print head, which is the number of pixels in each raster line.
+    pub head_pins: u16,
+
+    /// The resolution across the tape, which is also the resolution along the tape unless
+    /// high-resolution printing is enabled.
+    pub dpi: u16,
+
+    /// Whether the last page is announced with the page value 2 instead of 1.
+    pub last_page_2: bool,
+
+    /// The shortest label (excluding feed margins) that can be printed onto tape.
+    pub min_length_mm: u8,
+
+    /// The longest feed margin (`ESC i d`) that can be set.
+    pub max_feed_mm: u8,
+
+    /// The raster command dialect of the model.
+    pub command_set: CommandSet,
+
+    /// The bits of the advanced mode settings (`ESC i K`) that this model supports.
+    pub advanced_mode_flags: u8,
+
+    /// Whether the model prints black and red onto two-colour media, taking two-colour raster
+    /// lines (`w`).
+    pub two_color: bool,
+
+    /// The length of tape that is fed out and cut off before the first label of a job, since the
+    /// cutter sits some way ahead of the print head.
+    pub leader_mm: u8,
+
+    /// The speed at which the tape is fed while printing, in millimeters per second.
+    pub print_speed_mm_per_s: u8,
+
+    /// The tape widths supported by this model.
+    pub tapes: &'static [TapeGeometry],
+
+    /// The heat-shrink tube sizes supported by this model.
+    pub tubes: &'static [TubeGeometry],
+
+    /// The die-cut label sizes supported by this model.
+    pub labels: &'static [LabelGeometry],
+}
+impl ModelProfile {
+    /// The resolution along the tape (in the direction in which it is fed).
+    ///
+    /// High-resolution printing doubles the resolution in this direction.
+    pub fn feed_dpi(&self, hi_res: bool) -> u16 {
+        if hi_res {
+            2 * self.dpi
+        } else {
+            self.dpi
+        }
+    }
+
+    /// Returns the geometry of the tape with the given width in millimeters.
+    pub fn tape(&self, width_mm: u8) -> Option<&'static TapeGeometry> {
+        self.tapes
+            .iter()
+            .find(|tape| tape.width_mm == width_mm)
+    }
+
+    /// Returns the geometry of the heat-shrink tube with the given diameter (as given on the
+    /// cassette, e.g. `"8.8"`) or media width in millimeters.
+    pub fn tube(&self, size: &str) -> Option<&'static TubeGeometry> {
+        let size = size.trim_end_matches("mm").trim();
+        self.tubes
+            .iter()
+            .find(|tube| tube.diameter == size || tube.width_mm.to_string() == size)
+    }
+
+    /// Returns the geometry of the heat-shrink tube with the given media width in millimeters.
+    pub fn tube_by_width(&self, width_mm: u8) -> Option<&'static TubeGeometry> {
+        self.tubes
+            .iter()
+            .find(|tube| tube.width_mm == width_mm)
+    }
+
+    /// Returns the geometry of the die-cut labels with the given width and length in millimeters.
+    pub fn label(&self, width_mm: u8, length_mm: u8) -> Option<&'static LabelGeometry> {
+        self.labels
+            .iter()
+            .find(|label| label.width_mm == width_mm && label.length_mm == length_mm)
+    }
+
+    /// Returns the feed margin (`ESC i d`) in dots that the model expects on continuous tape or on
+    /// die-cut labels, or `None` if any margin up to `max_feed_mm` is fine.
+    pub fn required_feed_dots(&self, die_cut: bool) -> Option<u16> {
+        match self.command_set {
+            CommandSet::PTouch => None,
+            CommandSet::Ql if die_cut => Some(0),
+            CommandSet::Ql => Some(QL_CONTINUOUS_MARGIN_DOTS),
+        }
+    }
+
+    /// Converts a length along the tape into a number of raster lines, rounding to the nearest
+    /// line.
+    pub fn mm_to_lines(&self, mm: f64, hi_res: bool) -> usize {
+        let lines = (mm * f64::from(self.feed_dpi(hi_res)) / MM_PER_INCH).round();
+        assert!(lines >= 0.0);
+        lines as usize
+    }
+
+    /// Converts a number of raster lines into a length along the tape.
+    pub fn lines_to_mm(&self, lines: usize, hi_res: bool) -> f64 {
+        (lines as f64) * MM_PER_INCH / f64::from(self.feed_dpi(hi_res))
+    }
+}
+
+
+/// The margin that QL models leave before and after each label on continuous rolls, in dots.
+const QL_CONTINUOUS_MARGIN_DOTS: u16 = 35;
+
+
+/// Tape widths on models with a 128-pin, 180 dpi print head.
+const TAPES_180_DPI: [TapeGeometry; 6] = [
+    TapeGeometry { width_mm: 4, printable_pins: 24, margin_pins: 52 },
+    TapeGeometry { width_mm: 6, printable_pins: 32, margin_pins: 48 },
+    TapeGeometry { width_mm: 9, printable_pins: 50, margin_pins: 39 },
+    TapeGeometry { width_mm: 12, printable_pins: 70, margin_pins: 29 },
+    TapeGeometry { width_mm: 18, printable_pins: 112, margin_pins: 8 },
+    TapeGeometry { width_mm: 24, printable_pins: 128, margin_pins: 0 },
+];
+
+/// Tape widths on models with a 560-pin, 360 dpi print head.
+const TAPES_360_DPI: [TapeGeometry; 7] = [
+    TapeGeometry { width_mm: 4, printable_pins: 48, margin_pins: 256 },
+    TapeGeometry { width_mm: 6, printable_pins: 64, margin_pins: 248 },
+    TapeGeometry { width_mm: 9, printable_pins: 106, margin_pins: 227 },
+    TapeGeometry { width_mm: 12, printable_pins: 150, margin_pins: 205 },
+    TapeGeometry { width_mm: 18, printable_pins: 234, margin_pins: 163 },
+    TapeGeometry { width_mm: 24, printable_pins: 320, margin_pins: 120 },
+    TapeGeometry { width_mm: 36, printable_pins: 454, margin_pins: 53 },
+];
+
+/// HSe tube sizes on models with a 128-pin, 180 dpi print head.
+const TUBES_180_DPI: [TubeGeometry; 5] = [
+    TubeGeometry { diameter: "5.8", width_mm: 6, printable_pins: 28, margin_pins: 50, min_length_mm: 25 },
+    TubeGeometry { diameter: "8.8", width_mm: 9, printable_pins: 48, margin_pins: 40, min_length_mm: 25 },
+    TubeGeometry { diameter: "11.7", width_mm: 12, printable_pins: 66, margin_pins: 31, min_length_mm: 25 },
+    TubeGeometry { diameter: "17.7", width_mm: 18, printable_pins: 106, margin_pins: 11, min_length_mm: 25 },
+    TubeGeometry { diameter: "23.6", width_mm: 24, printable_pins: 128, margin_pins: 0, min_length_mm: 25 },
+];
+
+/// HSe tube sizes on models with a 560-pin, 360 dpi print head.
+const TUBES_360_DPI: [TubeGeometry; 6] = [
+    TubeGeometry { diameter: "5.8", width_mm: 6, printable_pins: 56, margin_pins: 252, min_length_mm: 25 },
+    TubeGeometry { diameter: "8.8", width_mm: 9, printable_pins: 96, margin_pins: 232, min_length_mm: 25 },
+    TubeGeometry { diameter: "11.7", width_mm: 12, printable_pins: 132, margin_pins: 214, min_length_mm: 25 },
+    TubeGeometry { diameter: "17.7", width_mm: 18, printable_pins: 212, margin_pins: 174, min_length_mm: 25 },
+    TubeGeometry { diameter: "23.6", width_mm: 24, printable_pins: 282, margin_pins: 139, min_length_mm: 25 },
+    TubeGeometry { diameter: "31.0", width_mm: 36, printable_pins: 382, margin_pins: 89, min_length_mm: 25 },
+];
+
+/// Continuous DK rolls on models with a 720-pin, 300 dpi print head.
+const ROLLS_300_DPI: [TapeGeometry; 6] = [
+    TapeGeometry { width_mm: 12, printable_pins: 106, margin_pins: 29 },
+    TapeGeometry { width_mm: 29, printable_pins: 306, margin_pins: 6 },
+    TapeGeometry { width_mm: 38, printable_pins: 413, margin_pins: 12 },
+    TapeGeometry { width_mm: 50, printable_pins: 554, margin_pins: 12 },
+    TapeGeometry { width_mm: 54, printable_pins: 590, margin_pins: 0 },
+    TapeGeometry { width_mm: 62, printable_pins: 696, margin_pins: 12 },
+];
+
+/// Die-cut DK labels on models with a 720-pin, 300 dpi print head.
+const LABELS_300_DPI: [LabelGeometry; 10] = [
+    LabelGeometry { width_mm: 17, length_mm: 54, printable_pins: 165, margin_pins: 0, printable_lines: 566 },
+    LabelGeometry { width_mm: 17, length_mm: 87, printable_pins: 165, margin_pins: 0, printable_lines: 956 },
+    LabelGeometry { width_mm: 23, length_mm: 23, printable_pins: 202, margin_pins: 42, printable_lines: 202 },
+    LabelGeometry { width_mm: 29, length_mm: 42, printable_pins: 306, margin_pins: 6, printable_lines: 425 },
+    LabelGeometry { width_mm: 29, length_mm: 90, printable_pins: 306, margin_pins: 6, printable_lines: 991 },
+    LabelGeometry { width_mm: 38, length_mm: 90, printable_pins: 413, margin_pins: 12, printable_lines: 991 },
+    LabelGeometry { width_mm: 39, length_mm: 48, printable_pins: 425, margin_pins: 6, printable_lines: 495 },
+    LabelGeometry { width_mm: 52, length_mm: 29, printable_pins: 578, margin_pins: 0, printable_lines: 271 },
+    LabelGeometry { width_mm: 62, length_mm: 29, printable_pins: 696, margin_pins: 12, printable_lines: 271 },
+    LabelGeometry { width_mm: 62, length_mm: 100, printable_pins: 696, margin_pins: 12, printable_lines: 1109 },
+];
+
+
+pub const PT_E500: ModelProfile = ModelProfile {
+    name: "PT-E500",
+    head_pins: 128,
+    dpi: 180,
+    last_page_2: false,
+    min_length_mm: 5,
+    max_feed_mm: 127,
+    command_set: CommandSet::PTouch,
+    // half cut, no chain printing, special tape, high resolution
+    advanced_mode_flags: 0x04 | 0x08 | 0x10 | 0x40,
+    two_color: false,
+    leader_mm: 25,
+    print_speed_mm_per_s: 30,
+    tapes: &TAPES_180_DPI,
+    tubes: &TUBES_180_DPI,
+    labels: &[],
+};
+
+pub const PT_E550W: ModelProfile = ModelProfile {
+    name: "PT-E550W",
+    ..PT_E500
+};
+
+pub const PT_P700: ModelProfile = ModelProfile {
+    name: "PT-P700",
+    // no half cutter
+    advanced_mode_flags: 0x08 | 0x10 | 0x40,
+    tubes: &[],
+    ..PT_E500
+};
+
+pub const PT_P710BT: ModelProfile = ModelProfile {
+    name: "PT-P710BT",
+    // no half cutter
+    advanced_mode_flags: 0x08 | 0x10 | 0x40,
+    print_speed_mm_per_s: 20,
+    tubes: &[],
+    ..PT_E500
+};
+
+pub const PT_P750W: ModelProfile = ModelProfile {
+    name: "PT-P750W",
+    tubes: &[],
+    ..PT_E500
+};
+
+pub const PT_P900W: ModelProfile = ModelProfile {
+    name: "PT-P900W",
+    head_pins: 560,
+    dpi: 360,
+    last_page_2: true,
+    min_length_mm: 5,
+    max_feed_mm: 127,
+    command_set: CommandSet::PTouch,
+    // draft, half cut, no chain printing, special tape, high resolution, no buffer clearing
+    advanced_mode_flags: 0x01 | 0x04 | 0x08 | 0x10 | 0x40 | 0x80,
+    two_color: false,
+    leader_mm: 25,
+    print_speed_mm_per_s: 60,
+    tapes: &TAPES_360_DPI,
+    tubes: &TUBES_360_DPI,
+    labels: &[],
+};
+
+pub const PT_P950NW: ModelProfile = ModelProfile {
+    name: "PT-P950NW",
+    ..PT_P900W
+};
+
+pub const QL_800: ModelProfile = ModelProfile {
+    name: "QL-800",
+    head_pins: 720,
+    dpi: 300,
+    last_page_2: false,
+    // 12.7 mm, rounded up
+    min_length_mm: 13,
+    // the margin on continuous rolls is fixed anyway
+    max_feed_mm: 3,
+    command_set: CommandSet::Ql,
+    // two-colour printing, cut at end, high resolution
+    advanced_mode_flags: 0x01 | 0x08 | 0x40,
+    two_color: true,
+    // the cutter sits right behind the print head
+    leader_mm: 0,
+    print_speed_mm_per_s: 148,
+    tapes: &ROLLS_300_DPI,
+    tubes: &[],
+    labels: &LABELS_300_DPI,
+};
+
+pub const QL_810W: ModelProfile = ModelProfile {
+    name: "QL-810W",
+    print_speed_mm_per_s: 176,
+    ..QL_800
+};
+
+pub const QL_820NWB: ModelProfile = ModelProfile {
+    name: "QL-820NWB",
+    ..QL_810W
+};
@@ -0,0 +1,242 @@
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::Parser;
+use ptouch_common::model::Model;
+
+use ptouch_decode::command::CommandReader;
+use ptouch_decode::diff::{self, DecodedJob, Page, PageEnd};
+use ptouch_decode::error::DecodeError;
+use ptouch_decode::job::Limits;
+
+
+/// Palette index of a blank pixel in both jobs.
+const PIXEL_WHITE: u8 = 0;
+
+/// Palette index of a pixel printed in both jobs.
+const PIXEL_SAME: u8 = 1;
+
+/// Palette index of a pixel printed only in the old job.
+const PIXEL_REMOVED: u8 = 2;
+
+/// Palette index of a pixel printed only in the new job.
+const PIXEL_ADDED: u8 = 3;
+
+/// Palette index of the line that marks the end of a page.
+const PIXEL_PAGE_END: u8 = 4;
+
+
+#[derive(Parser)]
+struct Opts {
+    #[arg(
+        long,
+        help = "Also accept print data captured in the middle of a job or without an invalidate command.",
+    )]
+    pub lenient: bool,
+
+    #[arg(
+        long,
+        help = concat!(
+            "Also write an image highlighting the changed pixels to this PNG file: pixels printed only",
+            " in the old job are red, those printed only in the new job are green.",
+        ),
+    )]
+    pub diff_image: Option<PathBuf>,
+
+    #[arg(help = "The old print job file.")]
+    pub old_path: PathBuf,
+
+    #[arg(help = "The new print job file.")]
+    pub new_path: PathBuf,
+}
+
+
+fn read_jobs(path: &Path, lenient: bool) -> Result<Vec<DecodedJob>, DecodeError> {
+    // the same limits as the defaults of ptouch-decode
+    let limits = Limits {
+        max_width: Model::ALL
+            .iter()
+            .map(|model| usize::from(model.profile().head_pins))
+            .max()
+            .unwrap(),
+        max_rows_per_page: 1_000_000,
+        max_pages: 10_000,
+        max_decompressed_bytes: 256 * 1024 * 1024,
+    };
+    let file = File::open(path)
+        .map_err(|e| DecodeError::Read { what: "print job file", kind: e.kind() })?;
+    let reader = BufReader::new(file);
+    let commands = if lenient {
+        CommandReader::new_lenient(reader)?
+    } else {
+        CommandReader::new(reader)?
+    };
+    DecodedJob::read_all(commands, limits, lenient)
+}
+
+fn page_end_name(end: PageEnd) -> &'static str {
+    match end {
+        PageEnd::Print => "print (0x0C)",
+        PageEnd::PrintFeed => "print with feed (0x1A)",
+        PageEnd::Unprinted => "not printed",
+    }
+}
+
+/// Describes the differences between two jobs, one per line.
+fn describe_differences(old: &DecodedJob, new: &DecodedJob) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for setting in diff::diff_settings(&old.settings, &new.settings) {
+        lines.push(format!("{}: {} -> {}", setting.name, setting.old, setting.new));
+    }
+
+    if old.ql_raster != new.ql_raster {
+        let raster_command = |job: &DecodedJob| if job.ql_raster { "g" } else { "G" };
+        lines.push(format!("raster command: {} -> {}", raster_command(old), raster_command(new)));
+    }
+
+    if old.pages.len() != new.pages.len() {
+        lines.push(format!("pages: {} -> {}", old.pages.len(), new.pages.len()));
+    }
+
+    for page in diff::diff_pages(old, new) {
+        if let Some((old_rows, new_rows)) = page.row_counts {
+            lines.push(format!("page {}: {} -> {} raster lines", page.page_index + 1, old_rows, new_rows));
+        }
+        if let Some((old_end, new_end)) = page.ends {
+            lines.push(format!("page {}: {} -> {}", page.page_index + 1, page_end_name(old_end), page_end_name(new_end)));
+        }
+        for region in &page.regions {
+            lines.push(format!(
+                "page {}: rows {} to {}, columns {} to {}: {} pixels differ",
+                page.page_index + 1, region.first_row, region.last_row,
+                region.first_column, region.last_column, region.changed_pixels,
+            ));
+        }
+    }
+
+    lines
+}
+
+/// Writes an image of all pages of both print data, one below the other, highlighting the changed
+/// pixels.
+fn write_diff_image(path: &Path, old_jobs: &[DecodedJob], new_jobs: &[DecodedJob]) {
+    // pair up the pages; pages missing on one side compare against a blank page
+    let blank = Page { rows: Vec::new(), red_rows: Vec::new(), information: None, end: PageEnd::Unprinted };
+    let mut page_pairs = Vec::new();
+    for job_index in 0..old_jobs.len().max(new_jobs.len()) {
+        let old_pages = old_jobs.get(job_index).map_or(&[][..], |job| &job.pages);
+        let new_pages = new_jobs.get(job_index).map_or(&[][..], |job| &job.pages);
+        for page_index in 0..old_pages.len().max(new_pages.len()) {
+            page_pairs.push((
+                old_pages.get(page_index).unwrap_or(&blank),
+                new_pages.get(page_index).unwrap_or(&blank),
+            ));
+        }
+    }
+
+    let width = page_pairs
+        .iter()
+        .map(|(old, new)| old.width().max(new.width()))
+        .max()
+        .unwrap_or(0);
+    let height: usize = page_pairs
+        .iter()
+        .map(|(old, new)| old.rows.len().max(new.rows.len()) + 1)
+        .sum();
+    if width == 0 || height == 0 {
+        eprintln!("warning: neither print job contains any pixels; no diff image has been written");
+        return;
+    }
+
+    let png_file = File::create(path)
+        .expect("failed to create PNG file");
+    let mut png_enc = png::Encoder::new(
+        png_file,
+        width.try_into().unwrap(),
+        height.try_into().unwrap(),
+    );
+    png_enc.set_color(png::ColorType::Indexed);
+    png_enc.set_depth(png::BitDepth::Eight);
+    png_enc.set_palette(&[
+        0xFF, 0xFF, 0xFF, // 0 = white (blank in both)
+        0xA0, 0xA0, 0xA0, // 1 = gray (printed in both)
+        0xFF, 0x00, 0x00, // 2 = red (printed only in old)
+        0x00, 0xC0, 0x00, // 3 = green (printed only in new)
+        0x00, 0x00, 0xFF, // 4 = blue (end of page)
+    ]);
+    let png_wr = png_enc.write_header()
+        .expect("failed to write PNG header");
+    let mut png_stream_wr = png_wr.into_stream_writer()
+        .expect("failed to obtain stream writer");
+
+    for (old, new) in page_pairs {
+        for row in 0..old.rows.len().max(new.rows.len()) {
+            let pixels: Vec<u8> = (0..width)
+                // either colour counts as printed
+                .map(|x| match (old.pixel(row, x) || old.red_pixel(row, x), new.pixel(row, x) || new.red_pixel(row, x)) {
+                    (false, false) => PIXEL_WHITE,
+                    (true, true) => PIXEL_SAME,
+                    (true, false) => PIXEL_REMOVED,
+                    (false, true) => PIXEL_ADDED,
+                })
+                .collect();
+            png_stream_wr.write_all(&pixels)
+                .expect("failed to write into PNG stream");
+        }
+        png_stream_wr.write_all(&vec![PIXEL_PAGE_END; width])
+            .expect("failed to write into PNG stream");
+    }
+    png_stream_wr.finish()
+        .expect("failed to finish PNG encoding");
+}
+
+
+fn main() -> ExitCode {
+    let opts = Opts::parse();
+
+    let (old_jobs, new_jobs) = match (read_jobs(&opts.old_path, opts.lenient), read_jobs(&opts.new_path, opts.lenient)) {
+        (Ok(old_jobs), Ok(new_jobs)) => (old_jobs, new_jobs),
+        (Err(e), _) => {
+            eprintln!("error: {}: {}", opts.old_path.display(), e);
+            return ExitCode::from(2);
+        },
+        (_, Err(e)) => {
+            eprintln!("error: {}: {}", opts.new_path.display(), e);
+            return ExitCode::from(2);
+        },
+    };
+
+    let mut differ = false;
+    if old_jobs.len() != new_jobs.len() {
+        println!("jobs: {} -> {}", old_jobs.len(), new_jobs.len());
+        differ = true;
+    }
+    for (job_index, (old_job, new_job)) in old_jobs.iter().zip(&new_jobs).enumerate() {
+        let differences = describe_differences(old_job, new_job);
+        if differences.is_empty() {
+            continue;
+        }
+        if old_jobs.len() > 1 || new_jobs.len() > 1 {
+            println!("job {}:", job_index + 1);
+        }
+        for line in differences {
+            println!("{}", line);
+        }
+        differ = true;
+    }
+
+    if let Some(diff_image) = &opts.diff_image {
+        write_diff_image(diff_image, &old_jobs, &new_jobs);
+    }
+
+    // like diff(1): 0 if the jobs are the same, 1 if they differ, 2 on trouble
+    if differ {
+        ExitCode::from(1)
+    } else {
+        println!("no differences");
+        ExitCode::SUCCESS
+    }
+}
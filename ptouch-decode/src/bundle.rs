@@ -0,0 +1,325 @@
+//! A print job as an editable bundle: a directory with one 1-bit PNG image per page and a JSON file
+//! holding every setting of the job.
+//!
+//! `ptouch-decode --bundle` writes bundles and `ptouch-encode --bundle` turns them back into print
+//! jobs, so captured jobs can be edited (e.g. to change the feed or swap a page) and printed again.
+
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::CompressionMode;
+use crate::diff::{DecodedJob, PageEnd};
+
+
+/// The name of the settings file within a bundle directory.
+pub const SETTINGS_FILE_NAME: &str = "job.json";
+
+
+/// The settings of the mode command (`ESC i M`).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct ModeSettings {
+    pub auto_cut: bool,
+    pub mirror_print: bool,
+}
+impl ModeSettings {
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            auto_cut: byte & 0x40 != 0,
+            mirror_print: byte & 0x80 != 0,
+        }
+    }
+
+    pub fn as_byte(&self) -> u8 {
+        let mut byte = 0x00;
+        if self.auto_cut {
+            byte |= 0x40;
+        }
+        if self.mirror_print {
+            byte |= 0x80;
+        }
+        byte
+    }
+}
+
+
+/// The settings of the advanced mode command (`ESC i K`).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct AdvancedModeSettings {
+    pub draft: bool,
+    pub half_cut: bool,
+    pub no_chain: bool,
+    pub special_tape: bool,
+    pub hi_res: bool,
+    pub dont_clear_print_buffer: bool,
+}
+impl AdvancedModeSettings {
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            draft: byte & 0x01 != 0,
+            half_cut: byte & 0x04 != 0,
+            no_chain: byte & 0x08 != 0,
+            special_tape: byte & 0x10 != 0,
+            hi_res: byte & 0x40 != 0,
+            dont_clear_print_buffer: byte & 0x80 != 0,
+        }
+    }
+
+    pub fn as_byte(&self) -> u8 {
+        let flags = [
+            (self.draft, 0x01),
+            (self.half_cut, 0x04),
+            (self.no_chain, 0x08),
+            (self.special_tape, 0x10),
+            (self.hi_res, 0x40),
+            (self.dont_clear_print_buffer, 0x80),
+        ];
+        flags
+            .iter()
+            .filter(|(set, _bit)| *set)
+            .fold(0x00, |byte, (_set, bit)| byte | bit)
+    }
+}
+
+
+/// The fields of the print information command (`ESC i z`) that announces a page.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct PageInformation {
+    pub printer_recovery: bool,
+
+    /// Whether the quality flag (0x40) is set.
+    #[serde(default)]
+    pub quality: bool,
+
+    pub media_type: Option<u8>,
+    pub media_width: Option<u8>,
+    pub media_length: Option<u8>,
+
+    /// The announced number of raster lines if it differs from the number of raster lines of the
+    /// page; otherwise, the actual number is announced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raster_number: Option<u32>,
+
+    /// 0 for the first page, 1 for other pages, 2 for the last page on some models.
+    pub page_byte: u8,
+}
+impl PageInformation {
+    /// Extracts the fields from the parameters of the command, given the actual number of raster
+    /// lines of the page.
+    pub fn from_bytes(info_buf: &[u8; 10], row_count: usize) -> Self {
+        let raster_number = u32::from_le_bytes(info_buf[4..8].try_into().unwrap());
+        Self {
+            printer_recovery: info_buf[0] & 0x80 != 0,
+            quality: info_buf[0] & 0x40 != 0,
+            media_type: (info_buf[0] & 0x02 != 0).then_some(info_buf[1]),
+            media_width: (info_buf[0] & 0x04 != 0).then_some(info_buf[2]),
+            media_length: (info_buf[0] & 0x08 != 0).then_some(info_buf[3]),
+            raster_number: (usize::try_from(raster_number).ok() != Some(row_count)).then_some(raster_number),
+            page_byte: info_buf[8],
+        }
+    }
+
+    /// Returns the parameters of the command for a page with the given number of raster lines.
+    pub fn to_bytes(&self, row_count: usize) -> [u8; 10] {
+        let mut info_buf = [0u8; 10];
+        if let Some(media_type) = self.media_type {
+            info_buf[0] |= 0x02;
+            info_buf[1] = media_type;
+        }
+        if let Some(media_width) = self.media_width {
+            info_buf[0] |= 0x04;
+            info_buf[2] = media_width;
+        }
+        if let Some(media_length) = self.media_length {
+            info_buf[0] |= 0x08;
+            info_buf[3] = media_length;
+        }
+        if self.quality {
+            info_buf[0] |= 0x40;
+        }
+        if self.printer_recovery {
+            info_buf[0] |= 0x80;
+        }
+        let raster_number = self.raster_number
+            .unwrap_or_else(|| row_count.try_into().expect("too many raster lines on page"));
+        info_buf[4..8].copy_from_slice(&raster_number.to_le_bytes());
+        info_buf[8] = self.page_byte;
+        info_buf
+    }
+}
+
+
+/// A page of a bundle.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct BundlePage {
+    /// The file name of the image of the page within the bundle directory, or `None` if the page
+    /// has no pixels. Each image row is one raster line, in the order in which they are sent.
+    pub image: Option<String>,
+
+    /// The file name of the image holding the red part of each raster line, if the page is printed
+    /// in two colours. Its black pixels are printed in red.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub red_image: Option<String>,
+
+    /// The number of raster lines of a page without an image.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub blank_rows: usize,
+
+    /// Whether the raster lines of the page are sent in two colours (`w`), even if the page has
+    /// no image.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub two_color: bool,
+
+    /// The print information with which the page is announced, if any.
+    pub information: Option<PageInformation>,
+
+    pub end: PageEnd,
+}
+
+
+/// The settings of a print job and its pages.
+///
+/// Settings that are `None` are not sent.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct JobBundle {
+    /// The number of zero bytes of the invalidate command at the start of the job.
+    pub invalidate_length: u64,
+
+    /// `ESC i !`
+    pub auto_status_notification: Option<u8>,
+
+    /// `ESC i M`
+    pub mode: Option<ModeSettings>,
+
+    /// `ESC i K`
+    pub advanced_mode: Option<AdvancedModeSettings>,
+
+    /// `ESC i A`
+    pub cut_every: Option<u8>,
+
+    /// `ESC i d`, in dots
+    pub feed: Option<u16>,
+
+    /// `M`
+    pub compression: Option<CompressionMode>,
+
+    /// Whether raster lines are sent using `g` (as QL models expect) instead of `G`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ql_raster: bool,
+
+    pub pages: Vec<BundlePage>,
+}
+impl JobBundle {
+    /// Writes the decoded job as a bundle into the given directory, which is created if necessary.
+    pub fn write(job: &DecodedJob, dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        // all images are as wide as the widest raster line of the job
+        let width = job.pages
+            .iter()
+            .map(|page| page.width())
+            .max()
+            .unwrap_or(0);
+        let mut pages = Vec::with_capacity(job.pages.len());
+        for (page_index, page) in job.pages.iter().enumerate() {
+            let image = if width > 0 && !page.rows.is_empty() {
+                let image_name = format!("page-{}.png", page_index + 1);
+                write_page_image(&dir.join(&image_name), &page.rows, width)?;
+                Some(image_name)
+            } else {
+                None
+            };
+            let red_image = if image.is_some() && page.is_two_color() {
+                let image_name = format!("page-{}-red.png", page_index + 1);
+                write_page_image(&dir.join(&image_name), &page.red_rows, width)?;
+                Some(image_name)
+            } else {
+                None
+            };
+            pages.push(BundlePage {
+                blank_rows: if image.is_none() { page.rows.len() } else { 0 },
+                two_color: page.is_two_color(),
+                image,
+                red_image,
+                information: page.information.map(|info_buf| PageInformation::from_bytes(&info_buf, page.rows.len())),
+                end: page.end,
+            });
+        }
+
+        let settings = job.settings;
+        let mode = settings.auto_cut.map(|auto_cut| ModeSettings {
+            auto_cut,
+            mirror_print: settings.mirror_print == Some(true),
+        });
+        let advanced_mode = settings.hi_res.map(|hi_res| AdvancedModeSettings {
+            draft: settings.draft == Some(true),
+            half_cut: settings.half_cut == Some(true),
+            no_chain: settings.no_chain == Some(true),
+            special_tape: settings.special_tape == Some(true),
+            hi_res,
+            dont_clear_print_buffer: settings.dont_clean_print_buffer == Some(true),
+        });
+        let bundle = Self {
+            invalidate_length: job.invalidate_length,
+            auto_status_notification: job.auto_status_notification,
+            mode,
+            advanced_mode,
+            cut_every: settings.cut_each_n_labels,
+            feed: settings.feed_amount,
+            compression: job.compression,
+            ql_raster: job.ql_raster,
+            pages,
+        };
+
+        let json = serde_json::to_string_pretty(&bundle)
+            .expect("failed to serialize job settings");
+        std::fs::write(dir.join(SETTINGS_FILE_NAME), json + "\n")?;
+        Ok(bundle)
+    }
+
+    /// Reads the settings of the bundle in the given directory.
+    pub fn read(dir: &Path) -> std::io::Result<Self> {
+        let json = std::fs::read_to_string(dir.join(SETTINGS_FILE_NAME))?;
+        serde_json::from_str(&json)
+            .map_err(std::io::Error::from)
+    }
+}
+
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Writes raster lines as a 1-bit PNG image of the given width.
+fn write_page_image(path: &Path, rows: &[Vec<u8>], width: usize) -> std::io::Result<()> {
+    let png_file = File::create(path)?;
+    let mut png_enc = png::Encoder::new(
+        BufWriter::new(png_file),
+        width.try_into().unwrap(),
+        rows.len().try_into().unwrap(),
+    );
+    // raster line bits can be used as they are with a white-black palette
+    png_enc.set_color(png::ColorType::Indexed);
+    png_enc.set_depth(png::BitDepth::One);
+    png_enc.set_palette(&[
+        0xFF, 0xFF, 0xFF, // 0 = white (medium)
+        0x00, 0x00, 0x00, // 1 = black (marker)
+    ][..]);
+    let mut png_wr = png_enc.write_header()?;
+    let row_bytes = width.div_ceil(8);
+    let mut data = Vec::with_capacity(row_bytes * rows.len());
+    for row in rows {
+        data.extend(row);
+        data.resize(data.len() + row_bytes - row.len(), 0x00);
+    }
+    png_wr.write_image_data(&data)?;
+    png_wr.finish()?;
+    Ok(())
+}
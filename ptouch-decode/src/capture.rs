@@ -0,0 +1,516 @@
+//! Extraction of print data and status replies from packet captures.
+//!
+//! Both the pcap and the pcapng file formats are supported. Print data is collected from USB bulk
+//! transfers (captured with usbmon on Linux or USBPcap on Windows) and from TCP connections to the
+//! raw printing port of a network printer.
+
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::error::DecodeError;
+
+
+/// The port on which network printers accept raw print data.
+pub const RAW_PRINTING_PORT: u16 = 9100;
+
+
+const PCAP_MAGIC_MICROSECONDS: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOSECONDS: u32 = 0xA1B2_3C4D;
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_OBSOLETE_PACKET: u32 = 0x0000_0002;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x0000_0003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_USB_LINUX: u32 = 189;
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_USBPCAP: u32 = 249;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88A8;
+
+const IP_PROTOCOL_TCP: u8 = 6;
+
+const USB_TRANSFER_BULK: u8 = 3;
+const USB_ENDPOINT_IN: u8 = 0x80;
+
+
+/// Returns whether the given data starts like a pcap or pcapng file.
+pub fn is_capture(data: &[u8]) -> bool {
+    if data.len() < 4 {
+        return false;
+    }
+    let magic: [u8; 4] = data[0..4].try_into().unwrap();
+    [PCAP_MAGIC_MICROSECONDS, PCAP_MAGIC_NANOSECONDS, PCAPNG_SECTION_HEADER]
+        .iter()
+        .any(|m| magic == m.to_le_bytes() || magic == m.to_be_bytes())
+}
+
+
+/// Where the data of a captured stream was sent.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum StreamSource {
+    /// A USB device, identified by its bus and device number.
+    Usb { bus: u16, device: u16 },
+
+    /// A TCP connection between a host and the raw printing port of a printer.
+    Tcp { host: SocketAddr, printer: SocketAddr },
+}
+impl fmt::Display for StreamSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usb { bus, device }
+                => write!(f, "USB device {}.{}", bus, device),
+            Self::Tcp { host, printer }
+                => write!(f, "TCP connection from {} to {}", host, printer),
+        }
+    }
+}
+
+
+/// The data exchanged with one printer (or over one connection) in a capture.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CapturedStream {
+    pub source: StreamSource,
+
+    /// The data sent from the host to the printer, i.e. the print data.
+    pub to_printer: Vec<u8>,
+
+    /// The data sent from the printer to the host, i.e. the status replies.
+    pub from_printer: Vec<u8>,
+
+    /// The number of bytes of TCP data that are missing from the capture; the data around them has
+    /// been joined.
+    pub missing_bytes: u64,
+}
+
+
+/// Reads fields from a byte slice in the byte order of the capture file.
+#[derive(Clone, Copy)]
+struct Fields<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+impl<'a> Fields<'a> {
+    fn slice(&self, offset: usize, length: usize) -> Result<&'a [u8], DecodeError> {
+        offset.checked_add(length)
+            .and_then(|end| self.bytes.get(offset..end))
+            .ok_or(DecodeError::InvalidCapture("truncated block or record"))
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, DecodeError> {
+        let bytes = self.slice(offset, 2)?.try_into().unwrap();
+        Ok(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, DecodeError> {
+        let bytes = self.slice(offset, 4)?.try_into().unwrap();
+        Ok(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn usize(&self, offset: usize) -> Result<usize, DecodeError> {
+        Ok(usize::try_from(self.u32(offset)?).unwrap())
+    }
+}
+
+
+/// Bytes of one direction of a TCP connection, ordered by their sequence numbers.
+#[derive(Default)]
+struct TcpHalf {
+    /// The sequence number of the first byte.
+    initial_sequence: Option<u32>,
+
+    /// The segments by their offset from the first byte.
+    segments: BTreeMap<u32, Vec<u8>>,
+}
+impl TcpHalf {
+    fn add(&mut self, sequence: u32, syn: bool, payload: &[u8]) {
+        if syn {
+            // the SYN flag takes up one sequence number
+            self.initial_sequence = Some(sequence.wrapping_add(1));
+            return;
+        }
+        if payload.is_empty() {
+            return;
+        }
+        let initial_sequence = *self.initial_sequence.get_or_insert(sequence);
+        let offset = sequence.wrapping_sub(initial_sequence);
+        if offset > u32::MAX / 2 {
+            // a retransmission of data sent before the capture started
+            return;
+        }
+        let segment = self.segments.entry(offset).or_default();
+        if segment.len() < payload.len() {
+            *segment = payload.to_vec();
+        }
+    }
+
+    /// Joins the segments, dropping retransmitted bytes; returns the data and the number of
+    /// missing bytes.
+    fn reassemble(self) -> (Vec<u8>, u64) {
+        let mut data = Vec::new();
+        let mut missing_bytes = 0;
+        let mut next_offset: u64 = 0;
+        for (offset, segment) in self.segments {
+            let offset = u64::from(offset);
+            let end = offset + u64::try_from(segment.len()).unwrap();
+            if end <= next_offset {
+                continue;
+            }
+            if offset > next_offset {
+                missing_bytes += offset - next_offset;
+                next_offset = offset;
+            }
+            let skip = usize::try_from(next_offset - offset).unwrap();
+            data.extend(&segment[skip..]);
+            next_offset = end;
+        }
+        (data, missing_bytes)
+    }
+}
+
+
+/// Collects the streams while the packets of a capture are being processed.
+struct Extractor {
+    printer_port: u16,
+    sources: Vec<StreamSource>,
+    usb: HashMap<StreamSource, (Vec<u8>, Vec<u8>)>,
+    tcp: HashMap<StreamSource, (TcpHalf, TcpHalf)>,
+}
+impl Extractor {
+    fn note_source(&mut self, source: StreamSource) {
+        if !self.sources.contains(&source) {
+            self.sources.push(source);
+        }
+    }
+
+    fn packet(&mut self, link_type: u32, big_endian: bool, packet: &[u8]) {
+        // packets that cannot be parsed are not ours to complain about
+        match link_type {
+            LINKTYPE_USB_LINUX => self.usbmon(packet, big_endian, 48),
+            LINKTYPE_USB_LINUX_MMAPPED => self.usbmon(packet, big_endian, 64),
+            LINKTYPE_USBPCAP => self.usbpcap(packet),
+            LINKTYPE_ETHERNET => self.ethernet(packet),
+            LINKTYPE_LINUX_SLL if packet.len() >= 16 => {
+                self.ethertype(u16::from_be_bytes([packet[14], packet[15]]), &packet[16..]);
+            },
+            LINKTYPE_LINUX_SLL2 if packet.len() >= 20 => {
+                self.ethertype(u16::from_be_bytes([packet[0], packet[1]]), &packet[20..]);
+            },
+            LINKTYPE_NULL if packet.len() >= 4 => {
+                // address family in the byte order of the capturing host, which is unknown;
+                // the smaller interpretation is the right one
+                let family_bytes: [u8; 4] = packet[0..4].try_into().unwrap();
+                let family = u32::from_le_bytes(family_bytes).min(u32::from_be_bytes(family_bytes));
+                match family {
+                    2 => self.ipv4(&packet[4..]),
+                    24|28|30 => self.ipv6(&packet[4..]),
+                    _ => {},
+                }
+            },
+            LINKTYPE_RAW => match packet.first().map(|b| b >> 4) {
+                Some(4) => self.ipv4(packet),
+                Some(6) => self.ipv6(packet),
+                _ => {},
+            },
+            LINKTYPE_IPV4 => self.ipv4(packet),
+            LINKTYPE_IPV6 => self.ipv6(packet),
+            _ => {},
+        }
+    }
+
+    fn usb_transfer(&mut self, bus: u16, device: u16, endpoint: u8, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let source = StreamSource::Usb { bus, device };
+        self.note_source(source);
+        let (to_printer, from_printer) = self.usb.entry(source).or_default();
+        if endpoint & USB_ENDPOINT_IN == 0 {
+            to_printer.extend(data);
+        } else {
+            from_printer.extend(data);
+        }
+    }
+
+    /// Processes a packet captured by usbmon on Linux.
+    fn usbmon(&mut self, packet: &[u8], big_endian: bool, header_length: usize) {
+        let fields = Fields { bytes: packet, big_endian };
+        let Ok(header) = fields.slice(0, header_length) else { return };
+        let event_type = header[8];
+        let transfer_type = header[9];
+        let endpoint = header[10];
+        let device = u16::from(header[11]);
+        let Ok(bus) = fields.u16(12) else { return };
+        let Ok(captured_length) = fields.usize(36) else { return };
+        if transfer_type != USB_TRANSFER_BULK {
+            return;
+        }
+        // outgoing data is captured when the transfer is submitted, incoming data when it completes
+        let expected_event = if endpoint & USB_ENDPOINT_IN == 0 { b'S' } else { b'C' };
+        if event_type != expected_event {
+            return;
+        }
+        let data = &packet[header_length..];
+        let data = &data[..captured_length.min(data.len())];
+        self.usb_transfer(bus, device, endpoint, data);
+    }
+
+    /// Processes a packet captured by USBPcap on Windows.
+    fn usbpcap(&mut self, packet: &[u8]) {
+        let fields = Fields { bytes: packet, big_endian: false };
+        let Ok(header_length) = fields.u16(0) else { return };
+        let header_length = usize::from(header_length);
+        if header_length < 27 || packet.len() < header_length {
+            return;
+        }
+        let from_device = packet[16] & 0x01 != 0;
+        let Ok(bus) = fields.u16(17) else { return };
+        let Ok(device) = fields.u16(19) else { return };
+        let endpoint = packet[21];
+        let transfer_type = packet[22];
+        if transfer_type != USB_TRANSFER_BULK {
+            return;
+        }
+        // outgoing data is captured with the request, incoming data with the completion
+        if from_device != (endpoint & USB_ENDPOINT_IN != 0) {
+            return;
+        }
+        self.usb_transfer(bus, device, endpoint, &packet[header_length..]);
+    }
+
+    fn ethernet(&mut self, packet: &[u8]) {
+        let mut offset = 12;
+        while packet.len() >= offset + 2 {
+            let ethertype = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+            if ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+                offset += 4;
+                continue;
+            }
+            self.ethertype(ethertype, &packet[offset+2..]);
+            return;
+        }
+    }
+
+    fn ethertype(&mut self, ethertype: u16, payload: &[u8]) {
+        match ethertype {
+            ETHERTYPE_IPV4 => self.ipv4(payload),
+            ETHERTYPE_IPV6 => self.ipv6(payload),
+            _ => {},
+        }
+    }
+
+    fn ipv4(&mut self, packet: &[u8]) {
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return;
+        }
+        let header_length = usize::from(packet[0] & 0x0F) * 4;
+        let total_length = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        if fragment & 0x3FFF != 0 {
+            // fragments (more fragments flag or nonzero offset) are not reassembled
+            return;
+        }
+        if packet[9] != IP_PROTOCOL_TCP || header_length < 20 || total_length < header_length || packet.len() < total_length {
+            return;
+        }
+        let source = IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap()));
+        let destination = IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap()));
+        // the total length also removes Ethernet padding
+        self.tcp(source, destination, &packet[header_length..total_length]);
+    }
+
+    fn ipv6(&mut self, packet: &[u8]) {
+        if packet.len() < 40 || packet[0] >> 4 != 6 {
+            return;
+        }
+        let payload_length = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+        let Some(packet) = packet.get(..40 + payload_length) else { return };
+        let source = IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap()));
+        let destination = IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap()));
+
+        // skip over extension headers (fragments are not reassembled)
+        let mut next_header = packet[6];
+        let mut offset = 40;
+        loop {
+            match next_header {
+                IP_PROTOCOL_TCP => break,
+                0|43|60 => {
+                    // hop-by-hop options, routing, destination options
+                    let Some(header) = packet.get(offset..offset+2) else { return };
+                    next_header = header[0];
+                    offset += (usize::from(header[1]) + 1) * 8;
+                },
+                _ => return,
+            }
+        }
+        let Some(segment) = packet.get(offset..) else { return };
+        self.tcp(source, destination, segment);
+    }
+
+    fn tcp(&mut self, source: IpAddr, destination: IpAddr, segment: &[u8]) {
+        if segment.len() < 20 {
+            return;
+        }
+        let source_port = u16::from_be_bytes([segment[0], segment[1]]);
+        let destination_port = u16::from_be_bytes([segment[2], segment[3]]);
+        let sequence = u32::from_be_bytes(segment[4..8].try_into().unwrap());
+        let data_offset = usize::from(segment[12] >> 4) * 4;
+        let syn = segment[13] & 0x02 != 0;
+        let Some(payload) = segment.get(data_offset..) else { return };
+
+        let to_printer = destination_port == self.printer_port;
+        let (host, printer) = if to_printer {
+            (SocketAddr::new(source, source_port), SocketAddr::new(destination, destination_port))
+        } else if source_port == self.printer_port {
+            (SocketAddr::new(destination, destination_port), SocketAddr::new(source, source_port))
+        } else {
+            return;
+        };
+        let source = StreamSource::Tcp { host, printer };
+        self.note_source(source);
+        let (to_printer_half, from_printer_half) = self.tcp.entry(source).or_default();
+        if to_printer {
+            to_printer_half.add(sequence, syn, payload);
+        } else {
+            from_printer_half.add(sequence, syn, payload);
+        }
+    }
+
+    fn finish(mut self) -> Vec<CapturedStream> {
+        let mut streams = Vec::with_capacity(self.sources.len());
+        for source in self.sources {
+            let stream = if let Some((to_printer, from_printer)) = self.usb.remove(&source) {
+                CapturedStream { source, to_printer, from_printer, missing_bytes: 0 }
+            } else {
+                let (to_printer_half, from_printer_half) = self.tcp.remove(&source).unwrap();
+                let (to_printer, to_printer_missing) = to_printer_half.reassemble();
+                let (from_printer, from_printer_missing) = from_printer_half.reassemble();
+                CapturedStream { source, to_printer, from_printer, missing_bytes: to_printer_missing + from_printer_missing }
+            };
+            if !stream.to_printer.is_empty() || !stream.from_printer.is_empty() {
+                streams.push(stream);
+            }
+        }
+        streams
+    }
+}
+
+
+/// Extracts the streams of printer traffic from a pcap or pcapng file, in the order in which they
+/// first appear.
+///
+/// `printer_port` is the TCP port on which the printer receives print data, usually
+/// [`RAW_PRINTING_PORT`].
+pub fn extract_streams(capture: &[u8], printer_port: u16) -> Result<Vec<CapturedStream>, DecodeError> {
+    let mut extractor = Extractor {
+        printer_port,
+        sources: Vec::new(),
+        usb: HashMap::new(),
+        tcp: HashMap::new(),
+    };
+
+    let magic = Fields { bytes: capture, big_endian: false }.u32(0)?;
+    if magic == PCAPNG_SECTION_HEADER {
+        read_pcapng(capture, &mut extractor)?;
+    } else {
+        read_pcap(capture, &mut extractor)?;
+    }
+    Ok(extractor.finish())
+}
+
+fn read_pcap(capture: &[u8], extractor: &mut Extractor) -> Result<(), DecodeError> {
+    let little = Fields { bytes: capture, big_endian: false };
+    let big_endian = match little.u32(0)? {
+        PCAP_MAGIC_MICROSECONDS|PCAP_MAGIC_NANOSECONDS => false,
+        magic if magic.swap_bytes() == PCAP_MAGIC_MICROSECONDS || magic.swap_bytes() == PCAP_MAGIC_NANOSECONDS => true,
+        _ => return Err(DecodeError::InvalidCapture("unknown file format")),
+    };
+    let fields = Fields { bytes: capture, big_endian };
+    let link_type = fields.u32(20)? & 0x0FFF_FFFF;
+
+    let mut offset = 24;
+    while offset < capture.len() {
+        let captured_length = fields.usize(offset + 8)?;
+        let packet = fields.slice(offset + 16, captured_length)?;
+        extractor.packet(link_type, big_endian, packet);
+        offset += 16 + captured_length;
+    }
+    Ok(())
+}
+
+fn read_pcapng(capture: &[u8], extractor: &mut Extractor) -> Result<(), DecodeError> {
+    let mut big_endian = false;
+    let mut link_types: Vec<u32> = Vec::new();
+
+    let mut offset = 0;
+    while offset < capture.len() {
+        let block_type = Fields { bytes: capture, big_endian }.u32(offset)?;
+        if block_type == PCAPNG_SECTION_HEADER {
+            // a new section, possibly with a different byte order
+            let byte_order_magic = Fields { bytes: capture, big_endian: false }.u32(offset + 8)?;
+            big_endian = match byte_order_magic {
+                PCAPNG_BYTE_ORDER_MAGIC => false,
+                magic if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+                _ => return Err(DecodeError::InvalidCapture("unknown byte order in section header")),
+            };
+            link_types.clear();
+        }
+
+        let fields = Fields { bytes: capture, big_endian };
+        let block_length = fields.usize(offset + 4)?;
+        if block_length < 12 || block_length % 4 != 0 {
+            return Err(DecodeError::InvalidCapture("invalid block length"));
+        }
+        let body = Fields { bytes: fields.slice(offset + 8, block_length - 12)?, big_endian };
+
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                link_types.push(u32::from(body.u16(0)?));
+            },
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = body.usize(0)?;
+                let captured_length = body.usize(12)?;
+                let packet = body.slice(20, captured_length)?;
+                let link_type = *link_types.get(interface)
+                    .ok_or(DecodeError::InvalidCapture("packet on an undescribed interface"))?;
+                extractor.packet(link_type, big_endian, packet);
+            },
+            PCAPNG_OBSOLETE_PACKET => {
+                let interface = usize::from(body.u16(0)?);
+                let captured_length = body.usize(12)?;
+                let packet = body.slice(20, captured_length)?;
+                let link_type = *link_types.get(interface)
+                    .ok_or(DecodeError::InvalidCapture("packet on an undescribed interface"))?;
+                extractor.packet(link_type, big_endian, packet);
+            },
+            PCAPNG_SIMPLE_PACKET => {
+                // simple packets belong to the first interface; the captured length is only
+                // limited by the block length
+                let original_length = body.usize(0)?;
+                let packet = body.slice(4, original_length.min(block_length - 16))?;
+                let link_type = *link_types.first()
+                    .ok_or(DecodeError::InvalidCapture("packet on an undescribed interface"))?;
+                extractor.packet(link_type, big_endian, packet);
+            },
+            _ => {
+                // statistics, name resolution, custom blocks etc.
+            },
+        }
+        offset += block_length;
+    }
+    Ok(())
+}
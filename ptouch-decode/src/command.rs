@@ -0,0 +1,620 @@
+use std::io::{self, BufRead, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::DecodeError;
+use crate::job::Limits;
+
+
+const ESC: u8 = 0x1B;
+
+
+trait BufReadExt {
+    /// Skips over all bytes that equal the given byte.
+    ///
+    /// Returns `Ok(true)` if a byte with a different value was reached and `Ok(false)` on EOF. The
+    /// byte with a different value is not consumed and is read during the next call to one of the
+    /// `read*()` functions.
+    fn skip_while(&mut self, byte: u8) -> Result<bool, io::Error>;
+}
+impl<T: BufRead> BufReadExt for T {
+    fn skip_while(&mut self, byte: u8) -> Result<bool, io::Error> {
+        loop {
+            // fill the reader buffer
+            let my_buf = self.fill_buf()?;
+            if my_buf.is_empty() {
+                // EOF reached
+                return Ok(false);
+            }
+            let until_pos = my_buf
+                .iter()
+                .position(|b| *b != byte)
+                .unwrap_or(my_buf.len());
+            if until_pos == 0 {
+                break;
+            }
+            self.consume(until_pos);
+        }
+        Ok(true)
+    }
+}
+
+
+/// Wraps a reader and counts the bytes that have been consumed from it.
+struct CountingReader<R: BufRead> {
+    inner: R,
+    position: u64,
+}
+impl<R: BufRead> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let count = self.inner.read(buf)?;
+        self.position += u64::try_from(count).unwrap();
+        Ok(count)
+    }
+}
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> Result<&[u8], io::Error> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += u64::try_from(amount).unwrap();
+        self.inner.consume(amount);
+    }
+}
+
+
+/// Whether the given byte can start a command; used to resynchronize in lenient mode.
+///
+/// `g` is left out: in text, the two bytes after it read as the big-endian length of a long raster
+/// line, which would swallow the rest of the print data.
+fn can_start_command(byte: u8) -> bool {
+    matches!(byte, 0x00|ESC|b'M'|b'G'|b'w'|b'Z'|0x0C|0x1A)
+}
+
+
+/// The colour of a two-colour raster graphics transfer (`w`).
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum RasterColor {
+    /// The first colour, printed at high energy.
+    Black,
+
+    /// The second colour, printed at low energy.
+    Red,
+}
+impl RasterColor {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(Self::Black),
+            0x02 => Some(Self::Red),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            Self::Black => 0x01,
+            Self::Red => 0x02,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionMode {
+    #[default] Raw,
+    PackBits,
+}
+
+
+/// A command in a print job.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Command {
+    /// A run of zero bytes (invalidate) after the start of the print data, which begins another
+    /// job.
+    ///
+    /// Zero bytes at the very end of the print data are skipped without producing this command.
+    Invalidate,
+
+    /// `ESC @`
+    Initialize,
+
+    /// `ESC i S`
+    StatusRequest,
+
+    /// `ESC i a`: switch the print data language.
+    SwitchLanguage(u8),
+
+    /// `ESC i z`: print information; always 10 bytes, whose validity is governed by the first byte.
+    PrintInformation([u8; 10]),
+
+    /// `ESC i M`: various mode settings.
+    Mode(u8),
+
+    /// `ESC i A`: cut after every this many labels.
+    CutEvery(u8),
+
+    /// `ESC i K`: advanced mode settings.
+    AdvancedMode(u8),
+
+    /// `ESC i d`: feed amount.
+    Feed(u16),
+
+    /// `ESC i !`: automatic status notification mode.
+    AutoStatusNotification(u8),
+
+    /// `M`: select compression mode.
+    Compression(CompressionMode),
+
+    /// `G`: raster graphics transfer; the data has already been decompressed.
+    Raster(Vec<u8>),
+
+    /// `g`: raster graphics transfer as sent to QL models, whose length is big-endian; the data has
+    /// already been decompressed.
+    QlRaster(Vec<u8>),
+
+    /// `w`: two-colour raster graphics transfer; the data has already been decompressed.
+    ///
+    /// Each colour of a raster line is sent using its own `w` command, black first. A red transfer
+    /// that follows a black one belongs to the same raster line; a colour that has not been sent
+    /// is empty.
+    TwoColorRaster { black: Vec<u8>, red: Vec<u8> },
+
+    /// `Z`: zero raster graphics.
+    ZeroRaster,
+
+    /// `0x0C`: print.
+    Print,
+
+    /// `0x1A`: print with feeding.
+    PrintFeed,
+}
+
+
+/// Returns the number of bytes that PackBits-compressed data decompresses to, without
+/// decompressing it.
+///
+/// Fails if the data ends in the middle of an instruction.
+pub fn unpacked_length(buf: &[u8]) -> Result<usize, DecodeError> {
+    let mut length = 0;
+    let mut pos = 0;
+    while let Some(instruction_u8) = buf.get(pos) {
+        let instruction = i8::from_le_bytes([*instruction_u8]);
+        let (data_length, output_length) = if instruction >= 0 {
+            let literal_byte_count = usize::try_from(instruction).unwrap() + 1;
+            (literal_byte_count, literal_byte_count)
+        } else if instruction == -128 {
+            (0, 0)
+        } else {
+            (1, usize::from(instruction.unsigned_abs()) + 1)
+        };
+        pos += 1 + data_length;
+        if pos > buf.len() {
+            return Err(DecodeError::TruncatedPackBits);
+        }
+        length += output_length;
+    }
+    Ok(length)
+}
+
+/// Decompresses PackBits-compressed data.
+///
+/// Fails if the data ends in the middle of an instruction.
+pub fn unpack_bits(buf: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut ret = Vec::with_capacity(unpacked_length(buf)?);
+
+    let mut iter = buf.iter();
+    while let Some(instruction_u8) = iter.next() {
+        let instruction = i8::from_le_bytes([*instruction_u8]);
+        if instruction >= 0 {
+            let literal_byte_count = usize::try_from(instruction).unwrap() + 1;
+            ret.reserve(literal_byte_count);
+            for _ in 0..literal_byte_count {
+                let literal_byte = iter.next()
+                    .ok_or(DecodeError::TruncatedPackBits)?;
+                ret.push(*literal_byte);
+            }
+        } else if instruction == -128 {
+            // skip
+        } else {
+            // repeated byte
+            let repeat_count = usize::from(instruction.unsigned_abs()) + 1;
+            assert!(repeat_count >= 2);
+            let value = iter.next()
+                .ok_or(DecodeError::TruncatedPackBits)?;
+            ret.resize(ret.len() + repeat_count, *value);
+        }
+    }
+
+    Ok(ret)
+}
+
+
+/// A range of bytes that has been skipped by a lenient [`CommandReader`].
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SkippedBytes {
+    /// The offset of the first skipped byte from the start of the print data.
+    pub offset: u64,
+
+    /// The number of skipped bytes.
+    pub length: u64,
+
+    /// The error that caused the bytes to be skipped.
+    pub reason: DecodeError,
+}
+
+
+/// Reads the commands of a print job one at a time.
+///
+/// The reader keeps track of the compression mode to be able to decompress raster lines; apart
+/// from that, interpreting the commands is left to the caller.
+pub struct CommandReader<R: BufRead> {
+    reader: CountingReader<R>,
+    compression_mode: CompressionMode,
+    expect_initialize: bool,
+    lenient: bool,
+    skipped: Vec<SkippedBytes>,
+    invalidate_length: u64,
+
+    /// A two-colour transfer that has been read while looking for the red transfer of the raster
+    /// line before it.
+    pending_color_transfer: Option<(RasterColor, Vec<u8>)>,
+
+    /// The limits that raster graphics transfers are checked against before they are
+    /// decompressed, if any.
+    limits: Option<Limits>,
+
+    /// The number of pages of the current job that have been printed so far.
+    page_count: usize,
+
+    /// The total length of the decompressed raster lines of the current job so far, in bytes.
+    decompressed_bytes: usize,
+}
+impl<R: BufRead> CommandReader<R> {
+    /// Checks that the print job starts with an invalidate and an initialize command and returns a
+    /// reader positioned after them.
+    pub fn new(reader: R) -> Result<Self, DecodeError> {
+        let mut reader = CountingReader { inner: reader, position: 0 };
+        // read 200 bytes to ensure we have an invalidate command
+        let mut invalidate_buf = vec![0u8; 200];
+        reader.read_exact(&mut invalidate_buf)
+            .map_err(|e| DecodeError::from_io("invalidate command", e))?;
+        if invalidate_buf.iter().any(|b| *b != 0x00) {
+            return Err(DecodeError::MissingInvalidate);
+        }
+
+        // skip over all following 0 bytes
+        reader.skip_while(0x00)
+            .map_err(|e| DecodeError::from_io("long invalidate command", e))?;
+        let invalidate_length = reader.position;
+
+        // read 2 bytes to ensure we start with an initialize command
+        let mut init_buf = [0u8; 2];
+        reader.read_exact(&mut init_buf)
+            .map_err(|e| DecodeError::from_io("init command", e))?;
+        if init_buf[0] != ESC || init_buf[1] != b'@' {
+            return Err(DecodeError::MissingInitialize { found: init_buf });
+        }
+
+        Ok(Self {
+            reader,
+            compression_mode: CompressionMode::Raw,
+            expect_initialize: false,
+            lenient: false,
+            skipped: Vec::new(),
+            invalidate_length,
+            pending_color_transfer: None,
+            limits: None,
+            page_count: 0,
+            decompressed_bytes: 0,
+        })
+    }
+
+    /// Returns a reader that also accepts print data captured in the middle of a job.
+    ///
+    /// The print data may start with an invalidate command of any length, with an initialize
+    /// command or with any other command. Jobs after the first one do not have to start with an
+    /// initialize command. If a command cannot be decoded, the reader skips ahead to the next byte
+    /// that can start a command; the skipped bytes can be obtained using
+    /// [`take_skipped`](Self::take_skipped). Failing to read from the underlying reader is still an
+    /// error.
+    pub fn new_lenient(reader: R) -> Result<Self, DecodeError> {
+        let mut reader = CountingReader { inner: reader, position: 0 };
+        reader.skip_while(0x00)
+            .map_err(|e| DecodeError::from_io("invalidate command", e))?;
+        let invalidate_length = reader.position;
+        Ok(Self {
+            reader,
+            compression_mode: CompressionMode::Raw,
+            expect_initialize: false,
+            lenient: true,
+            skipped: Vec::new(),
+            invalidate_length,
+            pending_color_transfer: None,
+            limits: None,
+            page_count: 0,
+            decompressed_bytes: 0,
+        })
+    }
+
+    /// Checks the width of each raster line and the total size of the raster lines of each job
+    /// against the given limits before decompressing the line.
+    ///
+    /// Without this, a single compressed line of 64 KiB may decompress to several MiB before
+    /// [`JobState`](crate::job::JobState) gets to check it. Exceeding a limit is an error even for
+    /// a lenient reader.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// The number of zero bytes of the most recent invalidate command, i.e. the one at the start of
+    /// the current job.
+    pub fn invalidate_length(&self) -> u64 {
+        self.invalidate_length
+    }
+
+    /// The number of bytes of print data that have been consumed so far.
+    pub fn position(&self) -> u64 {
+        self.reader.position
+    }
+
+    /// Returns the byte ranges that have been skipped since the last call.
+    ///
+    /// Adjacent ranges are merged; the reason is the one of the first range. Only lenient readers
+    /// skip bytes.
+    pub fn take_skipped(&mut self) -> Vec<SkippedBytes> {
+        std::mem::take(&mut self.skipped)
+    }
+
+    /// Records the bytes between `offset` and the current position as skipped.
+    fn record_skipped(&mut self, offset: u64, reason: DecodeError) {
+        let end = self.reader.position;
+        if let Some(last) = self.skipped.last_mut() && last.offset + last.length == offset {
+            last.length = end - last.offset;
+            return;
+        }
+        self.skipped.push(SkippedBytes { offset, length: end - offset, reason });
+    }
+
+    /// Skips bytes until one is reached that can start a command.
+    fn skip_to_command_start(&mut self) -> Result<(), DecodeError> {
+        loop {
+            let buf = self.reader.fill_buf()
+                .map_err(|e| DecodeError::from_io("bytes to skip", e))?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            match buf.iter().position(|b| can_start_command(*b)) {
+                Some(pos) => {
+                    self.reader.consume(pos);
+                    return Ok(());
+                },
+                None => {
+                    let len = buf.len();
+                    self.reader.consume(len);
+                },
+            }
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8], what: &'static str) -> Result<(), DecodeError> {
+        self.reader.read_exact(buf)
+            .map_err(|e| DecodeError::from_io(what, e))
+    }
+
+    fn read_byte(&mut self, what: &'static str) -> Result<u8, DecodeError> {
+        let mut buf = [0u8];
+        self.read_exact(&mut buf, what)?;
+        Ok(buf[0])
+    }
+
+    /// Reads the next command, returning `None` at the end of the print data.
+    ///
+    /// The print data may contain multiple jobs; each job after the first one starts with
+    /// [`Command::Invalidate`] followed by [`Command::Initialize`].
+    pub fn next_command(&mut self) -> Result<Option<Command>, DecodeError> {
+        loop {
+            let offset = self.reader.position;
+            match self.read_command() {
+                Ok(command) => return Ok(command),
+                Err(e) if self.lenient => match e {
+                    DecodeError::Read { .. }|DecodeError::LineTooWide { .. }|DecodeError::DataTooLarge { .. } => return Err(e),
+                    DecodeError::UnexpectedEnd { .. } => {
+                        // the print data is cut off; skip the incomplete command
+                        self.record_skipped(offset, e);
+                        return Ok(None);
+                    },
+                    _ => {
+                        self.skip_to_command_start()?;
+                        self.record_skipped(offset, e);
+                    },
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads the raster data of a raster graphics transfer whose length has been read and
+    /// decompresses it if necessary.
+    fn read_raster_data(&mut self, byte_count: usize) -> Result<Vec<u8>, DecodeError> {
+        let mut raster_buf = vec![0u8; byte_count];
+        self.read_exact(&mut raster_buf, "raster graphics data")?;
+        if self.compression_mode == CompressionMode::PackBits {
+            self.check_raster_length(unpacked_length(&raster_buf)?)?;
+            unpack_bits(&raster_buf)
+        } else {
+            self.check_raster_length(byte_count)?;
+            Ok(raster_buf)
+        }
+    }
+
+    /// Checks a raster graphics transfer that decompresses to the given number of bytes against
+    /// the limits and adds it to the total of the current job.
+    fn check_raster_length(&mut self, length: usize) -> Result<(), DecodeError> {
+        let Some(limits) = &self.limits else {
+            return Ok(())
+        };
+        let width = length * 8;
+        if width > limits.max_width {
+            return Err(DecodeError::LineTooWide { page_index: self.page_count, width, limit: limits.max_width });
+        }
+        self.decompressed_bytes += length;
+        if self.decompressed_bytes > limits.max_decompressed_bytes {
+            return Err(DecodeError::DataTooLarge { limit: limits.max_decompressed_bytes });
+        }
+        Ok(())
+    }
+
+    /// Reads the rest of a two-colour raster graphics transfer after its `w`.
+    fn read_color_transfer(&mut self) -> Result<(RasterColor, Vec<u8>), DecodeError> {
+        let color_byte = self.read_byte("raster graphics color")?;
+        let color = RasterColor::from_byte(color_byte)
+            .ok_or(DecodeError::UnexpectedCommand(vec![b'w', color_byte]))?;
+        let byte_count = self.read_byte("raster graphics transfer length")?;
+        let data = self.read_raster_data(byte_count.into())?;
+        Ok((color, data))
+    }
+
+    /// Assembles a two-colour raster line from its first transfer and, if it is black, the red
+    /// transfer that immediately follows it.
+    fn read_two_color_line(&mut self, color: RasterColor, data: Vec<u8>) -> Result<Command, DecodeError> {
+        if color == RasterColor::Red {
+            return Ok(Command::TwoColorRaster { black: Vec::new(), red: data });
+        }
+        let next_byte = self.reader.fill_buf()
+            .map_err(|e| DecodeError::from_io("next command", e))?
+            .first()
+            .copied();
+        if next_byte != Some(b'w') {
+            return Ok(Command::TwoColorRaster { black: data, red: Vec::new() });
+        }
+        self.reader.consume(1);
+        match self.read_color_transfer()? {
+            (RasterColor::Red, red) => Ok(Command::TwoColorRaster { black: data, red }),
+            next_transfer => {
+                // the black transfer of the next raster line
+                self.pending_color_transfer = Some(next_transfer);
+                Ok(Command::TwoColorRaster { black: data, red: Vec::new() })
+            },
+        }
+    }
+
+    fn read_command(&mut self) -> Result<Option<Command>, DecodeError> {
+        if let Some((color, data)) = self.pending_color_transfer.take() {
+            return self.read_two_color_line(color, data).map(Some);
+        }
+        if self.expect_initialize {
+            // a new job must start with an initialize command
+            self.expect_initialize = false;
+            let mut init_buf = [0u8; 2];
+            self.read_exact(&mut init_buf, "init command")?;
+            if init_buf[0] != ESC || init_buf[1] != b'@' {
+                return Err(DecodeError::MissingInitialize { found: init_buf });
+            }
+            return Ok(Some(Command::Initialize));
+        }
+
+        let mut buf = [0u8];
+        match self.reader.read(&mut buf) {
+            Ok(1) => {},
+            Ok(0) => return Ok(None), // EOF
+            Ok(n) => unreachable!(".read() read {} bytes into a 1-byte buffer?!", n),
+            Err(e) => return Err(DecodeError::from_io("next command", e)),
+        }
+        let command = match buf[0] {
+            0x00 => {
+                // invalidate: most probably a new job
+                let start = self.reader.position - 1;
+                let more_data = self.reader.skip_while(0x00)
+                    .map_err(|e| DecodeError::from_io("invalidate command", e))?;
+                if !more_data {
+                    // trailing padding
+                    return Ok(None);
+                }
+                self.invalidate_length = self.reader.position - start;
+                self.compression_mode = CompressionMode::Raw;
+                self.page_count = 0;
+                self.decompressed_bytes = 0;
+                self.expect_initialize = !self.lenient;
+                Command::Invalidate
+            },
+            ESC => {
+                // control command
+                match self.read_byte("type of escape")? {
+                    b'@' => Command::Initialize,
+                    b'i' => {
+                        // mode settings
+                        match self.read_byte("type of ESC i")? {
+                            b'S' => Command::StatusRequest,
+                            b'a' => Command::SwitchLanguage(self.read_byte("print data language to which to switch")?),
+                            b'z' => {
+                                let mut info_buf = [0u8; 10];
+                                self.read_exact(&mut info_buf, "print information command data")?;
+                                Command::PrintInformation(info_buf)
+                            },
+                            b'M' => Command::Mode(self.read_byte("print mode command data")?),
+                            b'A' => Command::CutEvery(self.read_byte("count data")?),
+                            b'K' => Command::AdvancedMode(self.read_byte("advanced mode command data")?),
+                            b'd' => {
+                                let mut value_buf = [0u8; 2];
+                                self.read_exact(&mut value_buf, "feed amount")?;
+                                Command::Feed(u16::from_le_bytes(value_buf))
+                            },
+                            b'!' => Command::AutoStatusNotification(self.read_byte("automatic status notification mode")?),
+                            other => return Err(DecodeError::UnexpectedCommand(vec![ESC, b'i', other])),
+                        }
+                    },
+                    other => return Err(DecodeError::UnexpectedCommand(vec![ESC, other])),
+                }
+            },
+            b'M' => {
+                // select compression mode
+                self.compression_mode = match self.read_byte("select compression mode data")? {
+                    0x00 => CompressionMode::Raw,
+                    0x02 => CompressionMode::PackBits,
+                    other => return Err(DecodeError::UnsupportedCompression(other)),
+                };
+                Command::Compression(self.compression_mode)
+            },
+            b'G' => {
+                // raster graphics transfer
+                let mut byte_count_buf = [0u8; 2];
+                self.read_exact(&mut byte_count_buf, "raster graphics transfer length")?;
+                let byte_count = usize::from(u16::from_le_bytes(byte_count_buf));
+                Command::Raster(self.read_raster_data(byte_count)?)
+            },
+            b'g' => {
+                // raster graphics transfer (QL)
+                let mut byte_count_buf = [0u8; 2];
+                self.read_exact(&mut byte_count_buf, "raster graphics transfer length")?;
+                let byte_count = usize::from(u16::from_be_bytes(byte_count_buf));
+                Command::QlRaster(self.read_raster_data(byte_count)?)
+            },
+            b'w' => {
+                // two-colour raster graphics transfer
+                let (color, data) = self.read_color_transfer()?;
+                self.read_two_color_line(color, data)?
+            },
+            b'Z' => Command::ZeroRaster,
+            0x0C => {
+                self.page_count += 1;
+                Command::Print
+            },
+            0x1A => {
+                self.page_count += 1;
+                Command::PrintFeed
+            },
+            other => return Err(DecodeError::UnexpectedCommand(vec![other])),
+        };
+        Ok(Some(command))
+    }
+}
+impl<R: BufRead> Iterator for CommandReader<R> {
+    type Item = Result<Command, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_command().transpose()
+    }
+}
@@ -0,0 +1,282 @@
+//! Comparing print jobs at the level of settings, pages and pixels.
+//!
+//! Raster lines are compared after decompression, and missing pixels count as blank, so two jobs
+//! that print the same label compare equal even if one of them compresses differently, sends
+//! narrower raster lines, uses `Z` for blank raster lines or sends them using `g` (as QL models
+//! expect) instead of `G`.
+
+
+use std::io::BufRead;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::{Command, CommandReader, CompressionMode};
+use crate::error::DecodeError;
+use crate::job::{JobSettings, JobState, Limits};
+
+
+/// How a page ends.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PageEnd {
+    /// The page is printed using `0x0C`.
+    Print,
+
+    /// The page is printed using `0x1A`, which also feeds the medium.
+    PrintFeed,
+
+    /// The job ends without printing the page.
+    Unprinted,
+}
+
+
+/// The raster lines of a page.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Page {
+    /// The decompressed raster lines; blank lines sent using `Z` are empty. Of raster lines sent in
+    /// two colours, this is the black part.
+    pub rows: Vec<Vec<u8>>,
+
+    /// The red part of each raster line if any of them has been sent in two colours; otherwise,
+    /// this is empty.
+    pub red_rows: Vec<Vec<u8>>,
+
+    /// The print information (`ESC i z`) with which the page has been announced, if any.
+    pub information: Option<[u8; 10]>,
+
+    pub end: PageEnd,
+}
+impl Page {
+    /// The width of the widest raster line in pixels.
+    pub fn width(&self) -> usize {
+        self.rows.iter()
+            .chain(&self.red_rows)
+            .map(|row| row.len() * 8)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether the page has been sent in two colours.
+    pub fn is_two_color(&self) -> bool {
+        !self.red_rows.is_empty()
+    }
+
+    /// Whether the pixel at the given position is printed in black. Pixels outside of the page are
+    /// blank.
+    pub fn pixel(&self, row: usize, x: usize) -> bool {
+        bit(&self.rows, row, x)
+    }
+
+    /// Whether the pixel at the given position is printed in red.
+    pub fn red_pixel(&self, row: usize, x: usize) -> bool {
+        bit(&self.red_rows, row, x)
+    }
+}
+
+
+fn bit(rows: &[Vec<u8>], row: usize, x: usize) -> bool {
+    rows.get(row)
+        .and_then(|data| data.get(x / 8))
+        .is_some_and(|byte| byte & (0x80 >> (x % 8)) != 0)
+}
+
+
+/// A print job, decoded into its settings and pages.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct DecodedJob {
+    pub settings: JobSettings,
+
+    /// The number of zero bytes of the invalidate command at the start of the job.
+    pub invalidate_length: u64,
+
+    /// The last compression mode (`M`) that has been set, if any.
+    pub compression: Option<CompressionMode>,
+
+    /// The last automatic status notification setting (`ESC i !`), if any.
+    pub auto_status_notification: Option<u8>,
+
+    /// Whether raster lines are sent using `g` (as QL models expect) instead of `G`.
+    pub ql_raster: bool,
+
+    pub pages: Vec<Page>,
+}
+impl DecodedJob {
+    /// Decodes every job in the given print data.
+    ///
+    /// The limits apply to each job separately. The command reader decides whether the commands
+    /// must be valid; the jobs themselves are checked leniently if the reader is lenient.
+    pub fn read_all<R: BufRead>(commands: CommandReader<R>, limits: Limits, lenient: bool) -> Result<Vec<Self>, DecodeError> {
+        let mut commands = commands.with_limits(limits);
+        let new_job = |invalidate_length| Self {
+            settings: JobSettings::default(),
+            invalidate_length,
+            compression: None,
+            auto_status_notification: None,
+            ql_raster: false,
+            pages: Vec::new(),
+        };
+        let new_state = || if lenient { JobState::new_lenient(limits) } else { JobState::new(limits) };
+        let mut jobs = vec![new_job(commands.invalidate_length())];
+        let mut state = new_state();
+        let mut rows = Vec::new();
+        let mut red_rows = Vec::new();
+        let mut information = None;
+        while let Some(command) = commands.next_command()? {
+            let job = jobs.last_mut().unwrap();
+            if command == Command::Invalidate {
+                if !rows.is_empty() {
+                    job.pages.push(take_page(&mut rows, &mut red_rows, information.take(), PageEnd::Unprinted));
+                }
+                job.settings = *std::mem::replace(&mut state, new_state()).settings();
+                jobs.push(new_job(commands.invalidate_length()));
+                continue;
+            }
+            state.apply(&command)?;
+            state.take_violations();
+            match command {
+                Command::PrintInformation(info_buf) => information = Some(info_buf),
+                Command::Compression(mode) => job.compression = Some(mode),
+                Command::AutoStatusNotification(setting) => job.auto_status_notification = Some(setting),
+                Command::Raster(data) => rows.push(data),
+                Command::QlRaster(data) => {
+                    job.ql_raster = true;
+                    rows.push(data);
+                },
+                Command::TwoColorRaster { black, red } => {
+                    // earlier raster lines of the page do not have a red part
+                    red_rows.resize(rows.len(), Vec::new());
+                    rows.push(black);
+                    red_rows.push(red);
+                },
+                Command::ZeroRaster => rows.push(Vec::new()),
+                Command::Print|Command::PrintFeed => {
+                    let end = if command == Command::Print { PageEnd::Print } else { PageEnd::PrintFeed };
+                    job.pages.push(take_page(&mut rows, &mut red_rows, information.take(), end));
+                },
+                _ => {},
+            }
+        }
+        let job = jobs.last_mut().unwrap();
+        if !rows.is_empty() {
+            job.pages.push(take_page(&mut rows, &mut red_rows, information, PageEnd::Unprinted));
+        }
+        job.settings = *state.settings();
+        Ok(jobs)
+    }
+}
+
+
+/// Assembles a page from the raster lines collected so far.
+fn take_page(rows: &mut Vec<Vec<u8>>, red_rows: &mut Vec<Vec<u8>>, information: Option<[u8; 10]>, end: PageEnd) -> Page {
+    if !red_rows.is_empty() {
+        // later raster lines of the page do not have a red part either
+        red_rows.resize(rows.len(), Vec::new());
+    }
+    Page {
+        rows: std::mem::take(rows),
+        red_rows: std::mem::take(red_rows),
+        information,
+        end,
+    }
+}
+
+
+/// A setting that differs between two jobs.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SettingDifference {
+    pub name: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+
+/// A rectangular region of a page with changed pixels, spanning consecutive changed raster lines.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ChangedRegion {
+    pub first_row: usize,
+    pub last_row: usize,
+    pub first_column: usize,
+    pub last_column: usize,
+
+    /// The number of changed pixels within the region.
+    pub changed_pixels: usize,
+}
+
+
+/// The differences between two pages at the same index.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PageDifference {
+    pub page_index: usize,
+
+    /// The number of raster lines of the old and the new page, if they differ.
+    pub row_counts: Option<(usize, usize)>,
+
+    /// How the old and the new page end, if that differs.
+    pub ends: Option<(PageEnd, PageEnd)>,
+
+    pub regions: Vec<ChangedRegion>,
+}
+
+
+/// Returns the settings that differ between two jobs.
+pub fn diff_settings(old: &JobSettings, new: &JobSettings) -> Vec<SettingDifference> {
+    old.describe()
+        .into_iter()
+        .zip(new.describe())
+        .filter(|((_name, old_value), (_new_name, new_value))| old_value != new_value)
+        .map(|((name, old), (_new_name, new))| SettingDifference { name, old, new })
+        .collect()
+}
+
+/// Returns the regions of changed pixels between two pages.
+///
+/// Raster lines beyond the end of the shorter page are compared against blank lines.
+pub fn diff_pixels(old: &Page, new: &Page) -> Vec<ChangedRegion> {
+    let width = old.width().max(new.width());
+    let height = old.rows.len().max(new.rows.len());
+    let mut regions: Vec<ChangedRegion> = Vec::new();
+    for row in 0..height {
+        let changed: Vec<usize> = (0..width)
+            .filter(|x| old.pixel(row, *x) != new.pixel(row, *x) || old.red_pixel(row, *x) != new.red_pixel(row, *x))
+            .collect();
+        let (Some(first_column), Some(last_column)) = (changed.first().copied(), changed.last().copied()) else {
+            continue;
+        };
+        match regions.last_mut() {
+            Some(region) if region.last_row + 1 == row => {
+                region.last_row = row;
+                region.first_column = region.first_column.min(first_column);
+                region.last_column = region.last_column.max(last_column);
+                region.changed_pixels += changed.len();
+            },
+            _ => regions.push(ChangedRegion {
+                first_row: row,
+                last_row: row,
+                first_column,
+                last_column,
+                changed_pixels: changed.len(),
+            }),
+        }
+    }
+    regions
+}
+
+/// Returns the differences between the pages of two jobs that both have a page at the same index.
+///
+/// Pages that are identical are left out.
+pub fn diff_pages(old: &DecodedJob, new: &DecodedJob) -> Vec<PageDifference> {
+    old.pages
+        .iter()
+        .zip(&new.pages)
+        .enumerate()
+        .map(|(page_index, (old_page, new_page))| PageDifference {
+            page_index,
+            row_counts: (old_page.rows.len() != new_page.rows.len())
+                .then_some((old_page.rows.len(), new_page.rows.len())),
+            ends: (old_page.end != new_page.end)
+                .then_some((old_page.end, new_page.end)),
+            regions: diff_pixels(old_page, new_page),
+        })
+        .filter(|difference| difference.row_counts.is_some() || difference.ends.is_some() || !difference.regions.is_empty())
+        .collect()
+}
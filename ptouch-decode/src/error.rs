@@ -0,0 +1,120 @@
+use std::fmt;
+use std::io;
+
+use crate::job::AnnouncedPage;
+
+
+/// An error that stops a print job from being decoded.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DecodeError {
+    /// Reading the print job failed.
+    Read { what: &'static str, kind: io::ErrorKind },
+
+    /// The print job ended in the middle of a command.
+    UnexpectedEnd { what: &'static str },
+
+    /// The print job does not start with at least 200 zero bytes.
+    MissingInvalidate,
+
+    /// The invalidate command is not followed by an initialize command.
+    MissingInitialize { found: [u8; 2] },
+
+    /// An unknown command was encountered; contains the bytes read so far.
+    UnexpectedCommand(Vec<u8>),
+
+    /// The print job selects an unsupported compression mode.
+    UnsupportedCompression(u8),
+
+    /// The print job switches to a print data language other than raster graphics.
+    UnsupportedLanguage(u8),
+
+    /// PackBits-compressed data ends in the middle of an instruction.
+    TruncatedPackBits,
+
+    /// Raster graphics are transferred without raster mode having been entered.
+    RasterWithoutRasterMode,
+
+    /// A page is announced that cannot follow the previously announced pages.
+    UnexpectedPageAnnouncement { page_byte: u8, state: AnnouncedPage },
+
+    /// A raster line is wider than the maximum width.
+    LineTooWide { page_index: usize, width: usize, limit: usize },
+
+    /// A page contains more raster lines than allowed.
+    TooManyRows { page_index: usize, limit: usize },
+
+    /// The print job contains more pages than allowed.
+    TooManyPages { limit: usize },
+
+    /// The raster lines of the print job decompress to more bytes than allowed.
+    DataTooLarge { limit: usize },
+
+    /// A status reply does not have the expected length.
+    StatusReplyLength(usize),
+
+    /// A status reply does not start with the expected header.
+    StatusReplyHeader([u8; 4]),
+
+    /// A packet capture file cannot be read.
+    InvalidCapture(&'static str),
+
+    /// A packet capture file does not contain any traffic to or from a printer.
+    NoPrinterTraffic,
+}
+impl DecodeError {
+    pub(crate) fn from_io(what: &'static str, error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            Self::UnexpectedEnd { what }
+        } else {
+            Self::Read { what, kind: error.kind() }
+        }
+    }
+}
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read { what, kind }
+                => write!(f, "failed to read {}: {}", what, kind),
+            Self::UnexpectedEnd { what }
+                => write!(f, "print data ends while reading {}", what),
+            Self::MissingInvalidate
+                => write!(f, "print data does not start with a valid invalidate command (200 zero bytes)"),
+            Self::MissingInitialize { found }
+                => write!(f, "first command is not init but {:#04X} {:#04X}", found[0], found[1]),
+            Self::UnexpectedCommand(bytes)
+                => write!(f, "unexpected command {:02X?}", bytes),
+            Self::UnsupportedCompression(mode)
+                => write!(f, "unsupported compression mode: {:#04X}", mode),
+            Self::UnsupportedLanguage(0)
+                => write!(f, "attempting to switch to ESC/P which we do not support"),
+            Self::UnsupportedLanguage(3)
+                => write!(f, "attempting to switch to P-touch Template Mode which we do not support"),
+            Self::UnsupportedLanguage(other)
+                => write!(f, "unknown print data language {:#04X}", other),
+            Self::TruncatedPackBits
+                => write!(f, "PackBits data ends in the middle of an instruction"),
+            Self::RasterWithoutRasterMode
+                => write!(f, "raster graphics transfer without raster mode entered"),
+            Self::UnexpectedPageAnnouncement { page_byte, state }
+                => write!(f, "page announcement byte {:#04X} in state {:?}", page_byte, state),
+            Self::LineTooWide { page_index, width, limit }
+                => write!(f, "page at index {} contains a raster line {} pixels wide; the limit is {} pixels", page_index, width, limit),
+            Self::TooManyRows { page_index, limit }
+                => write!(f, "page at index {} contains more than {} raster lines", page_index, limit),
+            Self::TooManyPages { limit }
+                => write!(f, "print job contains more than {} pages", limit),
+            Self::DataTooLarge { limit }
+                => write!(f, "raster lines decompress to more than {} bytes", limit),
+            Self::StatusReplyLength(length)
+                => write!(f, "status reply is {} bytes long; expected 32", length),
+            Self::StatusReplyHeader(header)
+                => write!(f, "status reply has unexpected header {:02X?}", header),
+            Self::InvalidCapture(problem)
+                => write!(f, "invalid packet capture: {}", problem),
+            Self::NoPrinterTraffic
+                => write!(f, "packet capture contains no USB bulk transfers or raw printing connections"),
+        }
+    }
+}
+impl std::error::Error for DecodeError {
+}
@@ -0,0 +1,256 @@
+//! Guessing which printer model a print job was generated for.
+//!
+//! Drivers generate print jobs for a specific printer model. Some traits of a job follow from the
+//! properties of the model (e.g. the width of the raster lines from the number of pins on the print
+//! head); others (e.g. the length of the invalidate command and the order of the setup commands)
+//! are rather traits of the driver.
+
+
+use ptouch_common::media::MediaType;
+use ptouch_common::model::{CommandSet, Model};
+
+use crate::command::{Command, CompressionMode};
+
+
+/// The traits of a print job that hint at the printer model and the driver.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Evidence {
+    /// The number of zero bytes of the invalidate command at the start of the job, if known.
+    pub invalidate_length: Option<u64>,
+
+    /// The setup commands (i.e. neither raster lines nor print commands nor the initialize command
+    /// which starts every job) in the order in which they first appear.
+    pub setup_commands: Vec<&'static str>,
+
+    /// The length of the longest raster line in bytes.
+    pub widest_line_bytes: usize,
+
+    /// The page byte of the first page announcement (`ESC i z`).
+    pub first_page_byte: Option<u8>,
+
+    /// The page byte of the last page announcement.
+    pub last_page_byte: Option<u8>,
+
+    /// The number of page announcements.
+    pub page_announcements: usize,
+
+    /// The media type announced last.
+    pub media_type: Option<u8>,
+
+    /// The media width announced last, in millimeters.
+    pub media_width: Option<u8>,
+
+    /// The media length announced last, in millimeters.
+    pub media_length: Option<u8>,
+
+    /// Whether raster lines are sent using `G`.
+    pub ptouch_raster: bool,
+
+    /// Whether raster lines are sent using `g`.
+    pub ql_raster: bool,
+
+    /// Whether raster lines are sent in two colours (`w`).
+    pub two_color: bool,
+}
+impl Evidence {
+    /// Records the traits of the given command.
+    pub fn observe(&mut self, command: &Command) {
+        if let Some(name) = setup_command_name(command) && !self.setup_commands.contains(&name) {
+            self.setup_commands.push(name);
+        }
+        match command {
+            Command::PrintInformation(info_buf) => {
+                self.first_page_byte.get_or_insert(info_buf[8]);
+                self.last_page_byte = Some(info_buf[8]);
+                self.page_announcements += 1;
+                if info_buf[0] & 0x02 != 0 {
+                    self.media_type = Some(info_buf[1]);
+                }
+                if info_buf[0] & 0x04 != 0 {
+                    self.media_width = Some(info_buf[2]);
+                }
+                if info_buf[0] & 0x08 != 0 {
+                    self.media_length = Some(info_buf[3]);
+                }
+            },
+            Command::Raster(data) => {
+                self.widest_line_bytes = self.widest_line_bytes.max(data.len());
+                self.ptouch_raster = true;
+            },
+            Command::QlRaster(data) => {
+                self.widest_line_bytes = self.widest_line_bytes.max(data.len());
+                self.ql_raster = true;
+            },
+            Command::TwoColorRaster { black, red } => {
+                self.widest_line_bytes = self.widest_line_bytes.max(black.len()).max(red.len());
+                self.two_color = true;
+            },
+            _ => {},
+        }
+    }
+
+    /// Weighs the evidence for and against each known printer model.
+    ///
+    /// The guesses are ordered from the most to the least likely model.
+    pub fn guess_models(&self) -> Vec<ModelGuess> {
+        let mut guesses: Vec<ModelGuess> = Model::ALL
+            .iter()
+            .map(|model| self.weigh(*model))
+            .collect();
+        guesses.sort_by_key(|guess| (guess.contradicting.len(), usize::MAX - guess.supporting.len()));
+        guesses
+    }
+
+    fn weigh(&self, model: Model) -> ModelGuess {
+        let profile = model.profile();
+        let mut guess = ModelGuess {
+            model,
+            supporting: Vec::new(),
+            contradicting: Vec::new(),
+        };
+
+        // drivers usually send raster lines as wide as the print head; narrower lines are accepted
+        // but wider ones are not
+        let head_bytes = usize::from(profile.head_pins) / 8;
+        if self.widest_line_bytes == head_bytes {
+            guess.supporting.push(format!("raster lines are {} bytes wide, matching its {}-pin print head", head_bytes, profile.head_pins));
+        } else if self.widest_line_bytes > head_bytes {
+            guess.contradicting.push(format!(
+                "raster lines are {} bytes wide, but its {}-pin print head only takes {} bytes",
+                self.widest_line_bytes, profile.head_pins, head_bytes,
+            ));
+        }
+
+        // the last (or only) page is announced with 2 on some models
+        match self.last_page_byte {
+            Some(2) if profile.last_page_2 => guess.supporting.push("the last page is announced with page byte 2".to_owned()),
+            Some(2) => guess.contradicting.push("the last page is announced with page byte 2, which it does not expect".to_owned()),
+            Some(byte) if profile.last_page_2 => guess.contradicting.push(format!("the last page is announced with page byte {} instead of 2", byte)),
+            Some(byte) => guess.supporting.push(format!("the last page is announced with page byte {}, not 2", byte)),
+            None => {},
+        }
+
+        // P-touch and QL models take different raster commands
+        let (raster_command, foreign_raster) = match profile.command_set {
+            CommandSet::PTouch => ("G", self.ql_raster.then_some("g")),
+            CommandSet::Ql => ("g", self.ptouch_raster.then_some("G")),
+        };
+        if let Some(foreign_raster) = foreign_raster {
+            guess.contradicting.push(format!("raster lines are sent using {}, which it does not take", foreign_raster));
+        } else if self.ptouch_raster || self.ql_raster {
+            guess.supporting.push(format!("raster lines are sent using {}", raster_command));
+        }
+
+        // only some models print in two colours
+        if self.two_color {
+            if profile.two_color {
+                guess.supporting.push("prints two-colour raster lines".to_owned());
+            } else {
+                guess.contradicting.push("raster lines are sent in two colours, which it cannot print".to_owned());
+            }
+        }
+
+        // the media must be supported
+        if let Some(width) = self.media_width {
+            let media_type = self.media_type.map(MediaType::from_byte);
+            let (supported, medium) = match (media_type, self.media_length) {
+                (Some(mt), _) if mt.is_tube() => (profile.tube_by_width(width).is_some(), format!("{} mm heat-shrink tube", width)),
+                (Some(MediaType::DieCutLabels), Some(length)) => (profile.label(width, length).is_some(), format!("{}x{} mm die-cut labels", width, length)),
+                _ => (profile.tape(width).is_some(), format!("{} mm tape", width)),
+            };
+            if supported {
+                guess.supporting.push(format!("supports {}", medium));
+            } else {
+                guess.contradicting.push(format!("does not support {}", medium));
+            }
+        }
+
+        guess
+    }
+
+    /// Whether the job looks like it has been generated by `ptouch-encode`, which sends a 350-byte
+    /// invalidate command and its setup commands in a fixed order.
+    pub fn matches_ptouch_encode(&self) -> bool {
+        let expected_order = ["ESC i a", "ESC i M", "ESC i K", "ESC i A", "ESC i d", "M 0x00", "M 0x02", "ESC i z"];
+        let in_expected_order = self.setup_commands
+            .iter()
+            .map(|name| expected_order.iter().position(|expected| expected == name))
+            .try_fold(0, |previous, position| position.filter(|p| *p >= previous));
+        self.invalidate_length == Some(350) && in_expected_order.is_some()
+    }
+
+    /// Outputs the evidence and the most likely printer models.
+    pub fn print_report(&self) {
+        let guesses = self.guess_models();
+        match self.invalidate_length {
+            Some(length) => println!("invalidate length: {} bytes", length),
+            None => println!("invalidate length: unknown"),
+        }
+        println!("setup commands: {}", self.setup_commands.join(", "));
+        println!("widest raster line: {} bytes ({} pixels)", self.widest_line_bytes, self.widest_line_bytes * 8);
+        match (self.first_page_byte, self.last_page_byte) {
+            (Some(first), Some(last)) => println!(
+                "page announcements: {} (first with page byte {}, last with page byte {})",
+                self.page_announcements, first, last,
+            ),
+            _ => println!("page announcements: none"),
+        }
+        if self.matches_ptouch_encode() {
+            println!("driver: invalidate length and command order match ptouch-encode");
+        }
+
+        let fewest_contradictions = guesses[0].contradicting.len();
+        let likely: Vec<String> = guesses
+            .iter()
+            .take_while(|guess| guess.contradicting.len() == fewest_contradictions)
+            .map(|guess| guess.model.to_string())
+            .collect();
+        if fewest_contradictions == 0 {
+            println!("likely models: {}", likely.join(", "));
+        } else {
+            println!("no model matches all evidence; closest: {}", likely.join(", "));
+        }
+        for guess in &guesses {
+            for reason in &guess.supporting {
+                println!("  {}: + {}", guess.model, reason);
+            }
+            for reason in &guess.contradicting {
+                println!("  {}: - {}", guess.model, reason);
+            }
+        }
+    }
+}
+
+
+/// How well a print job matches a printer model.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ModelGuess {
+    pub model: Model,
+
+    /// The traits of the job that are expected for this model.
+    pub supporting: Vec<String>,
+
+    /// The traits of the job that this model does not expect or support.
+    pub contradicting: Vec<String>,
+}
+
+
+/// Returns the name of a setup command or `None` for raster lines, print commands, invalidate and
+/// initialize.
+pub fn setup_command_name(command: &Command) -> Option<&'static str> {
+    let name = match command {
+        Command::Invalidate|Command::Initialize => return None,
+        Command::Raster(_)|Command::QlRaster(_)|Command::TwoColorRaster { .. }|Command::ZeroRaster|Command::Print|Command::PrintFeed => return None,
+        Command::StatusRequest => "ESC i S",
+        Command::SwitchLanguage(_) => "ESC i a",
+        Command::PrintInformation(_) => "ESC i z",
+        Command::Mode(_) => "ESC i M",
+        Command::CutEvery(_) => "ESC i A",
+        Command::AdvancedMode(_) => "ESC i K",
+        Command::Feed(_) => "ESC i d",
+        Command::AutoStatusNotification(_) => "ESC i !",
+        Command::Compression(CompressionMode::Raw) => "M 0x00",
+        Command::Compression(CompressionMode::PackBits) => "M 0x02",
+    };
+    Some(name)
+}
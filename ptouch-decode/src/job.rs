@@ -0,0 +1,382 @@
+use ptouch_common::media::MediaType;
+use ptouch_common::model::{CommandSet, Model};
+
+use crate::command::Command;
+use crate::error::DecodeError;
+use crate::fingerprint::Evidence;
+
+
+/// The last kind of page announced using `ESC i z`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum AnnouncedPage {
+    #[default] BeforeFirst,
+    First,
+    Other,
+    Last,
+}
+
+
+/// Upper bounds on the size of a print job, protecting against corrupt or hostile input.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Limits {
+    /// The widest raster line, in pixels (pins).
+    pub max_width: usize,
+
+    /// The largest number of raster lines per page.
+    pub max_rows_per_page: usize,
+
+    /// The largest number of pages.
+    pub max_pages: usize,
+
+    /// The largest number of bytes that the raster lines of the whole print job may decompress to.
+    pub max_decompressed_bytes: usize,
+}
+
+
+/// The settings of a print job, as far as they have been given.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct JobSettings {
+    pub media_type: Option<u8>,
+    pub media_width: Option<u8>,
+    pub media_length: Option<u8>,
+    pub raster_number: Option<u32>,
+    pub printer_recovery: Option<bool>,
+    pub auto_cut: Option<bool>,
+    pub mirror_print: Option<bool>,
+    pub draft: Option<bool>,
+    pub half_cut: Option<bool>,
+    pub no_chain: Option<bool>,
+    pub special_tape: Option<bool>,
+    pub hi_res: Option<bool>,
+    pub dont_clean_print_buffer: Option<bool>,
+    pub feed_amount: Option<u16>,
+    pub cut_each_n_labels: Option<u8>,
+
+    /// The dialect of the first raster line, which decides what some of the advanced mode flags
+    /// mean.
+    pub command_set: Option<CommandSet>,
+}
+impl JobSettings {
+    /// Returns the name and a human-readable value of each setting.
+    pub fn describe(&self) -> Vec<(&'static str, String)> {
+        fn flag(value: Option<bool>) -> String {
+            match value {
+                Some(true) => "yes".to_owned(),
+                Some(false) => "no".to_owned(),
+                None => "not set".to_owned(),
+            }
+        }
+        fn number<T: std::fmt::Display>(value: Option<T>) -> String {
+            match value {
+                Some(v) => v.to_string(),
+                None => "not set".to_owned(),
+            }
+        }
+        // QL models give two of the advanced mode bits another meaning
+        let (draft_name, no_chain_name) = match self.command_set {
+            Some(CommandSet::Ql) => ("two-colour printing", "cut at end"),
+            Some(CommandSet::PTouch)|None => ("draft", "no chain printing"),
+        };
+        vec![
+            ("auto cut", flag(self.auto_cut)),
+            ("mirror print", flag(self.mirror_print)),
+            (draft_name, flag(self.draft)),
+            ("half cut", flag(self.half_cut)),
+            (no_chain_name, flag(self.no_chain)),
+            ("special tape", flag(self.special_tape)),
+            ("high resolution", flag(self.hi_res)),
+            ("don't clear print buffer", flag(self.dont_clean_print_buffer)),
+            ("feed amount", number(self.feed_amount)),
+            ("cut after every n labels", number(self.cut_each_n_labels)),
+            ("printer recovery", flag(self.printer_recovery)),
+            ("media type", number(self.media_type.map(MediaType::from_byte))),
+            ("media width", number(self.media_width.map(|w| format!("{} mm", w)))),
+            ("media length", number(self.media_length.map(|l| format!("{} mm", l)))),
+            ("raster lines announced for last page", number(self.raster_number)),
+        ]
+    }
+}
+
+
+/// The settings of a print job and the dimensions of its rendering, collected while its commands
+/// are being read.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct JobState {
+    limits: Limits,
+
+    raster_mode: bool,
+    page_state: AnnouncedPage,
+    settings: JobSettings,
+
+    /// The width of the rendering in pixels, which is the width of the longest raster line.
+    pixel_data_width: usize,
+
+    /// The height of the rendering in pixels: one per raster line and one per print command.
+    height: usize,
+
+    /// The number of pages that have been printed so far, which is also the index of the current
+    /// page.
+    page_count: usize,
+
+    /// The number of raster lines of the current page so far.
+    page_rows: usize,
+
+    /// The number of raster lines of each page that has been printed so far.
+    printed_page_rows: Vec<usize>,
+
+    /// The total length of all raster lines so far, in bytes.
+    decompressed_bytes: usize,
+
+    /// Whether commands that are not valid in the current state are accepted anyway.
+    lenient: bool,
+
+    /// The invalid commands that have been accepted in lenient mode since the last call to
+    /// [`take_violations`](Self::take_violations).
+    violations: Vec<DecodeError>,
+
+    /// The traits of the job that hint at the printer model.
+    evidence: Evidence,
+}
+impl JobState {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            raster_mode: false,
+            page_state: AnnouncedPage::default(),
+            settings: JobSettings::default(),
+            pixel_data_width: 0,
+            height: 0,
+            page_count: 0,
+            page_rows: 0,
+            printed_page_rows: Vec::new(),
+            decompressed_bytes: 0,
+            lenient: false,
+            violations: Vec::new(),
+            evidence: Evidence::default(),
+        }
+    }
+
+    /// Returns a job state that accepts commands that are not valid in the current state, as is
+    /// common in print data captured in the middle of a job.
+    ///
+    /// Such commands take effect as well as possible and are recorded as violations. The limits
+    /// are still enforced.
+    pub fn new_lenient(limits: Limits) -> Self {
+        Self {
+            lenient: true,
+            ..Self::new(limits)
+        }
+    }
+
+    pub fn pixel_data_width(&self) -> usize { self.pixel_data_width }
+    pub fn height(&self) -> usize { self.height }
+    pub fn page_count(&self) -> usize { self.page_count }
+    /// The number of raster lines sent since the last print command.
+    pub fn pending_rows(&self) -> usize { self.page_rows }
+    /// The number of raster lines of each printed page.
+    pub fn printed_page_rows(&self) -> &[usize] { &self.printed_page_rows }
+    pub fn settings(&self) -> &JobSettings { &self.settings }
+    pub fn evidence(&self) -> &Evidence { &self.evidence }
+
+    /// Records the length of the invalidate command at the start of the job, which the command
+    /// reader knows but does not pass on as a command.
+    pub fn set_invalidate_length(&mut self, length: u64) {
+        self.evidence.invalidate_length = Some(length);
+    }
+
+    /// Returns the invalid commands that have been accepted since the last call.
+    pub fn take_violations(&mut self) -> Vec<DecodeError> {
+        std::mem::take(&mut self.violations)
+    }
+
+    /// Fails with the given error or, in lenient mode, records it as a violation.
+    fn violation(&mut self, error: DecodeError) -> Result<(), DecodeError> {
+        if self.lenient {
+            self.violations.push(error);
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+
+    /// Counts a raster line of the given width in pixels towards the limits.
+    fn add_row(&mut self, width: usize) -> Result<(), DecodeError> {
+        if width > self.limits.max_width {
+            return Err(DecodeError::LineTooWide { page_index: self.page_count, width, limit: self.limits.max_width });
+        }
+        if self.page_rows >= self.limits.max_rows_per_page {
+            return Err(DecodeError::TooManyRows { page_index: self.page_count, limit: self.limits.max_rows_per_page });
+        }
+        self.page_rows += 1;
+        self.pixel_data_width = self.pixel_data_width.max(width);
+        self.height += 1;
+        Ok(())
+    }
+
+    /// Updates the state according to the given command.
+    ///
+    /// Fails if the command is not valid in the current state or the print job exceeds one of the
+    /// limits.
+    pub fn apply(&mut self, command: &Command) -> Result<(), DecodeError> {
+        self.evidence.observe(command);
+        match command {
+            Command::Invalidate => {
+                // job boundaries are handled by the caller
+            },
+            Command::Initialize => {
+                // reinitialize again?
+
+                // raster_mode does not change
+                self.settings.media_type = None;
+                self.settings.media_width = None;
+                self.settings.media_length = None;
+                self.settings.raster_number = None;
+                self.settings.printer_recovery = None;
+                self.page_state = AnnouncedPage::BeforeFirst;
+            },
+            Command::StatusRequest => {
+                // nothing to do for us here
+            },
+            Command::SwitchLanguage(language) => {
+                if *language == 1 {
+                    self.raster_mode = true;
+                } else {
+                    self.violation(DecodeError::UnsupportedLanguage(*language))?;
+                }
+            },
+            Command::PrintInformation(info_buf) => {
+                if info_buf[0] & 0x02 != 0 {
+                    self.settings.media_type = Some(info_buf[1]);
+                }
+                if info_buf[0] & 0x04 != 0 {
+                    self.settings.media_width = Some(info_buf[2]);
+                }
+                if info_buf[0] & 0x08 != 0 {
+                    self.settings.media_length = Some(info_buf[3]);
+                }
+                self.settings.printer_recovery = Some(info_buf[0] & 0x80 != 0);
+                self.settings.raster_number = Some(u32::from_le_bytes(info_buf[4..8].try_into().unwrap()));
+                let new_state = match (info_buf[8], self.page_state) {
+                    // announcing page: first
+                    (0, AnnouncedPage::BeforeFirst) => AnnouncedPage::First,
+
+                    // announcing page: midway
+                    (1, AnnouncedPage::First|AnnouncedPage::Other) => AnnouncedPage::Other,
+
+                    // announcing page: last
+                    // (also used if there is only one page)
+                    (2, state) if state != AnnouncedPage::Last => AnnouncedPage::Last,
+
+                    (page_byte, state) => {
+                        self.violation(DecodeError::UnexpectedPageAnnouncement { page_byte, state })?;
+                        match page_byte {
+                            0 => AnnouncedPage::First,
+                            1 => AnnouncedPage::Other,
+                            2 => AnnouncedPage::Last,
+                            _ => state,
+                        }
+                    },
+                };
+                self.page_state = new_state;
+                // info_buf[9] is apparently always 0
+            },
+            Command::Mode(mode) => {
+                self.settings.auto_cut = Some((mode & 0x40) != 0);
+                self.settings.mirror_print = Some((mode & 0x80) != 0);
+            },
+            Command::CutEvery(count) => {
+                self.settings.cut_each_n_labels = Some(*count);
+            },
+            Command::AdvancedMode(settings) => {
+                self.settings.draft = Some((settings & 0x01) != 0);
+                // 0x02 unused
+                self.settings.half_cut = Some((settings & 0x04) != 0);
+                self.settings.no_chain = Some((settings & 0x08) != 0);
+                self.settings.special_tape = Some((settings & 0x10) != 0);
+                // 0x20 unused
+                self.settings.hi_res = Some((settings & 0x40) != 0);
+                self.settings.dont_clean_print_buffer = Some((settings & 0x80) != 0);
+            },
+            Command::Feed(amount) => {
+                self.settings.feed_amount = Some(*amount);
+            },
+            Command::AutoStatusNotification(_) => {
+                // nothing to do for us here
+            },
+            Command::Compression(_) => {
+                // handled by the command reader
+            },
+            Command::Raster(data)|Command::QlRaster(data) => {
+                if !self.raster_mode {
+                    // in lenient mode, assume that raster mode has been entered before
+                    self.violation(DecodeError::RasterWithoutRasterMode)?;
+                    self.raster_mode = true;
+                }
+                let command_set = if matches!(command, Command::QlRaster(_)) { CommandSet::Ql } else { CommandSet::PTouch };
+                self.settings.command_set.get_or_insert(command_set);
+                self.decompressed_bytes += data.len();
+                if self.decompressed_bytes > self.limits.max_decompressed_bytes {
+                    return Err(DecodeError::DataTooLarge { limit: self.limits.max_decompressed_bytes });
+                }
+                self.add_row(data.len() * 8)?;
+            },
+            Command::TwoColorRaster { black, red } => {
+                if !self.raster_mode {
+                    // in lenient mode, assume that raster mode has been entered before
+                    self.violation(DecodeError::RasterWithoutRasterMode)?;
+                    self.raster_mode = true;
+                }
+                // only QL models print in two colours
+                self.settings.command_set.get_or_insert(CommandSet::Ql);
+                self.decompressed_bytes += black.len() + red.len();
+                if self.decompressed_bytes > self.limits.max_decompressed_bytes {
+                    return Err(DecodeError::DataTooLarge { limit: self.limits.max_decompressed_bytes });
+                }
+                self.add_row(black.len().max(red.len()) * 8)?;
+            },
+            Command::ZeroRaster => {
+                if !self.raster_mode {
+                    // in lenient mode, assume that raster mode has been entered before
+                    self.violation(DecodeError::RasterWithoutRasterMode)?;
+                    self.raster_mode = true;
+                }
+                self.settings.command_set.get_or_insert(CommandSet::PTouch);
+                self.add_row(0)?;
+            },
+            Command::Print|Command::PrintFeed => {
+                if self.page_count >= self.limits.max_pages {
+                    return Err(DecodeError::TooManyPages { limit: self.limits.max_pages });
+                }
+                self.page_count += 1;
+                self.printed_page_rows.push(self.page_rows);
+                self.page_rows = 0;
+                self.height += 1;
+            },
+        }
+        Ok(())
+    }
+
+    /// Outputs the settings of the print job.
+    pub fn print_report(&self) {
+        for (name, value) in self.settings.describe() {
+            println!("{}: {}", name, value);
+        }
+
+        let media_type = self.settings.media_type.map(MediaType::from_byte);
+        // heat-shrink tube is announced with its media type; the special tape flag is a weaker hint
+        let is_tube = match media_type {
+            Some(mt) => mt.is_tube(),
+            None => self.settings.special_tape == Some(true),
+        };
+        if is_tube {
+            let tube = self.settings.media_width.and_then(|w| Model::ALL
+                .iter()
+                .find_map(|model| model.profile().tube_by_width(w))
+            );
+            match tube {
+                Some(t) => println!("heat-shrink tube job: HSe {} mm tube", t.diameter),
+                None => println!("heat-shrink tube job: unknown tube size"),
+            }
+        }
+    }
+}
@@ -0,0 +1,12 @@
+pub mod bundle;
+pub mod capture;
+pub mod command;
+pub mod diff;
+pub mod error;
+pub mod fingerprint;
+pub mod job;
+pub mod lint;
+pub mod png_settings;
+pub mod render;
+pub mod stats;
+pub mod status;
@@ -0,0 +1,400 @@
+//! Checking print jobs against the constraints of a printer model.
+//!
+//! Printers usually reject a job they do not like by blinking an error LED, so problems are easier
+//! to find before the job is sent.
+
+
+use std::fmt;
+
+use ptouch_common::media::{LabelGeometry, MediaType};
+use ptouch_common::model::{CommandSet, Model};
+
+use crate::command::Command;
+
+
+/// The names of the bits of the advanced mode settings (`ESC i K`) on P-touch models.
+const ADVANCED_MODE_FLAGS: [(u8, &str); 6] = [
+    (0x01, "draft"),
+    (0x04, "half cut"),
+    (0x08, "no chain printing"),
+    (0x10, "special tape"),
+    (0x40, "high resolution"),
+    (0x80, "no buffer clearing"),
+];
+
+/// The names of the bits of the advanced mode settings on QL models, where two of them mean
+/// something else.
+const QL_ADVANCED_MODE_FLAGS: [(u8, &str); 6] = [
+    (0x01, "two-colour printing"),
+    (0x04, "half cut"),
+    (0x08, "cut at end"),
+    (0x10, "special tape"),
+    (0x40, "high resolution"),
+    (0x80, "no buffer clearing"),
+];
+
+
+/// A problem that a printer model is likely to have with a print job.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum LintProblem {
+    /// The job does not print any page.
+    NoPages,
+
+    /// A page is not announced using `ESC i z`.
+    MissingPageAnnouncement { page_index: usize },
+
+    /// A page is announced with the wrong page byte.
+    UnexpectedPageByte { page_index: usize, page_byte: u8, expected: u8 },
+
+    /// The number of raster lines announced for a page differs from the number sent.
+    RasterCountMismatch { page_index: usize, announced: u32, actual: usize },
+
+    /// Raster lines of a page do not match the width of the print head.
+    LineWidth { page_index: usize, width_bytes: usize, expected_bytes: usize, count: usize },
+
+    /// A page is shorter than the shortest label the model can print.
+    LabelTooShort { page_index: usize, lines: usize, min_lines: usize, min_length_mm: u8 },
+
+    /// The compression mode is changed after raster lines have been sent.
+    CompressionAfterRaster { page_index: usize },
+
+    /// A page other than the last one is printed with `0x1A` (print with feed).
+    FeedBeforeLastPage { page_index: usize },
+
+    /// The last page is printed with `0x0C` instead of `0x1A` (print with feed).
+    LastPageWithoutFeed { page_index: usize },
+
+    /// Raster lines are sent after the last print command.
+    RowsAfterLastPage { count: usize },
+
+    /// Advanced mode settings are enabled that the model does not support.
+    UnsupportedFlags(Vec<&'static str>),
+
+    /// The feed margin is longer than the model supports.
+    FeedOutOfRange { dots: u16, max_dots: usize },
+
+    /// The media announced in the job is not supported by the model.
+    UnsupportedMedia { width_mm: u8, tube: bool },
+
+    /// The die-cut labels announced in the job are not supported by the model.
+    UnsupportedLabels { width_mm: u8, length_mm: u8 },
+
+    /// A page does not fill a die-cut label exactly.
+    LabelLengthMismatch { page_index: usize, lines: usize, expected: usize },
+
+    /// The feed margin differs from the one the model expects on the announced media.
+    MarginMismatch { dots: u16, expected: u16 },
+
+    /// Raster lines are sent using a command of the other dialect (`G` to a QL model or `g` to a
+    /// P-touch model).
+    ForeignRasterCommand { command: char, expected: char, count: usize },
+
+    /// Raster lines are sent in two colours, which the model cannot print.
+    TwoColorUnsupported { count: usize },
+}
+impl fmt::Display for LintProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoPages
+                => write!(f, "the job does not print any page"),
+            Self::MissingPageAnnouncement { page_index }
+                => write!(f, "page at index {} is not announced with ESC i z", page_index),
+            Self::UnexpectedPageByte { page_index, page_byte, expected }
+                => write!(f, "page at index {} is announced with page byte {} instead of {}", page_index, page_byte, expected),
+            Self::RasterCountMismatch { page_index, announced, actual }
+                => write!(f, "page at index {} announces {} raster lines but contains {}", page_index, announced, actual),
+            Self::LineWidth { page_index, width_bytes, expected_bytes, count }
+                => write!(f, "page at index {} contains {} raster lines {} bytes wide instead of {}", page_index, count, width_bytes, expected_bytes),
+            Self::LabelTooShort { page_index, lines, min_lines, min_length_mm }
+                => write!(f, "page at index {} is {} raster lines long; the minimum is {} ({} mm)", page_index, lines, min_lines, min_length_mm),
+            Self::CompressionAfterRaster { page_index }
+                => write!(f, "compression mode is changed after raster lines have been sent (page at index {})", page_index),
+            Self::FeedBeforeLastPage { page_index }
+                => write!(f, "page at index {} is printed with feed (0x1A) although more pages follow", page_index),
+            Self::LastPageWithoutFeed { page_index }
+                => write!(f, "last page (at index {}) is printed without feed (0x0C instead of 0x1A)", page_index),
+            Self::RowsAfterLastPage { count }
+                => write!(f, "{} raster lines follow the last print command", count),
+            Self::UnsupportedFlags(flags)
+                => write!(f, "unsupported advanced mode settings: {}", flags.join(", ")),
+            Self::FeedOutOfRange { dots, max_dots }
+                => write!(f, "feed amount of {} dots exceeds the maximum of {} dots", dots, max_dots),
+            Self::UnsupportedMedia { width_mm, tube: true }
+                => write!(f, "{} mm heat-shrink tube is not supported", width_mm),
+            Self::UnsupportedMedia { width_mm, tube: false }
+                => write!(f, "{} mm tape is not supported", width_mm),
+            Self::UnsupportedLabels { width_mm, length_mm }
+                => write!(f, "{}x{} mm die-cut labels are not supported", width_mm, length_mm),
+            Self::LabelLengthMismatch { page_index, lines, expected }
+                => write!(f, "page at index {} is {} raster lines long but the die-cut label takes {}", page_index, lines, expected),
+            Self::MarginMismatch { dots, expected }
+                => write!(f, "feed amount of {} dots differs from the {} dots the model expects on this media", dots, expected),
+            Self::ForeignRasterCommand { command, expected, count }
+                => write!(f, "{} raster lines are sent using {} instead of {}", count, command, expected),
+            Self::TwoColorUnsupported { count }
+                => write!(f, "{} raster lines are sent in two colours (w), which the model cannot print", count),
+        }
+    }
+}
+
+
+/// What is known about a page once it has been printed.
+struct PageSummary {
+    page_byte: Option<u8>,
+    feed: bool,
+}
+
+
+/// Checks the commands of a print job against the constraints of a printer model.
+pub struct Linter {
+    model: Model,
+    problems: Vec<LintProblem>,
+    pages: Vec<PageSummary>,
+
+    hi_res: bool,
+    media_type: Option<u8>,
+    media_width: Option<u8>,
+    media_length: Option<u8>,
+    feed_dots: Option<u16>,
+    raster_sent: bool,
+
+    /// The page byte and raster number with which the current page has been announced.
+    announcement: Option<(u8, u32)>,
+
+    /// The number of raster lines of the current page.
+    page_rows: usize,
+
+    /// The number of raster lines of the current page by width, if the width is wrong.
+    wrong_widths: Vec<(usize, usize)>,
+
+    /// The number of raster lines sent in two colours.
+    two_color_lines: usize,
+
+    /// The number of raster lines sent using the raster command of the other dialect.
+    foreign_raster_lines: usize,
+}
+impl Linter {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            problems: Vec::new(),
+            pages: Vec::new(),
+            hi_res: false,
+            media_type: None,
+            media_width: None,
+            media_length: None,
+            feed_dots: None,
+            raster_sent: false,
+            announcement: None,
+            page_rows: 0,
+            wrong_widths: Vec::new(),
+            two_color_lines: 0,
+            foreign_raster_lines: 0,
+        }
+    }
+
+    /// Checks the given command.
+    pub fn observe(&mut self, command: &Command) {
+        let profile = self.model.profile();
+        let page_index = self.pages.len();
+        match command {
+            Command::PrintInformation(info_buf) => {
+                if info_buf[0] & 0x02 != 0 {
+                    self.media_type = Some(info_buf[1]);
+                }
+                if info_buf[0] & 0x04 != 0 {
+                    self.media_width = Some(info_buf[2]);
+                }
+                if info_buf[0] & 0x08 != 0 {
+                    self.media_length = Some(info_buf[3]);
+                }
+                let raster_number = u32::from_le_bytes(info_buf[4..8].try_into().unwrap());
+                self.announcement = Some((info_buf[8], raster_number));
+            },
+            Command::AdvancedMode(settings) => {
+                self.hi_res = settings & 0x40 != 0;
+                let flag_names = match profile.command_set {
+                    CommandSet::PTouch => &ADVANCED_MODE_FLAGS,
+                    CommandSet::Ql => &QL_ADVANCED_MODE_FLAGS,
+                };
+                let unsupported: Vec<&'static str> = flag_names
+                    .iter()
+                    .filter(|(bit, _name)| settings & bit != 0 && profile.advanced_mode_flags & bit == 0)
+                    .map(|(_bit, name)| *name)
+                    .collect();
+                if !unsupported.is_empty() {
+                    self.problems.push(LintProblem::UnsupportedFlags(unsupported));
+                }
+            },
+            Command::Feed(dots) => {
+                self.feed_dots = Some(*dots);
+                let max_dots = profile.mm_to_lines(f64::from(profile.max_feed_mm), false);
+                if usize::from(*dots) > max_dots {
+                    self.problems.push(LintProblem::FeedOutOfRange { dots: *dots, max_dots });
+                }
+            },
+            Command::Compression(_) if self.raster_sent => {
+                self.problems.push(LintProblem::CompressionAfterRaster { page_index });
+            },
+            Command::Raster(data)|Command::QlRaster(data) => {
+                self.raster_sent = true;
+                self.page_rows += 1;
+                let ql_raster = matches!(command, Command::QlRaster(_));
+                if ql_raster != (profile.command_set == CommandSet::Ql) {
+                    self.foreign_raster_lines += 1;
+                }
+                self.check_line_width(data.len());
+            },
+            Command::TwoColorRaster { black, red } => {
+                self.raster_sent = true;
+                self.page_rows += 1;
+                self.two_color_lines += 1;
+                self.check_line_width(black.len().max(red.len()));
+            },
+            Command::ZeroRaster => {
+                self.raster_sent = true;
+                self.page_rows += 1;
+            },
+            Command::Print|Command::PrintFeed => {
+                self.finish_page(*command == Command::PrintFeed);
+            },
+            _ => {},
+        }
+    }
+
+    /// Counts a raster line of the given width in bytes if it does not match the print head.
+    fn check_line_width(&mut self, width_bytes: usize) {
+        if width_bytes != usize::from(self.model.profile().head_pins) / 8 {
+            match self.wrong_widths.iter_mut().find(|(width, _count)| *width == width_bytes) {
+                Some((_width, count)) => *count += 1,
+                None => self.wrong_widths.push((width_bytes, 1)),
+            }
+        }
+    }
+
+    fn finish_page(&mut self, feed: bool) {
+        let profile = self.model.profile();
+        let page_index = self.pages.len();
+
+        match self.announcement.take() {
+            Some((page_byte, announced)) => {
+                if usize::try_from(announced).unwrap() != self.page_rows {
+                    self.problems.push(LintProblem::RasterCountMismatch { page_index, announced, actual: self.page_rows });
+                }
+                self.pages.push(PageSummary { page_byte: Some(page_byte), feed });
+            },
+            None => {
+                self.problems.push(LintProblem::MissingPageAnnouncement { page_index });
+                self.pages.push(PageSummary { page_byte: None, feed });
+            },
+        }
+
+        let expected_bytes = usize::from(profile.head_pins) / 8;
+        for (width_bytes, count) in self.wrong_widths.drain(..) {
+            self.problems.push(LintProblem::LineWidth { page_index, width_bytes, expected_bytes, count });
+        }
+
+        if let Some(label) = self.label() {
+            // die-cut labels have a fixed length instead of a minimum one
+            let expected = usize::from(label.printable_lines) * if self.hi_res { 2 } else { 1 };
+            if self.page_rows != expected {
+                self.problems.push(LintProblem::LabelLengthMismatch { page_index, lines: self.page_rows, expected });
+            }
+        } else {
+            let tube = self.media_type.is_some_and(|mt| MediaType::from_byte(mt).is_tube());
+            let min_length_mm = match self.media_width.and_then(|w| profile.tube_by_width(w)) {
+                Some(tube_geometry) if tube => tube_geometry.min_length_mm,
+                _ => profile.min_length_mm,
+            };
+            let min_lines = profile.mm_to_lines(f64::from(min_length_mm), self.hi_res);
+            if self.page_rows < min_lines {
+                self.problems.push(LintProblem::LabelTooShort { page_index, lines: self.page_rows, min_lines, min_length_mm });
+            }
+        }
+
+        self.page_rows = 0;
+    }
+
+    /// Whether die-cut labels have been announced.
+    fn die_cut(&self) -> bool {
+        self.media_type.is_some_and(|mt| MediaType::from_byte(mt) == MediaType::DieCutLabels)
+    }
+
+    /// Returns the geometry of the announced die-cut labels, if the model supports them.
+    fn label(&self) -> Option<&'static LabelGeometry> {
+        if !self.die_cut() {
+            return None;
+        }
+        self.model.profile().label(self.media_width?, self.media_length?)
+    }
+
+    /// Performs the checks that need the whole job and returns all problems found.
+    pub fn finish(mut self) -> Vec<LintProblem> {
+        let profile = self.model.profile();
+
+        if self.page_rows > 0 {
+            self.problems.push(LintProblem::RowsAfterLastPage { count: self.page_rows });
+        }
+        if self.pages.is_empty() {
+            self.problems.push(LintProblem::NoPages);
+        }
+
+        let last_index = self.pages.len().saturating_sub(1);
+        for (page_index, page) in self.pages.iter().enumerate() {
+            let expected = if page_index == last_index && profile.last_page_2 {
+                2
+            } else if page_index == 0 {
+                0
+            } else {
+                1
+            };
+            if let Some(page_byte) = page.page_byte && page_byte != expected {
+                self.problems.push(LintProblem::UnexpectedPageByte { page_index, page_byte, expected });
+            }
+            if page_index == last_index && !page.feed {
+                self.problems.push(LintProblem::LastPageWithoutFeed { page_index });
+            } else if page_index != last_index && page.feed {
+                self.problems.push(LintProblem::FeedBeforeLastPage { page_index });
+            }
+        }
+
+        if let Some(width_mm) = self.media_width {
+            let tube = self.media_type.is_some_and(|mt| MediaType::from_byte(mt).is_tube());
+            if self.die_cut() {
+                if let Some(length_mm) = self.media_length && self.label().is_none() {
+                    self.problems.push(LintProblem::UnsupportedLabels { width_mm, length_mm });
+                }
+            } else {
+                let supported = if tube {
+                    profile.tube_by_width(width_mm).is_some()
+                } else {
+                    profile.tape(width_mm).is_some()
+                };
+                if !supported {
+                    self.problems.push(LintProblem::UnsupportedMedia { width_mm, tube });
+                }
+            }
+        }
+
+        if let Some(expected) = profile.required_feed_dots(self.die_cut()) {
+            let dots = self.feed_dots.unwrap_or(0);
+            if dots != expected {
+                self.problems.push(LintProblem::MarginMismatch { dots, expected });
+            }
+        }
+
+        if self.foreign_raster_lines > 0 {
+            let (command, expected) = match profile.command_set {
+                CommandSet::PTouch => ('g', 'G'),
+                CommandSet::Ql => ('G', 'g'),
+            };
+            self.problems.push(LintProblem::ForeignRasterCommand { command, expected, count: self.foreign_raster_lines });
+        }
+
+        if self.two_color_lines > 0 && !profile.two_color {
+            self.problems.push(LintProblem::TwoColorUnsupported { count: self.two_color_lines });
+        }
+
+        self.problems
+    }
+}
@@ -1,437 +1,487 @@
-use std::ffi::OsString;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 
+use clap::Parser;
+use ptouch_common::model::Model;
 
-const ESC: u8 = 0x1B;
+use ptouch_decode::bundle::JobBundle;
+use ptouch_decode::capture::{self, CapturedStream};
+use ptouch_decode::command::{Command, CommandReader};
+use ptouch_decode::diff::DecodedJob;
+use ptouch_decode::error::DecodeError;
+use ptouch_decode::job::{JobState, Limits};
+use ptouch_decode::lint::{LintProblem, Linter};
+use ptouch_decode::render::{RenderOptions, Renderer};
+use ptouch_decode::stats::JobStats;
+use ptouch_decode::status::{STATUS_REPLY_LENGTH, StatusReply};
 
 
-trait BufReadExt {
-    /// Skips over all bytes that equal the given byte.
-    ///
-    /// Returns `Ok(true)` if a byte with a different value was reached and `Ok(false)` on EOF. The
-    /// byte with a different value is not consumed and is read during the next call to one of the
-    /// `read*()` functions.
-    fn skip_while(&mut self, byte: u8) -> Result<bool, io::Error>;
+#[derive(Parser)]
+struct Opts {
+    #[arg(
+        long,
+        help = concat!(
+            "The widest raster line to accept, in pixels.",
+            " Defaults to the widest print head of any known model.",
+        ),
+    )]
+    pub max_width_px: Option<usize>,
+
+    #[arg(long, default_value = "1000000", help = "The largest number of raster lines to accept per page.")]
+    pub max_rows_per_page: usize,
+
+    #[arg(long, default_value = "10000", help = "The largest number of pages to accept.")]
+    pub max_pages: usize,
+
+    #[arg(
+        long,
+        default_value = "268435456",
+        help = "The largest number of bytes to which the raster lines of the whole job may decompress.",
+    )]
+    pub max_decompressed_bytes: usize,
+
+    #[arg(
+        long,
+        help = concat!(
+            "Also accept print data captured in the middle of a job or without an invalidate command,",
+            " and skip over data that cannot be decoded. Everything that is skipped is reported.",
+        ),
+    )]
+    pub lenient: bool,
+
+    #[arg(
+        long,
+        help = "Also guess the printer model each job was generated for and report the evidence.",
+    )]
+    pub fingerprint: bool,
+
+    #[arg(
+        long,
+        value_name = "MODEL",
+        help = concat!(
+            "Check each job against the profile of this printer model and list every problem found.",
+            " The exit status is nonzero if there are any problems.",
+        ),
+    )]
+    pub lint: Option<Model>,
+
+    #[arg(
+        long,
+        help = concat!(
+            "Also report the length of each label, the tape consumed, the black pixel coverage, the",
+            " compression ratio and the estimated print time of each job.",
+        ),
+    )]
+    pub stats: bool,
+
+    #[arg(
+        long,
+        value_name = "MODEL",
+        help = concat!(
+            "The printer model whose resolution, leader and print speed --stats assumes.",
+            " Defaults to the model given to --lint or else the most likely model of each job.",
+        ),
+    )]
+    pub model: Option<Model>,
+
+    #[arg(
+        long,
+        default_value = "9100",
+        help = "The TCP port on which the printer receives print data in a packet capture.",
+    )]
+    pub printer_port: u16,
+
+    #[arg(
+        long,
+        help = "Also write the print data extracted from a packet capture to this file.",
+    )]
+    pub extract_print_data: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = concat!(
+            "Also write each job as a bundle into this directory: one 1-bit PNG image per page and a",
+            " job.json file with all settings, from which ptouch-encode --bundle rebuilds the job.",
+            " With several jobs, the job number is appended to the directory name.",
+        ),
+    )]
+    pub bundle: Option<PathBuf>,
+
+    #[arg(help = "The print job file or packet capture (pcap or pcapng) to decode.")]
+    pub print_data_path: PathBuf,
+
+    #[arg(
+        required_unless_present_any = ["lint", "stats", "bundle"],
+        help = "The path of the PNG file to write. Optional with --lint, --stats or --bundle.",
+    )]
+    pub png_path: Option<PathBuf>,
 }
-impl<T: BufRead> BufReadExt for T {
-    fn skip_while(&mut self, byte: u8) -> Result<bool, io::Error> {
-        loop {
-            // fill the reader buffer
-            let my_buf = self.fill_buf()?;
-            if my_buf.len() == 0 {
-                // EOF reached
-                return Ok(false);
-            }
-            let until_pos = my_buf
-                .iter()
-                .position(|b| *b != byte)
-                .unwrap_or(my_buf.len());
-            if until_pos == 0 {
-                break;
-            }
-            self.consume(until_pos);
+impl Opts {
+    pub fn limits(&self) -> Limits {
+        let max_width = self.max_width_px.unwrap_or_else(|| Model::ALL
+            .iter()
+            .map(|model| usize::from(model.profile().head_pins))
+            .max()
+            .unwrap()
+        );
+        Limits {
+            max_width,
+            max_rows_per_page: self.max_rows_per_page,
+            max_pages: self.max_pages,
+            max_decompressed_bytes: self.max_decompressed_bytes,
         }
-        Ok(true)
     }
 }
 
 
-#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum AnnouncedPage {
-    #[default] BeforeFirst,
-    First,
-    Other,
-    Last,
+/// Where the print data is read from.
+enum PrintData {
+    /// A file containing print data.
+    File(PathBuf),
+
+    /// Print data extracted from a packet capture.
+    Captured(Vec<u8>),
 }
 
-#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum CompressionMode {
-    #[default] Raw,
-    PackBits,
+
+fn open_print_data(print_data: &PrintData, lenient: bool) -> Result<CommandReader<Box<dyn BufRead + '_>>, DecodeError> {
+    let reader: Box<dyn BufRead> = match print_data {
+        PrintData::File(path) => {
+            let print_data_file = File::open(path)
+                .expect("file not found");
+            Box::new(BufReader::new(print_data_file))
+        },
+        PrintData::Captured(data) => Box::new(data.as_slice()),
+    };
+    if lenient {
+        CommandReader::new_lenient(reader)
+    } else {
+        CommandReader::new(reader)
+    }
 }
 
-#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum LabelPart {
-    LabelData { rows: Vec<Vec<u8>> },
-    Print,
-    PrintFeed,
+/// Returns whether the file at the given path is a packet capture.
+fn is_capture_file(path: &Path) -> bool {
+    let file = File::open(path)
+        .expect("file not found");
+    let mut magic = Vec::with_capacity(4);
+    file.take(4).read_to_end(&mut magic)
+        .expect("failed to read file");
+    capture::is_capture(&magic)
 }
 
+/// Outputs the status replies that the printer has sent within a captured stream.
+fn print_status_replies(stream: &CapturedStream) {
+    println!("status replies from {}:", stream.source);
+    let mut replies = stream.from_printer.chunks_exact(STATUS_REPLY_LENGTH);
+    for (reply_index, reply_bytes) in replies.by_ref().enumerate() {
+        match StatusReply::parse(reply_bytes) {
+            Ok(reply) => {
+                println!();
+                println!("status reply {}:", reply_index + 1);
+                reply.print_report();
+            },
+            Err(e) => eprintln!("warning: status reply {} from {}: {}", reply_index + 1, stream.source, e),
+        }
+    }
+    if !replies.remainder().is_empty() {
+        eprintln!(
+            "warning: {} bytes from {} do not make up a whole status reply",
+            replies.remainder().len(), stream.source,
+        );
+    }
+}
 
-fn unpack_bits(buf: &[u8]) -> Vec<u8> {
-    let mut ret = Vec::new();
+/// Reads the whole print data, collecting the settings and the dimensions of the rendering of
+/// each job within it.
+///
+/// The limits apply to each job separately. In lenient mode, skipped data and invalid commands are
+/// reported as warnings.
+fn scan_print_data(print_data: &PrintData, limits: Limits, lenient: bool) -> Result<Vec<JobState>, DecodeError> {
+    let new_job = || if lenient { JobState::new_lenient(limits) } else { JobState::new(limits) };
+    let mut jobs = vec![new_job()];
+    let mut commands = open_print_data(print_data, lenient)?
+        .with_limits(limits);
+    jobs[0].set_invalidate_length(commands.invalidate_length());
+    loop {
+        let mut offset = commands.position();
+        let command = commands.next_command()?;
+        for skipped in commands.take_skipped() {
+            eprintln!(
+                "warning: skipped {} bytes at offset {} ({:#X}): {}",
+                skipped.length, skipped.offset, skipped.offset, skipped.reason,
+            );
+            // the command follows the skipped bytes
+            offset = skipped.offset + skipped.length;
+        }
+        let Some(command) = command else { break };
 
-    let mut iter = buf.iter();
-    while let Some(instruction_u8) = iter.next() {
-        let instruction = i8::from_le_bytes([*instruction_u8]);
-        if instruction >= 0 {
-            let literal_byte_count = (1 + instruction).try_into().unwrap();
-            ret.reserve(literal_byte_count);
-            for _ in 0..literal_byte_count {
-                let literal_byte = iter.next()
-                    .expect("short read of literal bytes");
-                ret.push(*literal_byte);
-            }
-        } else if instruction == -128 {
-            // skip
-        } else {
-            // repeated byte
-            let repeat_count = usize::try_from(1 - instruction).unwrap();
-            assert!(repeat_count >= 2);
-            let value = iter.next()
-                .expect("repeat without repeated value");
-            ret.reserve(repeat_count);
-            for _ in 0..repeat_count {
-                ret.push(*value);
-            }
+        if command == Command::Invalidate {
+            jobs.push(new_job());
+            jobs.last_mut().unwrap().set_invalidate_length(commands.invalidate_length());
+        }
+        let job = jobs.last_mut().unwrap();
+        job.apply(&command)?;
+        for violation in job.take_violations() {
+            eprintln!("warning: accepted invalid command at offset {} ({:#X}): {}", offset, offset, violation);
         }
     }
+    Ok(jobs)
+}
 
-    ret
+/// Checks each job within the print data against the profile of the given model.
+///
+/// Returns the problems found in each job. The print data must already have been scanned
+/// successfully.
+fn lint_print_data(print_data: &PrintData, model: Model, lenient: bool) -> Vec<Vec<LintProblem>> {
+    let mut problems = Vec::new();
+    let mut linter = Linter::new(model);
+    let commands = open_print_data(print_data, lenient)
+        .expect("print data changed between passes");
+    for command in commands {
+        let command = command
+            .expect("print data changed between passes");
+        if command == Command::Invalidate {
+            problems.push(std::mem::replace(&mut linter, Linter::new(model)).finish());
+        }
+        linter.observe(&command);
+    }
+    problems.push(linter.finish());
+    problems
 }
 
+/// Collects the figures of each job within the print data. The print data must already have been
+/// scanned successfully.
+fn collect_stats(print_data: &PrintData, lenient: bool) -> Vec<JobStats> {
+    let mut stats = vec![JobStats::new()];
+    let mut commands = open_print_data(print_data, lenient)
+        .expect("print data changed between passes");
+    loop {
+        let mut offset = commands.position();
+        let command = commands.next_command()
+            .expect("print data changed between passes");
+        if let Some(skipped) = commands.take_skipped().last() {
+            offset = skipped.offset + skipped.length;
+        }
+        let Some(command) = command else { break };
 
-fn main() -> ExitCode {
-    let args: Vec<OsString> = std::env::args_os().collect();
-    let prog_name = args
-        .get(0)
-        .map(|pn | pn.display().to_string())
-        .unwrap_or_else(|| "ptouch-decode".to_owned());
-    if args.len() != 3 {
-        eprintln!("Usage: {} PRINTDATA PNGDATA", prog_name);
-        return ExitCode::FAILURE;
+        if command == Command::Invalidate {
+            stats.push(JobStats::new());
+        }
+        stats.last_mut().unwrap().observe(&command, commands.position() - offset);
     }
-    let print_data_path = Path::new(&args[1]);
-    let print_data_file = File::open(print_data_path)
-        .expect("file not found");
-    let mut print_data_buffering = BufReader::new(print_data_file);
-
-    // read 200 bytes to ensure we have an invalidate command
-    let mut invalidate_buf = vec![0u8; 200];
-    print_data_buffering.read_exact(&mut invalidate_buf)
-        .expect("failed to read invalidate command");
-    if invalidate_buf.iter().any(|b| *b != 0x00) {
-        panic!("print data does not start with a valid invalidate command (200 zero bytes)");
+    stats
+}
+
+/// Returns the path of the PNG file (or bundle directory) for the job at the given index.
+///
+/// If the print data contains only one job, this is the path given by the user; otherwise, the job
+/// number is appended to the file name (`label.png` becomes `label-1.png`, `label-2.png` etc.).
+fn job_output_path(png_path: &Path, job_index: usize, job_count: usize) -> PathBuf {
+    if job_count == 1 {
+        return png_path.to_owned();
     }
+    let mut file_name = png_path.file_stem()
+        .map(|stem| stem.to_owned())
+        .unwrap_or_default();
+    file_name.push(format!("-{}", job_index + 1));
+    if let Some(extension) = png_path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    png_path.with_file_name(file_name)
+}
 
-    // skip over all following 0 bytes
-    print_data_buffering.skip_while(0x00)
-        .expect("failed to fast-forward over long invalidate command");
 
-    // read 2 bytes to ensure we start with an initialize command
-    let mut init_buf = [0u8; 2];
-    print_data_buffering.read_exact(&mut init_buf)
-        .expect("failed to read init command");
-    if init_buf[0] != ESC || init_buf[1] != b'@' {
-        panic!("first command is not init but {:#04X} {:#04X}", init_buf[0], init_buf[1]);
-    }
+fn main() -> ExitCode {
+    let opts = Opts::parse();
 
-    // parse commands
-    let mut raster_mode = false;
-    let mut media_type = None;
-    let mut media_width = None;
-    let mut media_length = None;
-    let mut raster_number = None;
-    let mut printer_recovery = false;
-    let mut page_state = AnnouncedPage::BeforeFirst;
-    let mut auto_cut = None;
-    let mut mirror_print = None;
-    let mut draft = None;
-    let mut half_cut = None;
-    let mut no_chain = None;
-    let mut special_tape = None;
-    let mut hi_res = None;
-    let mut dont_clean_print_buffer = None;
-    let mut feed_amount = None;
-    let mut cut_each_n_labels = None;
-    let mut compression_mode = CompressionMode::Raw;
-    let mut pixel_data_width = 0;
-    let mut parts = Vec::new();
-    let mut rows = Vec::new();
-    loop {
-        let mut buf = [0u8];
-        match print_data_buffering.read(&mut buf) {
-            Ok(1) => {},
-            Ok(0) => break, // EOF
-            Ok(n) => unreachable!(".read() read {} bytes into a 1-byte buffer?!", n),
-            Err(e) => panic!("failed to read next command: {}", e),
-        }
-        match buf[0] {
-            ESC => {
-                // control command
-                let mut esc_buf = [0u8];
-                print_data_buffering.read_exact(&mut esc_buf)
-                    .expect("failed to read type of escape");
-                match esc_buf[0] {
-                    b'@' => {
-                        // reinitialize again?
-
-                        // raster_mode does not change
-                        media_type = None;
-                        media_width = None;
-                        media_length = None;
-                        raster_number = None;
-                        page_state = AnnouncedPage::BeforeFirst;
-                    },
-                    b'i' => {
-                        // mode settings
-                        let mut set_buf = [0u8];
-                        print_data_buffering.read_exact(&mut set_buf)
-                            .expect("failed to read type of ESC i");
-                        match set_buf[0] {
-                            b'S' => {
-                                // status info request
-                                // nothing to do for us here
-                            },
-                            b'a' => {
-                                // switch print data language
-                                let mut lang_buf = [0u8];
-                                print_data_buffering.read_exact(&mut lang_buf)
-                                    .expect("failed to read print data language to which to switch");
-                                match lang_buf[0] {
-                                    0 => panic!("attempting to switch to ESC/P which we do not support"),
-                                    1 => {
-                                        raster_mode = true;
-                                    },
-                                    3 => panic!("attempting to switch to P-touch Template Mode which we do not support"),
-                                    other => panic!("unknown print data language {:#04X}", other),
-                                }
-                            },
-                            b'z' => {
-                                // print information
-                                // always followed by 10 bytes, whose validity is governed by the first byte
-                                let mut info_buf = [0u8; 10];
-                                print_data_buffering.read_exact(&mut info_buf)
-                                    .expect("failed to read print information command data");
-                                if info_buf[0] & 0x02 != 0 {
-                                    media_type = Some(info_buf[1]);
-                                }
-                                if info_buf[0] & 0x04 != 0 {
-                                    media_width = Some(info_buf[2]);
-                                }
-                                if info_buf[0] & 0x08 != 0 {
-                                    media_length = Some(info_buf[3]);
-                                }
-                                raster_number = Some(u32::from_le_bytes(info_buf[4..8].try_into().unwrap()));
-                                match info_buf[8] {
-                                    0 => {
-                                        // announcing page: first
-                                        if page_state == AnnouncedPage::BeforeFirst {
-                                            page_state = AnnouncedPage::First;
-                                        } else {
-                                            panic!("announcing first page in state {:?}", page_state);
-                                        }
-                                    },
-                                    1 => {
-                                        // announcing page: midway
-                                        if page_state == AnnouncedPage::First || page_state == AnnouncedPage::Other {
-                                            page_state = AnnouncedPage::Other;
-                                        } else {
-                                            panic!("announcing midway page in state {:?}", page_state);
-                                        }
-                                    },
-                                    2 => {
-                                        // announcing page: last
-                                        // (also used if there is only one page)
-                                        if page_state != AnnouncedPage::Last {
-                                            page_state = AnnouncedPage::Last;
-                                        } else {
-                                            panic!("announcing last page in state {:?}", page_state);
-                                        }
-                                    },
-                                    other => panic!("unknown page announcement byte {:#04X}", other),
-                                }
-                                // info_buf[9] is apparently always 0
-                            },
-                            b'M' => {
-                                // mode
-                                let mut mode_buf = [0u8];
-                                print_data_buffering.read_exact(&mut mode_buf)
-                                    .expect("failed to read print mode command data");
-                                auto_cut = Some((mode_buf[0] & 0x40) != 0);
-                                mirror_print = Some((mode_buf[0] & 0x80) != 0);
-                            },
-                            b'A' => {
-                                // cut after sets of how many labels?
-                                let mut count_buf = [0u8];
-                                print_data_buffering.read_exact(&mut count_buf)
-                                    .expect("failed to read count data");
-                                cut_each_n_labels = Some(count_buf[0]);
-                            },
-                            b'K' => {
-                                // advanced settings
-                                let mut settings_buf = [0u8];
-                                print_data_buffering.read_exact(&mut settings_buf)
-                                    .expect("failed to read print mode command data");
-                                draft = Some((settings_buf[0] & 0x01) != 0);
-                                // 0x02 unused
-                                half_cut = Some((settings_buf[0] & 0x04) != 0);
-                                no_chain = Some((settings_buf[0] & 0x08) != 0);
-                                special_tape = Some((settings_buf[0] & 0x10) != 0);
-                                // 0x20 unused
-                                hi_res = Some((settings_buf[0] & 0x40) != 0);
-                                dont_clean_print_buffer = Some((settings_buf[0] & 0x80) != 0);
-                            },
-                            b'd' => {
-                                // feed amount
-                                let mut value_buf = [0u8; 2];
-                                print_data_buffering.read_exact(&mut value_buf)
-                                    .expect("failed to read feed amount");
-                                feed_amount = Some(u16::from_le_bytes(value_buf));
-                            },
-                            b'!' => {
-                                // auto status notification mode
-                            },
-                            other => panic!("unexpected ESC i command {:#04X}", other),
-                        }
-                    },
-                    other => panic!("unexpected ESC command {:#04X}", other),
-                }
+    // packet captures are reassembled in memory; print data files are read directly
+    let mut captured_streams = Vec::new();
+    let print_data = if is_capture_file(&opts.print_data_path) {
+        let capture_data = std::fs::read(&opts.print_data_path)
+            .expect("failed to read packet capture");
+        captured_streams = match capture::extract_streams(&capture_data, opts.printer_port) {
+            Ok(streams) if streams.is_empty() => {
+                eprintln!("error: {}", DecodeError::NoPrinterTraffic);
+                return ExitCode::FAILURE;
             },
-            b'M' => {
-                // select compression mode
-                let mut mode_buf = [0u8];
-                print_data_buffering.read_exact(&mut mode_buf)
-                    .expect("failed to read select compression mode data");
-                match mode_buf[0] {
-                    0x00 => {
-                        compression_mode = CompressionMode::Raw;
-                    },
-                    0x02 => {
-                        compression_mode = CompressionMode::PackBits;
-                    },
-                    other => panic!("unsupported compression mode: {:#04X}", other),
-                }
+            Ok(streams) => streams,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                return ExitCode::FAILURE;
             },
-            b'G' => {
-                // raster graphics transfer
-                if !raster_mode {
-                    panic!("raster graphics transfer without raster mode entered");
-                }
-                let mut byte_count_buf = [0u8; 2];
-                print_data_buffering.read_exact(&mut byte_count_buf)
-                    .expect("failed to read raster graphics transfer length");
-                let byte_count = usize::from(u16::from_le_bytes(byte_count_buf));
-                let mut raster_buf = vec![0u8; byte_count];
-                print_data_buffering.read_exact(&mut raster_buf)
-                    .expect("failed to read raster graphics data");
-
-                // convert into pixelzzz
-                let raw_buf = if compression_mode == CompressionMode::PackBits {
-                    unpack_bits(&raster_buf)
-                } else {
-                    raster_buf
-                };
-                pixel_data_width = pixel_data_width.max(raw_buf.len() * 8);
-                let mut row: Vec<u8> = Vec::with_capacity(pixel_data_width);
-                for byte in &raw_buf {
-                    for bit_index in (0..8).rev() {
-                        if (*byte & (1 << bit_index)) == 0 {
-                            row.push(0x00);
-                        } else {
-                            row.push(0x01);
-                        }
-                    }
+        };
+        for stream in &captured_streams {
+            println!(
+                "{}: {} bytes to printer, {} bytes from printer",
+                stream.source, stream.to_printer.len(), stream.from_printer.len(),
+            );
+            if stream.missing_bytes > 0 {
+                eprintln!(
+                    "warning: {} bytes of {} are missing from the capture; consider --lenient",
+                    stream.missing_bytes, stream.source,
+                );
+            }
+        }
+        println!();
+
+        let print_data: Vec<u8> = captured_streams
+            .iter()
+            .flat_map(|stream| stream.to_printer.iter().copied())
+            .collect();
+        if let Some(extract_path) = &opts.extract_print_data {
+            std::fs::write(extract_path, &print_data)
+                .expect("failed to write extracted print data");
+        }
+        PrintData::Captured(print_data)
+    } else {
+        PrintData::File(opts.print_data_path.clone())
+    };
+
+    // first pass: collect the settings and find out how large the image of each job will be
+    // (this also enforces the limits before anything is written)
+    let jobs = match scan_print_data(&print_data, opts.limits(), opts.lenient) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let png_paths: Option<Vec<PathBuf>> = opts.png_path.as_ref().map(|png_path| (0..jobs.len())
+        .map(|job_index| job_output_path(png_path, job_index, jobs.len()))
+        .collect()
+    );
+
+    // second pass: render the image of each job row by row
+    // (jobs without any pixels, e.g. only blank raster lines, do not get an image)
+    let has_image = |job: &JobState| job.pixel_data_width() > 0 && job.height() > 0;
+    if let Some(png_paths) = &png_paths {
+        let start_png = |job_index: usize| {
+            let options = RenderOptions {
+                embed_settings: true,
+                ..RenderOptions::default()
+            };
+            let renderer = Renderer::new(options, &jobs[job_index]);
+            if !renderer.has_image() {
+                return None;
+            }
+            let png_file = File::create(&png_paths[job_index])
+                .expect("failed to create PNG file");
+            let png_stream_wr = renderer.start_png(png_file);
+            Some((renderer, png_stream_wr))
+        };
+        let mut job_index = 0;
+        let mut rendering = start_png(0);
+        let commands = open_print_data(&print_data, opts.lenient)
+            .expect("print data changed between passes");
+        for command in commands {
+            let command = command
+                .expect("print data changed between passes");
+            if command == Command::Invalidate {
+                if let Some((_renderer, wr)) = rendering.take() {
+                    wr.finish()
+                        .expect("failed to finish PNG encoding");
                 }
-                rows.push(row);
-            },
-            b'Z' => {
-                // zero raster graphics
-                if !raster_mode {
-                    panic!("zero raster graphics transfer without raster mode entered");
+                job_index += 1;
+                rendering = start_png(job_index);
+                continue;
+            }
+            if let Some((renderer, wr)) = rendering.as_mut() {
+                for row in renderer.rows(&command) {
+                    wr.write_all(&row)
+                        .expect("failed to write into PNG stream");
                 }
-                rows.push(Vec::with_capacity(0));
-            },
-            0x0C => {
-                // form feed = print
-                let old_rows = std::mem::replace(&mut rows, Vec::new());
-                parts.push(LabelPart::LabelData { rows: old_rows });
-                parts.push(LabelPart::Print);
-            },
-            0x1A => {
-                // substitute = print with feeding
-                let old_rows = std::mem::replace(&mut rows, Vec::new());
-                parts.push(LabelPart::LabelData { rows: old_rows });
-                parts.push(LabelPart::PrintFeed);
-            },
-            other => panic!("unexpected command byte {:#04X}", other),
+            }
+        }
+        if let Some((_renderer, wr)) = rendering {
+            wr.finish()
+                .expect("failed to finish PNG encoding");
         }
     }
 
-    parts.push(LabelPart::LabelData { rows });
-
-    // update the parts to match the image width and calculate the image height
-    let mut height = 0;
-    for part in &mut parts {
-        match part {
-            LabelPart::LabelData { rows } => {
-                for row in rows.iter_mut() {
-                    assert!(row.len() <= pixel_data_width);
-                    row.resize(pixel_data_width, 0x00);
-                }
-                height += rows.len();
-            },
-            LabelPart::Print|LabelPart::PrintFeed => {
-                height += 1;
-            },
+    if let Some(bundle_dir) = &opts.bundle {
+        let commands = open_print_data(&print_data, opts.lenient)
+            .expect("print data changed between passes");
+        let decoded_jobs = DecodedJob::read_all(commands, opts.limits(), opts.lenient)
+            .expect("print data changed between passes");
+        for (job_index, decoded_job) in decoded_jobs.iter().enumerate() {
+            let job_dir = job_output_path(bundle_dir, job_index, decoded_jobs.len());
+            JobBundle::write(decoded_job, &job_dir)
+                .expect("failed to write bundle");
         }
     }
 
-    // output as PNG
-    let mut png_buf = Vec::new();
+    // third pass (if requested): check each job against the model
+    let lint_problems: Option<Vec<Vec<LintProblem>>> = opts.lint
+        .map(|model| lint_print_data(&print_data, model, opts.lenient));
+    let job_stats: Option<Vec<JobStats>> = opts.stats
+        .then(|| collect_stats(&print_data, opts.lenient));
 
-    {
-        let mut png_enc = png::Encoder::new(
-            &mut png_buf,
-            pixel_data_width.try_into().unwrap(),
-            height.try_into().unwrap(),
-        );
-        png_enc.set_color(png::ColorType::Indexed);
-        png_enc.set_depth(png::BitDepth::Eight);
-        png_enc.set_palette(&[
-            0xFF, 0xFF, 0xFF, // 0 = white (medium)
-            0x00, 0x00, 0x00, // 1 = black (marker)
-            0xFF, 0x00, 0x00, // 2 = red (print)
-            0x00, 0x00, 0xFF, // 3 = blue (print+feed)
-        ]);
-        let mut png_wr = png_enc.write_header()
-            .expect("failed to write PNG header");
-        let mut png_stream_wr = png_wr.stream_writer()
-            .expect("failed to obtain stream writer");
-        for part in &parts {
-            match part {
-                LabelPart::LabelData { rows } => {
-                    for row in rows {
-                        png_stream_wr.write_all(row)
-                            .expect("failed to write into PNG stream");
-                    }
-                },
-                LabelPart::Print => {
-                    // row of 0x02
-                    let row_0x02 = vec![0x02; pixel_data_width];
-                    png_stream_wr.write_all(&row_0x02)
-                        .expect("failed to write into PNG stream");
+    // report the settings
+    let print_job_report = |job_index: usize, job: &JobState| {
+        job.print_report();
+        if opts.fingerprint {
+            println!();
+            println!("fingerprint:");
+            job.evidence().print_report();
+        }
+        if let (Some(model), Some(lint_problems)) = (opts.lint, &lint_problems) {
+            let problems = &lint_problems[job_index];
+            println!();
+            println!("lint ({}): {} problems", model, problems.len());
+            for problem in problems {
+                println!("  {}", problem);
+            }
+        }
+        if let Some(job_stats) = &job_stats {
+            println!();
+            match opts.model.or(opts.lint) {
+                Some(model) => {
+                    println!("statistics ({}):", model);
+                    job_stats[job_index].print_report(model.profile());
                 },
-                LabelPart::PrintFeed => {
-                    // row of 0x03
-                    let row_0x03 = vec![0x03; pixel_data_width];
-                    png_stream_wr.write_all(&row_0x03)
-                        .expect("failed to write into PNG stream");
+                None => {
+                    let model = job.evidence().guess_models()[0].model;
+                    println!("statistics ({}, guessed):", model);
+                    job_stats[job_index].print_report(model.profile());
                 },
             }
         }
-        // done
-        png_stream_wr.finish()
-            .expect("failed to finish PNG stream encoding");
-        png_wr.finish()
-            .expect("failed to finish PNG encoding");
+    };
+    if jobs.len() == 1 {
+        if png_paths.is_some() && !has_image(&jobs[0]) {
+            eprintln!("warning: the print job contains no pixels; no image has been written");
+        }
+        print_job_report(0, &jobs[0]);
+    } else {
+        for (job_index, job) in jobs.iter().enumerate() {
+            if job_index > 0 {
+                println!();
+            }
+            match &png_paths {
+                Some(png_paths) if has_image(job) => println!("job {} ({}):", job_index + 1, png_paths[job_index].display()),
+                Some(_) => println!("job {} (no pixels):", job_index + 1),
+                None => println!("job {}:", job_index + 1),
+            }
+            print_job_report(job_index, job);
+        }
     }
 
-    std::fs::write(&args[2], &png_buf)
-        .expect("failed to write PNG");
+    // report what the printer replied
+    for stream in captured_streams.iter().filter(|stream| !stream.from_printer.is_empty()) {
+        println!();
+        print_status_replies(stream);
+    }
 
-    ExitCode::SUCCESS
+    let found_problems = lint_problems
+        .is_some_and(|lint_problems| lint_problems.iter().any(|problems| !problems.is_empty()));
+    if found_problems {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
@@ -0,0 +1,188 @@
+//! Job settings stored in text chunks of a rendered PNG image.
+//!
+//! `ptouch-decode` writes the settings of the job and the position of each page into the image, and
+//! `ptouch-encode` uses them as defaults when it is given such an image, so the image can be encoded
+//! again without knowing how the original job was set up.
+
+
+use std::fmt;
+use std::ops::Range;
+
+use ptouch_common::media::MediaType;
+
+use crate::job::JobState;
+
+
+/// The prefix of the keywords of all text chunks holding settings.
+pub const KEYWORD_PREFIX: &str = "ptouch:";
+
+const AUTO_CUT: &str = "auto-cut";
+const MIRROR_PRINT: &str = "mirror-print";
+const DRAFT: &str = "draft";
+const HALF_CUT: &str = "half-cut";
+const NO_CHAIN: &str = "no-chain";
+const SPECIAL_TAPE: &str = "special-tape";
+const HI_RES: &str = "hi-res";
+const DONT_CLEAR_PRINT_BUFFER: &str = "dont-clear-print-buffer";
+const CUT_EVERY: &str = "cut-every";
+const FEED: &str = "feed";
+const MEDIA_WIDTH: &str = "media-width";
+const LABEL_LENGTH: &str = "label-length";
+const PAGES: &str = "pages";
+
+
+/// A text chunk whose value cannot be understood.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct InvalidPngSetting {
+    pub keyword: String,
+    pub value: String,
+}
+impl fmt::Display for InvalidPngSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid value {:?} of PNG text chunk {:?}", self.value, self.keyword)
+    }
+}
+impl std::error::Error for InvalidPngSetting {
+}
+
+
+/// The settings of a job as stored in the text chunks of its image.
+///
+/// Settings that are `None` are not stored.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct PngSettings {
+    pub auto_cut: Option<bool>,
+    pub mirror_print: Option<bool>,
+    pub draft: Option<bool>,
+    pub half_cut: Option<bool>,
+    pub no_chain: Option<bool>,
+    pub special_tape: Option<bool>,
+    pub hi_res: Option<bool>,
+    pub dont_clear_print_buffer: Option<bool>,
+
+    /// Cut after every this many labels (`ESC i A`).
+    pub cut_every: Option<u8>,
+
+    /// The feed margin (`ESC i d`) in dots.
+    pub feed: Option<u16>,
+
+    /// The media width in millimeters.
+    pub media_width: Option<u8>,
+
+    /// The length of each label in millimeters if the media are die-cut labels.
+    pub label_length: Option<u8>,
+
+    /// The image rows holding the raster lines of each printed page, in the order in which they
+    /// are sent. Rows between the pages (e.g. the lines marking print commands) are not part of
+    /// any page.
+    pub pages: Option<Vec<Range<usize>>>,
+}
+impl PngSettings {
+    /// Collects the settings of a job whose pages start at the given image rows.
+    pub fn from_job(job: &JobState, page_starts: &[usize]) -> Self {
+        let settings = job.settings();
+        let pages = page_starts
+            .iter()
+            .zip(job.printed_page_rows())
+            .map(|(start, rows)| *start..*start + *rows)
+            .collect();
+        Self {
+            auto_cut: settings.auto_cut,
+            mirror_print: settings.mirror_print,
+            draft: settings.draft,
+            half_cut: settings.half_cut,
+            no_chain: settings.no_chain,
+            special_tape: settings.special_tape,
+            hi_res: settings.hi_res,
+            dont_clear_print_buffer: settings.dont_clean_print_buffer,
+            cut_every: settings.cut_each_n_labels,
+            feed: settings.feed_amount,
+            media_width: settings.media_width,
+            label_length: settings.media_length
+                .filter(|_| settings.media_type.map(MediaType::from_byte) == Some(MediaType::DieCutLabels)),
+            pages: Some(pages),
+        }
+    }
+
+    /// Returns the keyword and text of each text chunk that stores a setting.
+    pub fn text_chunks(&self) -> Vec<(String, String)> {
+        fn flag(value: bool) -> String {
+            if value { "yes" } else { "no" }.to_owned()
+        }
+
+        let mut chunks = Vec::new();
+        let mut push = |keyword: &str, text: Option<String>| {
+            if let Some(text) = text {
+                chunks.push((format!("{}{}", KEYWORD_PREFIX, keyword), text));
+            }
+        };
+        push(AUTO_CUT, self.auto_cut.map(flag));
+        push(MIRROR_PRINT, self.mirror_print.map(flag));
+        push(DRAFT, self.draft.map(flag));
+        push(HALF_CUT, self.half_cut.map(flag));
+        push(NO_CHAIN, self.no_chain.map(flag));
+        push(SPECIAL_TAPE, self.special_tape.map(flag));
+        push(HI_RES, self.hi_res.map(flag));
+        push(DONT_CLEAR_PRINT_BUFFER, self.dont_clear_print_buffer.map(flag));
+        push(CUT_EVERY, self.cut_every.map(|cut_every| cut_every.to_string()));
+        push(FEED, self.feed.map(|feed| feed.to_string()));
+        push(MEDIA_WIDTH, self.media_width.map(|width| width.to_string()));
+        push(LABEL_LENGTH, self.label_length.map(|length| length.to_string()));
+        push(PAGES, self.pages.as_ref().map(|pages| pages
+            .iter()
+            .map(|page| format!("{}:{}", page.start, page.len()))
+            .collect::<Vec<_>>()
+            .join(",")
+        ));
+        chunks
+    }
+
+    /// Extracts the settings from the keyword and text of each text chunk of an image.
+    ///
+    /// Text chunks whose keywords do not start with [`KEYWORD_PREFIX`] are ignored, as are unknown
+    /// settings.
+    pub fn from_text_chunks<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(chunks: I) -> Result<Self, InvalidPngSetting> {
+        let mut settings = Self::default();
+        for (keyword, text) in chunks {
+            let Some(name) = keyword.strip_prefix(KEYWORD_PREFIX) else {
+                continue;
+            };
+            let invalid = || InvalidPngSetting { keyword: keyword.to_owned(), value: text.to_owned() };
+            let flag = || match text {
+                "yes" => Ok(true),
+                "no" => Ok(false),
+                _ => Err(invalid()),
+            };
+            match name {
+                AUTO_CUT => settings.auto_cut = Some(flag()?),
+                MIRROR_PRINT => settings.mirror_print = Some(flag()?),
+                DRAFT => settings.draft = Some(flag()?),
+                HALF_CUT => settings.half_cut = Some(flag()?),
+                NO_CHAIN => settings.no_chain = Some(flag()?),
+                SPECIAL_TAPE => settings.special_tape = Some(flag()?),
+                HI_RES => settings.hi_res = Some(flag()?),
+                DONT_CLEAR_PRINT_BUFFER => settings.dont_clear_print_buffer = Some(flag()?),
+                CUT_EVERY => settings.cut_every = Some(text.parse().map_err(|_| invalid())?),
+                FEED => settings.feed = Some(text.parse().map_err(|_| invalid())?),
+                MEDIA_WIDTH => settings.media_width = Some(text.parse().map_err(|_| invalid())?),
+                LABEL_LENGTH => settings.label_length = Some(text.parse().map_err(|_| invalid())?),
+                PAGES => {
+                    let pages = text
+                        .split(',')
+                        .filter(|page| !page.is_empty())
+                        .map(|page| {
+                            let (start, rows) = page.split_once(':')?;
+                            let start: usize = start.parse().ok()?;
+                            let rows: usize = rows.parse().ok()?;
+                            Some(start..start.checked_add(rows)?)
+                        })
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(invalid)?;
+                    settings.pages = Some(pages);
+                },
+                _ => {},
+            }
+        }
+        Ok(settings)
+    }
+}
@@ -0,0 +1,271 @@
+//! Rendering print jobs into indexed PNG images, one raster line per image row.
+
+
+use std::io::Write;
+
+use ptouch_common::model::ModelProfile;
+
+use crate::command::Command;
+use crate::job::JobState;
+use crate::png_settings::PngSettings;
+
+
+const INCHES_PER_METER: f64 = 1000.0 / 25.4;
+
+
+/// Palette index of blank medium.
+pub const PIXEL_WHITE: u8 = 0;
+
+/// Palette index of a marker.
+pub const PIXEL_BLACK: u8 = 1;
+
+/// Palette index of the line that marks a print command.
+pub const PIXEL_PRINT: u8 = 2;
+
+/// Palette index of the line that marks a print-with-feed command.
+pub const PIXEL_PRINT_FEED: u8 = 3;
+
+/// Palette index of the blank medium fed as a margin before and after a page.
+pub const PIXEL_FEED_MARGIN: u8 = 4;
+
+/// Palette index of the line that marks a full cut.
+pub const PIXEL_CUT: u8 = 5;
+
+/// Palette index of the line that marks a half cut.
+pub const PIXEL_HALF_CUT: u8 = 6;
+
+/// Palette index of a marker printed in red by a two-colour printer.
+pub const PIXEL_RED: u8 = 7;
+
+const PALETTE: [u8; 24] = [
+    0xFF, 0xFF, 0xFF, // 0 = white (medium)
+    0x00, 0x00, 0x00, // 1 = black (marker)
+    0xFF, 0x00, 0x00, // 2 = red (print)
+    0x00, 0x00, 0xFF, // 3 = blue (print+feed)
+    0xE0, 0xE0, 0xE0, // 4 = light gray (feed margin)
+    0xFF, 0x00, 0xFF, // 5 = magenta (full cut)
+    0xFF, 0x80, 0x00, // 6 = orange (half cut)
+    0xDC, 0x14, 0x3C, // 7 = crimson (red marker)
+];
+
+
+/// What to render besides the raster lines and print commands.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct RenderOptions {
+    /// Render the feed margin (`ESC i d`) before and after each page.
+    pub feed_margins: bool,
+
+    /// Render a line after each page where the tape is cut.
+    pub cut_marks: bool,
+
+    /// Store the physical resolution of the given model in the image, taking high-resolution
+    /// printing into account.
+    pub scale: Option<&'static ModelProfile>,
+
+    /// Store the settings of the job and the position of each page in text chunks of the image.
+    pub embed_settings: bool,
+}
+
+
+/// Renders the commands of one print job into image rows.
+///
+/// The dimensions and settings of the job must have been collected beforehand, i.e. the commands
+/// have to be read twice.
+pub struct Renderer {
+    options: RenderOptions,
+    width: usize,
+    height: usize,
+    hi_res: bool,
+
+    /// The number of rows of each feed margin, if feed margins are rendered.
+    margin_rows: usize,
+
+    /// Cut after every this many pages, if automatic cutting is enabled.
+    cut_every: Option<usize>,
+    half_cut: bool,
+
+    page_count: usize,
+    page_index: usize,
+    page_started: bool,
+
+    /// The settings to store in the image, if requested.
+    settings: Option<PngSettings>,
+}
+impl Renderer {
+    pub fn new(options: RenderOptions, job: &JobState) -> Self {
+        let settings = job.settings();
+        let hi_res = settings.hi_res == Some(true);
+
+        // the feed margin is given in dots at the resolution across the tape
+        let margin_rows = if options.feed_margins {
+            usize::from(settings.feed_amount.unwrap_or(0)) * if hi_res { 2 } else { 1 }
+        } else {
+            0
+        };
+        let cut_every = (settings.auto_cut == Some(true))
+            .then(|| usize::from(settings.cut_each_n_labels.unwrap_or(1)).max(1));
+        let half_cut = settings.half_cut == Some(true);
+
+        let mut renderer = Self {
+            options,
+            width: job.pixel_data_width(),
+            height: 0,
+            hi_res,
+            margin_rows,
+            cut_every,
+            half_cut,
+            page_count: job.page_count(),
+            page_index: 0,
+            page_started: false,
+            settings: None,
+        };
+
+        // raster lines after the last print command still get a leading margin
+        let margins = 2 * job.page_count() + usize::from(job.pending_rows() > 0);
+        let cut_lines = if options.cut_marks {
+            (0..job.page_count())
+                .filter(|page_index| renderer.cut_after(*page_index) != PIXEL_WHITE)
+                .count()
+        } else {
+            0
+        };
+        renderer.height = job.height() + margins * margin_rows + cut_lines;
+
+        if options.embed_settings {
+            let mut page_starts = Vec::with_capacity(job.page_count());
+            let mut row = 0;
+            for (page_index, page_rows) in job.printed_page_rows().iter().enumerate() {
+                page_starts.push(row + margin_rows);
+                row += 2 * margin_rows + page_rows + 1;
+                if options.cut_marks && renderer.cut_after(page_index) != PIXEL_WHITE {
+                    row += 1;
+                }
+            }
+            renderer.settings = Some(PngSettings::from_job(job, &page_starts));
+        }
+        renderer
+    }
+
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
+
+    /// Whether the image has any pixels at all.
+    pub fn has_image(&self) -> bool {
+        self.width > 0 && self.height > 0
+    }
+
+    /// Returns the palette index of the cut line after the page at the given index, or
+    /// [`PIXEL_WHITE`] if the tape is not cut there.
+    fn cut_after(&self, page_index: usize) -> u8 {
+        let Some(cut_every) = self.cut_every else {
+            return PIXEL_WHITE;
+        };
+        if (page_index + 1).is_multiple_of(cut_every) || page_index + 1 == self.page_count {
+            PIXEL_CUT
+        } else if self.half_cut {
+            PIXEL_HALF_CUT
+        } else {
+            PIXEL_WHITE
+        }
+    }
+
+    /// Writes the PNG header and returns a writer that takes the rows returned by
+    /// [`rows`](Self::rows).
+    pub fn start_png<W: Write>(&self, writer: W) -> png::StreamWriter<'static, W> {
+        let mut png_enc = png::Encoder::new(
+            writer,
+            self.width.try_into().unwrap(),
+            self.height.try_into().unwrap(),
+        );
+        png_enc.set_color(png::ColorType::Indexed);
+        png_enc.set_depth(png::BitDepth::Four);
+        png_enc.set_palette(&PALETTE[..]);
+        if let Some(profile) = self.options.scale {
+            // raster lines run across the tape, image rows along it
+            let pixels_per_meter = |dpi: u16| (f64::from(dpi) * INCHES_PER_METER).round() as u32;
+            png_enc.set_pixel_dims(Some(png::PixelDimensions {
+                xppu: pixels_per_meter(profile.dpi),
+                yppu: pixels_per_meter(profile.feed_dpi(self.hi_res)),
+                unit: png::Unit::Meter,
+            }));
+        }
+        if let Some(settings) = &self.settings {
+            for (keyword, text) in settings.text_chunks() {
+                png_enc.add_text_chunk(keyword, text)
+                    .expect("failed to add PNG text chunk");
+            }
+        }
+        let png_wr = png_enc.write_header()
+            .expect("failed to write PNG header");
+        png_wr.into_stream_writer()
+            .expect("failed to obtain stream writer")
+    }
+
+    /// Returns the image rows for the given command, packed as the PNG encoder expects them.
+    pub fn rows(&mut self, command: &Command) -> Vec<Vec<u8>> {
+        let mut rows = Vec::new();
+        let starts_page = matches!(command, Command::Raster(_)|Command::QlRaster(_)|Command::TwoColorRaster { .. }|Command::ZeroRaster|Command::Print|Command::PrintFeed);
+        if starts_page && !self.page_started {
+            self.page_started = true;
+            rows.extend(self.margin());
+        }
+        match command {
+            Command::Raster(data)|Command::QlRaster(data) => rows.push(self.raster_row(data)),
+            Command::TwoColorRaster { black, red } => rows.push(self.two_color_row(black, red)),
+            Command::ZeroRaster => rows.push(self.pack_row(std::iter::empty())),
+            Command::Print|Command::PrintFeed => {
+                rows.extend(self.margin());
+                let print_pixel = if *command == Command::Print { PIXEL_PRINT } else { PIXEL_PRINT_FEED };
+                rows.push(self.pack_row(std::iter::repeat(print_pixel)));
+                let cut_pixel = self.cut_after(self.page_index);
+                if self.options.cut_marks && cut_pixel != PIXEL_WHITE {
+                    rows.push(self.pack_row(std::iter::repeat(cut_pixel)));
+                }
+                self.page_index += 1;
+                self.page_started = false;
+            },
+            _ => {},
+        }
+        rows
+    }
+
+    fn margin(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..self.margin_rows).map(|_| self.pack_row(std::iter::repeat(PIXEL_FEED_MARGIN)))
+    }
+
+    fn pack_row<I: Iterator<Item = u8>>(&self, pixels: I) -> Vec<u8> {
+        let mut row = vec![0u8; self.width.div_ceil(2)];
+        for (x, pixel) in pixels.take(self.width).enumerate() {
+            row[x / 2] |= pixel << (4 - 4 * (x % 2));
+        }
+        row
+    }
+
+    fn raster_row(&self, data: &[u8]) -> Vec<u8> {
+        let pixels = data
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |bit_index| {
+                if (*byte & (1 << bit_index)) == 0 {
+                    PIXEL_WHITE
+                } else {
+                    PIXEL_BLACK
+                }
+            }));
+        self.pack_row(pixels)
+    }
+
+    fn two_color_row(&self, black: &[u8], red: &[u8]) -> Vec<u8> {
+        let is_set = |data: &[u8], x: usize| data.get(x / 8).is_some_and(|byte| byte & (0x80 >> (x % 8)) != 0);
+        let pixels = (0..self.width).map(|x| {
+            // pixels set in both colours are shown in black
+            if is_set(black, x) {
+                PIXEL_BLACK
+            } else if is_set(red, x) {
+                PIXEL_RED
+            } else {
+                PIXEL_WHITE
+            }
+        });
+        self.pack_row(pixels)
+    }
+}
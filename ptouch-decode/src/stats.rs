@@ -0,0 +1,192 @@
+//! Figures about a print job: label lengths, tape consumption, coverage, compression and print time.
+
+
+use ptouch_common::media::MediaType;
+use ptouch_common::model::ModelProfile;
+
+use crate::command::Command;
+
+
+/// The figures of a print job that do not depend on the printer model, collected while its
+/// commands are being read.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct JobStats {
+    hi_res: bool,
+
+    /// The feed margin (`ESC i d`) in dots.
+    feed_dots: u16,
+
+    /// The length of each die-cut label in millimeters, if die-cut labels have been announced.
+    label_length_mm: Option<u8>,
+
+    /// The number of raster lines of each printed page.
+    page_rows: Vec<usize>,
+
+    /// The number of raster lines of the current page so far.
+    current_rows: usize,
+
+    /// The number of printed pixels.
+    black_pixels: u64,
+
+    /// The length of the widest raster line in bytes.
+    widest_line_bytes: usize,
+
+    /// The number of raster lines sent using `Z`.
+    zero_lines: usize,
+
+    /// The number of raster lines sent in two colours (`w`).
+    two_color_lines: usize,
+
+    /// The total length of the raster lines after decompression, in bytes.
+    raw_raster_bytes: u64,
+
+    /// The total length of the raster line commands as sent, in bytes.
+    encoded_raster_bytes: u64,
+}
+impl JobStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the given command, which takes up the given number of bytes in the print data.
+    pub fn observe(&mut self, command: &Command, encoded_length: u64) {
+        match command {
+            Command::AdvancedMode(settings) => {
+                self.hi_res = settings & 0x40 != 0;
+            },
+            Command::Feed(dots) => {
+                self.feed_dots = *dots;
+            },
+            Command::PrintInformation(info_buf) => {
+                let die_cut = info_buf[0] & 0x02 != 0 && MediaType::from_byte(info_buf[1]) == MediaType::DieCutLabels;
+                if die_cut && info_buf[0] & 0x08 != 0 {
+                    self.label_length_mm = Some(info_buf[3]);
+                }
+            },
+            Command::Raster(data)|Command::QlRaster(data) => {
+                self.current_rows += 1;
+                self.black_pixels += data.iter().map(|byte| u64::from(byte.count_ones())).sum::<u64>();
+                self.widest_line_bytes = self.widest_line_bytes.max(data.len());
+                self.raw_raster_bytes += u64::try_from(data.len()).unwrap();
+                self.encoded_raster_bytes += encoded_length;
+            },
+            Command::TwoColorRaster { black, red } => {
+                self.current_rows += 1;
+                self.two_color_lines += 1;
+                // a pixel printed in both colours still counts once
+                let pixels = (0..black.len().max(red.len()))
+                    .map(|i| black.get(i).copied().unwrap_or(0) | red.get(i).copied().unwrap_or(0));
+                self.black_pixels += pixels.map(|byte| u64::from(byte.count_ones())).sum::<u64>();
+                self.widest_line_bytes = self.widest_line_bytes.max(black.len()).max(red.len());
+                self.raw_raster_bytes += u64::try_from(black.len() + red.len()).unwrap();
+                self.encoded_raster_bytes += encoded_length;
+            },
+            Command::ZeroRaster => {
+                self.current_rows += 1;
+                self.zero_lines += 1;
+                self.encoded_raster_bytes += encoded_length;
+            },
+            Command::Print|Command::PrintFeed => {
+                self.page_rows.push(self.current_rows);
+                self.current_rows = 0;
+            },
+            _ => {},
+        }
+    }
+
+    pub fn page_rows(&self) -> &[usize] { &self.page_rows }
+    pub fn black_pixels(&self) -> u64 { self.black_pixels }
+
+    /// The number of pixels of all raster lines if they were all as wide as the widest one.
+    pub fn total_pixels(&self) -> u64 {
+        let rows: usize = self.page_rows.iter().sum::<usize>() + self.current_rows;
+        u64::try_from(rows * self.widest_line_bytes * 8).unwrap()
+    }
+
+    /// The total length of the raster line commands as sent, in bytes.
+    pub fn encoded_raster_bytes(&self) -> u64 { self.encoded_raster_bytes }
+
+    /// The total length of the raster line commands if they were sent without compression, in
+    /// bytes.
+    ///
+    /// Each transfer takes three bytes of command and length (or colour and length), and a line
+    /// sent in two colours takes two transfers. Lines sent using `Z` count as blank lines as wide
+    /// as the widest line, and in jobs with two-colour lines as blank lines in both colours.
+    pub fn raw_raster_bytes(&self) -> u64 {
+        let rows = self.page_rows.iter().sum::<usize>() + self.current_rows;
+        let planes = if self.two_color_lines > 0 { 2 } else { 1 };
+        let transfers = rows + self.two_color_lines + (planes - 1) * self.zero_lines;
+        let zero_bytes = self.zero_lines * planes * self.widest_line_bytes;
+        self.raw_raster_bytes + u64::try_from(3 * transfers + zero_bytes).unwrap()
+    }
+
+    /// The length of each printed page in millimeters, including the feed margins before and after
+    /// it. Pages on die-cut labels take up at least one whole label.
+    pub fn page_lengths_mm(&self, profile: &ModelProfile) -> Vec<f64> {
+        let feed_mm = profile.lines_to_mm(usize::from(self.feed_dots), false);
+        let min_mm = self.label_length_mm.map_or(0.0, f64::from);
+        self.page_rows
+            .iter()
+            .map(|rows| (profile.lines_to_mm(*rows, self.hi_res) + 2.0 * feed_mm).max(min_mm))
+            .collect()
+    }
+
+    /// The length of tape consumed by the job in millimeters, including the leader.
+    pub fn tape_consumed_mm(&self, profile: &ModelProfile) -> f64 {
+        if self.page_rows.is_empty() {
+            return 0.0;
+        }
+        let labels_mm: f64 = self.page_lengths_mm(profile).iter().sum();
+        labels_mm + f64::from(profile.leader_mm)
+    }
+
+    /// The time it takes to feed all the consumed tape past the print head at the model's print
+    /// speed, in seconds.
+    pub fn print_time_s(&self, profile: &ModelProfile) -> f64 {
+        self.tape_consumed_mm(profile) / f64::from(profile.print_speed_mm_per_s)
+    }
+
+    /// Outputs the figures of the print job as printed on a printer with the given profile.
+    pub fn print_report(&self, profile: &ModelProfile) {
+        let feed_mm = profile.lines_to_mm(usize::from(self.feed_dots), false);
+        for (page_index, (rows, length_mm)) in self.page_rows.iter().zip(self.page_lengths_mm(profile)).enumerate() {
+            println!(
+                "page {}: {:.1} mm ({} raster lines and 2 x {:.1} mm feed margin)",
+                page_index + 1, length_mm, rows, feed_mm,
+            );
+        }
+        if self.current_rows > 0 {
+            println!("not printed: {} raster lines after the last page", self.current_rows);
+        }
+        println!(
+            "tape consumed: {:.1} mm, including {} mm leader",
+            self.tape_consumed_mm(profile), profile.leader_mm,
+        );
+
+        let total_pixels = self.total_pixels();
+        if total_pixels > 0 {
+            println!(
+                "black pixel coverage: {:.1}% ({} of {} pixels)",
+                100.0 * (self.black_pixels as f64) / (total_pixels as f64), self.black_pixels, total_pixels,
+            );
+        } else {
+            println!("black pixel coverage: no pixels");
+        }
+
+        let raw_bytes = self.raw_raster_bytes();
+        if raw_bytes > 0 {
+            println!(
+                "raster data: {} bytes as sent, {} bytes uncompressed ({:.1}%)",
+                self.encoded_raster_bytes, raw_bytes,
+                100.0 * (self.encoded_raster_bytes as f64) / (raw_bytes as f64),
+            );
+        } else {
+            println!("raster data: none");
+        }
+
+        println!(
+            "estimated print time: {:.1} s at {} mm/s",
+            self.print_time_s(profile), profile.print_speed_mm_per_s,
+        );
+    }
+}
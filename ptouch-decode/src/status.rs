@@ -0,0 +1,168 @@
+use ptouch_common::media::MediaType;
+
+use crate::error::DecodeError;
+
+
+/// The length of a status reply in bytes.
+pub const STATUS_REPLY_LENGTH: usize = 32;
+
+
+/// Why the printer sent a status reply.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum StatusType {
+    ReplyToRequest,
+    PrintingCompleted,
+    ErrorOccurred,
+    TurnedOff,
+    Notification,
+    PhaseChange,
+    Other(u8),
+}
+impl StatusType {
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            0x00 => Self::ReplyToRequest,
+            0x01 => Self::PrintingCompleted,
+            0x02 => Self::ErrorOccurred,
+            0x04 => Self::TurnedOff,
+            0x05 => Self::Notification,
+            0x06 => Self::PhaseChange,
+            other => Self::Other(other),
+        }
+    }
+}
+
+
+/// What the printer is doing.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Phase {
+    Receiving,
+    Printing,
+    Other(u8),
+}
+impl Phase {
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            0x00 => Self::Receiving,
+            0x01 => Self::Printing,
+            other => Self::Other(other),
+        }
+    }
+}
+
+
+/// The descriptions of the bits of the first error information byte.
+const ERROR_1_DESCRIPTIONS: [&str; 8] = [
+    "no media",
+    "end of media",
+    "cutter jam",
+    "weak batteries",
+    "printer in use",
+    "printer turned off",
+    "high-voltage adapter",
+    "fan motor error",
+];
+
+/// The descriptions of the bits of the second error information byte.
+const ERROR_2_DESCRIPTIONS: [&str; 8] = [
+    "replace media",
+    "expansion buffer full",
+    "communication error",
+    "communication buffer full",
+    "cover open",
+    "overheating",
+    "media cannot be fed",
+    "system error",
+];
+
+
+/// A status reply sent by the printer, e.g. in response to `ESC i S`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct StatusReply {
+    pub model_code: u8,
+    pub error_information_1: u8,
+    pub error_information_2: u8,
+    pub media_width_mm: u8,
+    pub media_type: MediaType,
+    pub mode: u8,
+    pub media_length_mm: u8,
+    pub status_type: StatusType,
+    pub phase: Phase,
+    pub phase_number: u16,
+    pub notification_number: u8,
+    pub tape_color: u8,
+    pub text_color: u8,
+}
+impl StatusReply {
+    /// Parses a 32-byte status reply.
+    pub fn parse(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() != STATUS_REPLY_LENGTH {
+            return Err(DecodeError::StatusReplyLength(bytes.len()));
+        }
+
+        // print head mark, size, Brother code, series code
+        let header: [u8; 4] = bytes[0..4].try_into().unwrap();
+        if header != [0x80, 0x20, b'B', b'0'] {
+            return Err(DecodeError::StatusReplyHeader(header));
+        }
+
+        Ok(Self {
+            model_code: bytes[4],
+            error_information_1: bytes[8],
+            error_information_2: bytes[9],
+            media_width_mm: bytes[10],
+            media_type: MediaType::from_byte(bytes[11]),
+            mode: bytes[15],
+            media_length_mm: bytes[17],
+            status_type: StatusType::from_byte(bytes[18]),
+            phase: Phase::from_byte(bytes[19]),
+            phase_number: u16::from_be_bytes([bytes[20], bytes[21]]),
+            notification_number: bytes[22],
+            tape_color: bytes[24],
+            text_color: bytes[25],
+        })
+    }
+
+    /// Returns the descriptions of all errors reported in this status reply.
+    pub fn errors(&self) -> Vec<&'static str> {
+        let mut errors = Vec::new();
+        for (byte, descriptions) in [(self.error_information_1, &ERROR_1_DESCRIPTIONS), (self.error_information_2, &ERROR_2_DESCRIPTIONS)] {
+            for (bit_index, description) in descriptions.iter().enumerate() {
+                if byte & (1 << bit_index) != 0 {
+                    errors.push(*description);
+                }
+            }
+        }
+        errors
+    }
+
+    /// Outputs the contents of the status reply.
+    pub fn print_report(&self) {
+        let status_type = match self.status_type {
+            StatusType::ReplyToRequest => "reply to status request".to_owned(),
+            StatusType::PrintingCompleted => "printing completed".to_owned(),
+            StatusType::ErrorOccurred => "error occurred".to_owned(),
+            StatusType::TurnedOff => "turned off".to_owned(),
+            StatusType::Notification => "notification".to_owned(),
+            StatusType::PhaseChange => "phase change".to_owned(),
+            StatusType::Other(other) => format!("unknown ({:#04X})", other),
+        };
+        let phase = match self.phase {
+            Phase::Receiving => "receiving".to_owned(),
+            Phase::Printing => "printing".to_owned(),
+            Phase::Other(other) => format!("unknown ({:#04X})", other),
+        };
+        let errors = self.errors();
+        println!("status type: {}", status_type);
+        println!("phase: {} (number {:#06X})", phase, self.phase_number);
+        println!("model code: {:#04X}", self.model_code);
+        println!("media type: {}", self.media_type);
+        println!("media width: {} mm", self.media_width_mm);
+        println!("media length: {} mm", self.media_length_mm);
+        if errors.is_empty() {
+            println!("errors: none");
+        } else {
+            println!("errors: {}", errors.join(", "));
+        }
+    }
+}
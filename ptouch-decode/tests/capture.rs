@@ -0,0 +1,282 @@
+//! Builds packet captures of print jobs being sent to a printer and checks that the print data and
+//! status replies are extracted from them.
+
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use ptouch_decode::capture::{CapturedStream, RAW_PRINTING_PORT, StreamSource, extract_streams};
+
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_USB_LINUX: u32 = 189;
+const LINKTYPE_USBPCAP: u32 = 249;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+
+fn corpus_job(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("corpus").join(name)
+}
+
+fn status_reply(status_type: u8) -> Vec<u8> {
+    let mut reply = vec![0u8; 32];
+    reply[0..4].copy_from_slice(&[0x80, 0x20, b'B', b'0']);
+    reply[4] = 0x68;
+    reply[10] = 12;
+    reply[11] = 0x01;
+    reply[18] = status_type;
+    reply
+}
+
+/// Builds a little-endian pcap file.
+fn pcap(link_type: u32, packets: &[Vec<u8>]) -> Vec<u8> {
+    let mut file = Vec::new();
+    file.extend(0xA1B2_C3D4u32.to_le_bytes());
+    file.extend(2u16.to_le_bytes());
+    file.extend(4u16.to_le_bytes());
+    file.extend([0u8; 8]);
+    file.extend(65535u32.to_le_bytes());
+    file.extend(link_type.to_le_bytes());
+    for (i, packet) in packets.iter().enumerate() {
+        let length = u32::try_from(packet.len()).unwrap();
+        file.extend(u32::try_from(i).unwrap().to_le_bytes());
+        file.extend(0u32.to_le_bytes());
+        file.extend(length.to_le_bytes());
+        file.extend(length.to_le_bytes());
+        file.extend(packet);
+    }
+    file
+}
+
+/// Builds a big-endian pcapng file with one interface.
+fn pcapng(link_type: u16, packets: &[Vec<u8>]) -> Vec<u8> {
+    fn block(file: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+        let padded_length = body.len().div_ceil(4) * 4;
+        let block_length = u32::try_from(12 + padded_length).unwrap();
+        file.extend(block_type.to_be_bytes());
+        file.extend(block_length.to_be_bytes());
+        file.extend(body);
+        file.extend(vec![0u8; padded_length - body.len()]);
+        file.extend(block_length.to_be_bytes());
+    }
+
+    let mut file = Vec::new();
+    let mut section_header = Vec::new();
+    section_header.extend(0x1A2B_3C4Du32.to_be_bytes());
+    section_header.extend(1u16.to_be_bytes());
+    section_header.extend(0u16.to_be_bytes());
+    section_header.extend((-1i64).to_be_bytes());
+    block(&mut file, 0x0A0D_0D0A, &section_header);
+
+    let mut interface = Vec::new();
+    interface.extend(link_type.to_be_bytes());
+    interface.extend(0u16.to_be_bytes());
+    interface.extend(0u32.to_be_bytes());
+    block(&mut file, 0x0000_0001, &interface);
+
+    for packet in packets {
+        let length = u32::try_from(packet.len()).unwrap();
+        let mut enhanced_packet = Vec::new();
+        enhanced_packet.extend(0u32.to_be_bytes());
+        enhanced_packet.extend([0u8; 8]);
+        enhanced_packet.extend(length.to_be_bytes());
+        enhanced_packet.extend(length.to_be_bytes());
+        enhanced_packet.extend(packet);
+        block(&mut file, 0x0000_0006, &enhanced_packet);
+    }
+    file
+}
+
+/// Builds a packet as captured by usbmon.
+fn usbmon(event: u8, transfer_type: u8, endpoint: u8, device: u8, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; 48];
+    packet[8] = event;
+    packet[9] = transfer_type;
+    packet[10] = endpoint;
+    packet[11] = device;
+    packet[12..14].copy_from_slice(&3u16.to_le_bytes());
+    packet[32..36].copy_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+    packet[36..40].copy_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+    packet.extend(data);
+    packet
+}
+
+/// Builds a packet as captured by USBPcap.
+fn usbpcap(from_device: bool, transfer_type: u8, endpoint: u8, device: u16, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; 27];
+    packet[0..2].copy_from_slice(&27u16.to_le_bytes());
+    packet[16] = if from_device { 0x01 } else { 0x00 };
+    packet[17..19].copy_from_slice(&1u16.to_le_bytes());
+    packet[19..21].copy_from_slice(&device.to_le_bytes());
+    packet[21] = endpoint;
+    packet[22] = transfer_type;
+    packet[23..27].copy_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+    packet.extend(data);
+    packet
+}
+
+/// Builds an Ethernet frame containing a TCP segment over IPv4.
+fn tcp(source: ([u8; 4], u16), destination: ([u8; 4], u16), sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; 12];
+    frame.extend(0x0800u16.to_be_bytes());
+
+    let total_length = u16::try_from(20 + 20 + payload.len()).unwrap();
+    frame.extend([0x45, 0x00]);
+    frame.extend(total_length.to_be_bytes());
+    frame.extend([0x00, 0x00, 0x40, 0x00, 64, 6, 0x00, 0x00]);
+    frame.extend(source.0);
+    frame.extend(destination.0);
+
+    frame.extend(source.1.to_be_bytes());
+    frame.extend(destination.1.to_be_bytes());
+    frame.extend(sequence.to_be_bytes());
+    frame.extend(0u32.to_be_bytes());
+    frame.extend([0x50, flags, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00]);
+    frame.extend(payload);
+
+    // Ethernet pads short frames
+    frame.resize(frame.len().max(60), 0x00);
+    frame
+}
+
+fn usb_stream(streams: &[CapturedStream], bus: u16, device: u16) -> &CapturedStream {
+    streams.iter()
+        .find(|stream| stream.source == StreamSource::Usb { bus, device })
+        .expect("USB device not found in capture")
+}
+
+
+#[test]
+fn usbmon_bulk_transfers_are_extracted() {
+    let job = std::fs::read(corpus_job("pt-e550w-12mm-serial.prn")).unwrap();
+    let mut packets = vec![
+        // control transfer of the enumeration and an unrelated device
+        usbmon(b'S', 2, 0x80, 7, &[0x12, 0x01]),
+        usbmon(b'S', 1, 0x81, 2, &[0x01, 0x02, 0x03]),
+    ];
+    for chunk in job.chunks(512) {
+        packets.push(usbmon(b'S', 3, 0x02, 7, chunk));
+        // the completion of an outgoing transfer carries no data
+        packets.push(usbmon(b'C', 3, 0x02, 7, &[]));
+    }
+    // the submission of an incoming transfer carries no data either
+    packets.push(usbmon(b'S', 3, 0x81, 7, &[]));
+    packets.push(usbmon(b'C', 3, 0x81, 7, &status_reply(0x06)));
+    packets.push(usbmon(b'C', 3, 0x81, 7, &status_reply(0x01)));
+
+    let streams = extract_streams(&pcap(LINKTYPE_USB_LINUX, &packets), RAW_PRINTING_PORT).unwrap();
+    assert_eq!(streams.len(), 1);
+    let stream = usb_stream(&streams, 3, 7);
+    assert_eq!(stream.to_printer, job);
+    assert_eq!(stream.from_printer, [status_reply(0x06), status_reply(0x01)].concat());
+}
+
+#[test]
+fn usbpcap_bulk_transfers_are_extracted_from_pcapng() {
+    let first_job = std::fs::read(corpus_job("pt-e550w-12mm-serial.prn")).unwrap();
+    let second_job = std::fs::read(corpus_job("pt-p750w-24mm-hires.prn")).unwrap();
+    let mut packets = Vec::new();
+    for chunk in first_job.chunks(1000) {
+        packets.push(usbpcap(false, 3, 0x02, 5, chunk));
+        packets.push(usbpcap(true, 3, 0x02, 5, &[]));
+    }
+    packets.push(usbpcap(true, 3, 0x81, 5, &status_reply(0x01)));
+    for chunk in second_job.chunks(4096) {
+        packets.push(usbpcap(false, 3, 0x01, 9, chunk));
+    }
+
+    let streams = extract_streams(&pcapng(LINKTYPE_USBPCAP.try_into().unwrap(), &packets), RAW_PRINTING_PORT).unwrap();
+    assert_eq!(streams.len(), 2);
+    assert_eq!(usb_stream(&streams, 1, 5).to_printer, first_job);
+    assert_eq!(usb_stream(&streams, 1, 5).from_printer, status_reply(0x01));
+    assert_eq!(usb_stream(&streams, 1, 9).to_printer, second_job);
+    assert!(usb_stream(&streams, 1, 9).from_printer.is_empty());
+}
+
+#[test]
+fn tcp_segments_are_reassembled() {
+    let job = std::fs::read(corpus_job("pt-p950nw-36mm-trimmed.prn")).unwrap();
+    let host = ([192, 168, 1, 10], 50123);
+    let printer = ([192, 168, 1, 20], RAW_PRINTING_PORT);
+    let initial_sequence = 0xFFFF_F000u32;
+
+    let mut segments: Vec<Vec<u8>> = job.chunks(700)
+        .scan(initial_sequence.wrapping_add(1), |sequence, chunk| {
+            let segment = tcp(host, printer, *sequence, TCP_ACK, chunk);
+            *sequence = sequence.wrapping_add(u32::try_from(chunk.len()).unwrap());
+            Some(segment)
+        })
+        .collect();
+    // reorder two segments and retransmit another one
+    segments.swap(1, 2);
+    segments.insert(4, segments[3].clone());
+
+    let mut packets = vec![
+        tcp(host, printer, initial_sequence, TCP_SYN, &[]),
+        tcp(printer, host, 0x1000, TCP_SYN|TCP_ACK, &[]),
+        tcp(([192, 168, 1, 10], 50124), ([10, 0, 0, 1], 80), 1, TCP_ACK, b"GET / HTTP/1.1\r\n"),
+    ];
+    packets.extend(segments);
+    packets.push(tcp(printer, host, 0x1001, TCP_ACK, &status_reply(0x01)));
+
+    let streams = extract_streams(&pcapng(LINKTYPE_ETHERNET.try_into().unwrap(), &packets), RAW_PRINTING_PORT).unwrap();
+    assert_eq!(streams.len(), 1);
+    assert_eq!(
+        streams[0].source,
+        StreamSource::Tcp { host: "192.168.1.10:50123".parse().unwrap(), printer: "192.168.1.20:9100".parse().unwrap() },
+    );
+    assert_eq!(streams[0].to_printer, job);
+    assert_eq!(streams[0].from_printer, status_reply(0x01));
+    assert_eq!(streams[0].missing_bytes, 0);
+
+    // without one of the segments, the rest is joined and the gap is counted
+    packets.remove(5);
+    let streams = extract_streams(&pcap(LINKTYPE_ETHERNET, &packets), RAW_PRINTING_PORT).unwrap();
+    assert_eq!(streams[0].missing_bytes, 700);
+    assert_eq!(streams[0].to_printer.len(), job.len() - 700);
+}
+
+#[test]
+fn captures_are_decoded_like_print_data() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let job_path = corpus_job("pt-e550w-18mm-flag");
+    let job = std::fs::read(job_path.with_extension("prn")).unwrap();
+    let mut packets: Vec<Vec<u8>> = job.chunks(64)
+        .map(|chunk| usbmon(b'S', 3, 0x02, 4, chunk))
+        .collect();
+    packets.push(usbmon(b'C', 3, 0x81, 4, &status_reply(0x01)));
+    let capture_path = out_dir.path().join("capture.pcap");
+    std::fs::write(&capture_path, pcap(LINKTYPE_USB_LINUX, &packets)).unwrap();
+
+    let decoded_path = out_dir.path().join("capture.png");
+    let extracted_path = out_dir.path().join("extracted.prn");
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .arg("--extract-print-data")
+        .arg(&extracted_path)
+        .arg(&capture_path)
+        .arg(&decoded_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(output.status.success(), "failed to decode capture: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(std::fs::read(&extracted_path).unwrap(), job);
+    assert_eq!(std::fs::read(&decoded_path).unwrap(), {
+        let reference_path = out_dir.path().join("reference.png");
+        let status = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+            .arg(job_path.with_extension("prn"))
+            .arg(&reference_path)
+            .stdout(std::process::Stdio::null())
+            .status()
+            .expect("failed to run ptouch-decode");
+        assert!(status.success());
+        std::fs::read(&reference_path).unwrap()
+    });
+
+    let report = String::from_utf8_lossy(&output.stdout);
+    let expected_report = std::fs::read_to_string(job_path.with_extension("txt")).unwrap();
+    assert!(report.starts_with(&format!("USB device 3.4: {} bytes to printer, 32 bytes from printer\n\n", job.len())));
+    assert!(report.contains(&expected_report));
+    assert!(report.ends_with("status reply 1:\nstatus type: printing completed\nphase: receiving (number 0x0000)\nmodel code: 0x68\nmedia type: laminated tape\nmedia width: 12 mm\nmedia length: 0 mm\nerrors: none\n"));
+}
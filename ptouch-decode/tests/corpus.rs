@@ -0,0 +1,532 @@
+//! Decodes every print job in `tests/corpus` and compares the result with the snapshot of the
+//! report (`.txt`) and image (`.png`) next to it.
+//!
+//! Run with `UPDATE_SNAPSHOTS=1` to (re)generate the snapshots after an intentional change.
+
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use ptouch_common::model::Model;
+use ptouch_decode::command::{self, CommandReader, CompressionMode};
+use ptouch_decode::error::DecodeError;
+use ptouch_decode::job::{JobState, Limits};
+use ptouch_decode::lint::{LintProblem, Linter};
+use ptouch_decode::png_settings::PngSettings;
+
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("corpus")
+}
+
+/// Returns the dimensions and palette indexes of an indexed PNG image.
+fn read_indexed_png(path: &Path) -> (u32, u32, Vec<u8>) {
+    let file = File::open(path)
+        .expect("failed to open PNG file");
+    let dec = png::Decoder::new(BufReader::new(file));
+    let mut reader = dec.read_info()
+        .expect("failed to decode PNG file");
+    let mut buf = vec![0u8; reader.output_buffer_size().unwrap()];
+    let info = reader.next_frame(&mut buf)
+        .expect("failed to read PNG image data");
+    assert_eq!(info.color_type, png::ColorType::Indexed);
+
+    let bits: usize = match info.bit_depth {
+        png::BitDepth::One => 1,
+        png::BitDepth::Two => 2,
+        png::BitDepth::Four => 4,
+        png::BitDepth::Eight => 8,
+        png::BitDepth::Sixteen => panic!("indexed PNG with 16-bit depth"),
+    };
+    // unpack the indexes into one byte each so that the bit depth does not matter
+    let width: usize = info.width.try_into().unwrap();
+    let mut pixels = Vec::with_capacity(width * usize::try_from(info.height).unwrap());
+    for line in buf.chunks_exact(info.line_size).take(info.height.try_into().unwrap()) {
+        for x in 0..width {
+            let bit_pos = x * bits;
+            let shift = 8 - bits - (bit_pos % 8);
+            pixels.push((line[bit_pos / 8] >> shift) & ((1 << bits) - 1));
+        }
+    }
+    (info.width, info.height, pixels)
+}
+
+#[test]
+fn corpus_decodes_as_expected() {
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+    let out_dir = tempfile::tempdir().unwrap();
+
+    let mut job_paths: Vec<PathBuf> = std::fs::read_dir(corpus_dir())
+        .expect("failed to list corpus")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "prn"))
+        .collect();
+    job_paths.sort();
+    assert!(!job_paths.is_empty(), "corpus is empty");
+
+    for job_path in &job_paths {
+        let report_path = job_path.with_extension("txt");
+        let image_path = job_path.with_extension("png");
+        let decoded_path = out_dir.path().join("decoded.png");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+            .arg(job_path)
+            .arg(&decoded_path)
+            .output()
+            .expect("failed to run ptouch-decode");
+        assert!(output.status.success(), "failed to decode {}", job_path.display());
+
+        if update {
+            std::fs::write(&report_path, &output.stdout)
+                .expect("failed to write expected report");
+            std::fs::copy(&decoded_path, &image_path)
+                .expect("failed to write expected image");
+            continue;
+        }
+
+        let expected_report = std::fs::read_to_string(&report_path)
+            .expect("failed to read expected report");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected_report, "report of {}", job_path.display());
+        assert!(
+            read_indexed_png(&decoded_path) == read_indexed_png(&image_path),
+            "image of {} differs from the expected image", job_path.display(),
+        );
+    }
+}
+
+#[test]
+fn concatenated_jobs_are_decoded_separately() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let first = corpus_dir().join("pt-e550w-12mm-serial");
+    let second = corpus_dir().join("pt-p750w-24mm-hires");
+
+    // as captured from a spooler: one job after the other, with some padding at the end
+    let mut print_data = std::fs::read(first.with_extension("prn")).unwrap();
+    print_data.extend(std::fs::read(second.with_extension("prn")).unwrap());
+    print_data.extend([0x00; 64]);
+    let print_data_path = out_dir.path().join("jobs.prn");
+    std::fs::write(&print_data_path, &print_data).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .arg(&print_data_path)
+        .arg(out_dir.path().join("jobs.png"))
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(output.status.success(), "failed to decode concatenated jobs");
+
+    for (job_number, job_path) in [(1, &first), (2, &second)] {
+        let decoded_path = out_dir.path().join(format!("jobs-{}.png", job_number));
+        assert!(
+            read_indexed_png(&decoded_path) == read_indexed_png(&job_path.with_extension("png")),
+            "image of job {} differs from the expected image", job_number,
+        );
+    }
+    assert!(!out_dir.path().join("jobs-3.png").exists());
+
+    let report = String::from_utf8_lossy(&output.stdout);
+    let expected_report = format!(
+        "job 1 ({}):\n{}\njob 2 ({}):\n{}",
+        out_dir.path().join("jobs-1.png").display(),
+        std::fs::read_to_string(first.with_extension("txt")).unwrap(),
+        out_dir.path().join("jobs-2.png").display(),
+        std::fs::read_to_string(second.with_extension("txt")).unwrap(),
+    );
+    assert_eq!(report, expected_report);
+}
+
+#[test]
+fn lenient_mode_skips_what_it_cannot_decode() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let job_path = corpus_dir().join("pt-e550w-12mm-serial");
+    let print_data = std::fs::read(job_path.with_extension("prn")).unwrap();
+
+    // a short invalidate command and some garbage in front of a raster line
+    let raster_pos = 200 + print_data[200..].iter().position(|b| *b == b'G').unwrap();
+    let mut damaged = vec![0x00; 16];
+    damaged.extend(&print_data[200..raster_pos]);
+    damaged.extend(b"\x99garbage");
+    damaged.extend(&print_data[raster_pos..]);
+    let damaged_path = out_dir.path().join("damaged.prn");
+    std::fs::write(&damaged_path, &damaged).unwrap();
+    let decoded_path = out_dir.path().join("damaged.png");
+
+    let strict = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .arg(&damaged_path)
+        .arg(&decoded_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(!strict.status.success(), "damaged print data decoded without --lenient");
+
+    let lenient = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .arg("--lenient")
+        .arg(&damaged_path)
+        .arg(&decoded_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(lenient.status.success(), "failed to decode damaged print data with --lenient");
+
+    let garbage_offset = raster_pos - 200 + 16;
+    assert_eq!(
+        String::from_utf8_lossy(&lenient.stderr),
+        format!("warning: skipped 8 bytes at offset {} ({:#X}): unexpected command [99]\n", garbage_offset, garbage_offset),
+    );
+    let expected_report = std::fs::read_to_string(job_path.with_extension("txt")).unwrap();
+    assert_eq!(String::from_utf8_lossy(&lenient.stdout), expected_report);
+    assert!(
+        read_indexed_png(&decoded_path) == read_indexed_png(&job_path.with_extension("png")),
+        "image of damaged print data differs from the expected image",
+    );
+}
+
+#[test]
+fn fingerprints_point_at_the_model_of_each_job() {
+    const LIMITS: Limits = Limits {
+        max_width: 720,
+        max_rows_per_page: 1_000_000,
+        max_pages: 1000,
+        max_decompressed_bytes: 256 * 1024 * 1024,
+    };
+
+    for entry in std::fs::read_dir(corpus_dir()).expect("failed to list corpus") {
+        let job_path = entry.unwrap().path();
+        if job_path.extension().is_none_or(|ext| ext != "prn") {
+            continue;
+        }
+        // the files are named after the model, e.g. "pt-e550w-12mm-serial.prn"
+        let file_name = job_path.file_name().unwrap().to_str().unwrap();
+        let model: Model = file_name.split('-').take(2).collect::<Vec<_>>().join("-").parse()
+            .expect("corpus file not named after a model");
+
+        let data = std::fs::read(&job_path).unwrap();
+        let mut reader = CommandReader::new(data.as_slice()).expect("invalid print job header");
+        let mut job = JobState::new(LIMITS);
+        job.set_invalidate_length(reader.invalidate_length());
+        while let Some(command) = reader.next_command().expect("invalid command") {
+            job.apply(&command).expect("command rejected");
+        }
+
+        let evidence = job.evidence();
+        assert_eq!(evidence.invalidate_length, Some(350), "{}", file_name);
+        assert!(evidence.matches_ptouch_encode(), "{}", file_name);
+        let guesses = evidence.guess_models();
+        let likely: Vec<Model> = guesses
+            .iter()
+            .take_while(|guess| guess.contradicting.is_empty())
+            .map(|guess| guess.model)
+            .collect();
+        assert!(likely.contains(&model), "{} not among the likely models {:?}", model, likely);
+        // the print head width and page byte tell the two families apart
+        assert!(likely.iter().all(|m| m.profile().head_pins == model.profile().head_pins), "{}: {:?}", file_name, likely);
+    }
+}
+
+#[test]
+fn lint_accepts_a_clean_job_and_lists_every_problem_of_a_broken_one() {
+    let job_path = corpus_dir().join("pt-e550w-12mm-serial.prn");
+    let clean = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .args(["--lint", "pt-e550w"])
+        .arg(&job_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(clean.status.success(), "clean job failed linting");
+    assert!(String::from_utf8_lossy(&clean.stdout).contains("lint (PT-E550W): 0 problems\n"));
+
+    // the same job checked against a model with a wider print head
+    let wrong_model = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .args(["--lint", "pt-p950nw"])
+        .arg(&job_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(!wrong_model.status.success(), "job for another model passed linting");
+
+    // two pages: a short first one announced with the wrong raster count and the wrong
+    // printing command, then a last one with narrow lines and compression switched midway
+    let mut info = [0x86, 0x01, 12, 0, 0, 0, 0, 0, 1, 0];
+    info[4..8].copy_from_slice(&5u32.to_le_bytes());
+    let mut commands = vec![
+        command::Command::Invalidate,
+        command::Command::Initialize,
+        command::Command::AdvancedMode(0x01|0x08),
+        command::Command::Feed(2000),
+        command::Command::Compression(CompressionMode::PackBits),
+        command::Command::PrintInformation(info),
+    ];
+    commands.extend(std::iter::repeat_n(command::Command::ZeroRaster, 3));
+    commands.push(command::Command::PrintFeed);
+    info[4..8].copy_from_slice(&200u32.to_le_bytes());
+    commands.push(command::Command::PrintInformation(info));
+    commands.extend(std::iter::repeat_n(command::Command::Raster(vec![0xFF; 8]), 100));
+    commands.push(command::Command::Compression(CompressionMode::Raw));
+    commands.extend(std::iter::repeat_n(command::Command::Raster(vec![0xFF; 16]), 100));
+    commands.push(command::Command::Print);
+
+    let mut linter = Linter::new(Model::PtP700);
+    for command in &commands {
+        linter.observe(command);
+    }
+    let mut problems = linter.finish();
+    problems.sort();
+    let profile = Model::PtP700.profile();
+    let min_lines = profile.mm_to_lines(f64::from(profile.min_length_mm), false);
+    let max_dots = profile.mm_to_lines(f64::from(profile.max_feed_mm), false);
+    let mut expected = vec![
+        LintProblem::UnsupportedFlags(vec!["draft"]),
+        LintProblem::FeedOutOfRange { dots: 2000, max_dots },
+        LintProblem::RasterCountMismatch { page_index: 0, announced: 5, actual: 3 },
+        LintProblem::LabelTooShort { page_index: 0, lines: 3, min_lines, min_length_mm: profile.min_length_mm },
+        LintProblem::CompressionAfterRaster { page_index: 1 },
+        LintProblem::LineWidth { page_index: 1, width_bytes: 8, expected_bytes: 16, count: 100 },
+        LintProblem::UnexpectedPageByte { page_index: 0, page_byte: 1, expected: 0 },
+        LintProblem::FeedBeforeLastPage { page_index: 0 },
+        LintProblem::LastPageWithoutFeed { page_index: 1 },
+    ];
+    expected.sort();
+    assert_eq!(problems, expected);
+}
+
+#[test]
+fn stats_count_label_lengths_leader_and_feed_margins() {
+    let job_path = corpus_dir().join("pt-p750w-24mm-hires.prn");
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .args(["--stats", "--model", "pt-p750w"])
+        .arg(&job_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(output.status.success(), "failed to decode {}", job_path.display());
+
+    // 100 raster lines at 360 dpi (high resolution) plus 14 dots of feed margin at 180 dpi on
+    // either side
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stats = stdout.split_once("statistics (PT-P750W):\n")
+        .expect("no statistics in report")
+        .1;
+    assert_eq!(
+        stats,
+        concat!(
+            "page 1: 11.0 mm (100 raster lines and 2 x 2.0 mm feed margin)\n",
+            "page 2: 11.0 mm (100 raster lines and 2 x 2.0 mm feed margin)\n",
+            "tape consumed: 47.0 mm, including 25 mm leader\n",
+            "black pixel coverage: 28.3% (3620 of 12800 pixels)\n",
+            "raster data: 2190 bytes as sent, 2200 bytes uncompressed (99.5%)\n",
+            "estimated print time: 1.6 s at 30 mm/s\n",
+        ),
+    );
+}
+
+#[test]
+fn decoded_images_carry_the_settings_and_page_layout_of_the_job() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let decoded_path = out_dir.path().join("decoded.png");
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .arg(corpus_dir().join("pt-p750w-24mm-hires.prn"))
+        .arg(&decoded_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(output.status.success(), "failed to decode");
+
+    let file = File::open(&decoded_path)
+        .expect("failed to open PNG file");
+    let reader = png::Decoder::new(BufReader::new(file)).read_info()
+        .expect("failed to decode PNG file");
+    let chunks = reader.info().uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str()));
+    let settings = PngSettings::from_text_chunks(chunks)
+        .expect("invalid settings in decoded image");
+    assert_eq!(settings.auto_cut, Some(false));
+    assert_eq!(settings.half_cut, Some(false));
+    assert_eq!(settings.no_chain, Some(true));
+    assert_eq!(settings.hi_res, Some(true));
+    assert_eq!(settings.cut_every, Some(2));
+    assert_eq!(settings.feed, Some(14));
+    assert_eq!(settings.media_width, Some(24));
+    // each page is followed by the line that marks its print command
+    assert_eq!(settings.pages, Some(vec![0..100, 101..201]));
+}
+
+#[test]
+fn two_colour_jobs_are_rendered_in_black_and_red() {
+    let mut job = vec![0x00; 200];
+    job.extend(b"\x1B@");
+    job.extend(b"\x1Bia\x01");
+    job.extend(b"\x1BiK\x01");
+    job.extend(b"M\x00");
+    for _ in 0..4 {
+        job.extend(b"w\x01\x02\xF0\x00");
+        job.extend(b"w\x02\x02\x0F\x01");
+    }
+    job.push(b'Z');
+    job.push(0x1A);
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let job_path = out_dir.path().join("two-colour.prn");
+    std::fs::write(&job_path, &job).unwrap();
+    let decoded_path = out_dir.path().join("decoded.png");
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .arg(&job_path)
+        .arg(&decoded_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(output.status.success(), "failed to decode");
+
+    let (width, _height, pixels) = read_indexed_png(&decoded_path);
+    assert_eq!(width, 16);
+    // red ink must not look like any other line, e.g. the one marking the print command
+    let file = File::open(&decoded_path)
+        .expect("failed to open PNG file");
+    let reader = png::Decoder::new(BufReader::new(file)).read_info()
+        .expect("failed to decode PNG file");
+    let palette: Vec<&[u8]> = reader.info().palette.as_ref().unwrap().chunks_exact(3).collect();
+    assert_eq!(palette[7], [0xDC, 0x14, 0x3C]);
+    assert_eq!(palette.iter().filter(|color| **color == palette[7]).count(), 1);
+    let expected: Vec<u8> = (0..16)
+        .map(|x| match x {
+            0..4 => 1,
+            4..8|15 => 7,
+            _ => 0,
+        })
+        .collect();
+    for y in 0..4 {
+        assert_eq!(pixels[y * 16..(y + 1) * 16], expected, "row {}", y);
+    }
+
+    // the P-touch models cannot print in red
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .args(["--lint", "pt-e550w"])
+        .arg(&job_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(!output.status.success(), "two-colour job passed linting for a P-touch model");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("4 raster lines are sent in two colours (w), which the model cannot print"));
+
+    // each two-colour line takes two transfers of 3 + 2 bytes; the blank line would take as many
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-decode"))
+        .args(["--stats", "--model", "ql-800"])
+        .arg(&job_path)
+        .output()
+        .expect("failed to run ptouch-decode");
+    assert!(output.status.success(), "failed to decode");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("raster data: 41 bytes as sent, 50 bytes uncompressed (82.0%)\n"));
+}
+
+#[test]
+fn ql_jobs_are_checked_against_their_own_dialect() {
+    const LIMITS: Limits = Limits {
+        max_width: 720,
+        max_rows_per_page: 1_000_000,
+        max_pages: 1000,
+        max_decompressed_bytes: 256 * 1024 * 1024,
+    };
+
+    // 200 lines across the whole 720-pin head of a QL model, sent using "g" with big-endian lengths
+    // onto a 62 mm continuous roll
+    let mut job = vec![0x00; 200];
+    job.extend(b"\x1B@");
+    job.extend(b"\x1Bia\x01");
+    job.extend(b"\x1BiK\x08");
+    job.extend(b"\x1Bid\x23\x00");
+    job.extend(b"M\x00");
+    job.extend(b"\x1Biz\x86\x0A\x3E\x00\xC8\x00\x00\x00\x00\x00");
+    for _ in 0..200 {
+        job.extend(b"g\x00\x5A");
+        job.extend([0x0F; 90]);
+    }
+    job.push(0x1A);
+
+    let mut reader = CommandReader::new(job.as_slice()).expect("invalid print job header");
+    let mut state = JobState::new(LIMITS);
+    let mut linter = Linter::new(Model::Ql800);
+    while let Some(command) = reader.next_command().expect("invalid command") {
+        state.apply(&command).expect("command rejected");
+        linter.observe(&command);
+    }
+    assert_eq!(linter.finish(), Vec::new());
+    // the report names the advanced mode bits the way QL models read them
+    let described = state.settings().describe();
+    assert!(described.contains(&("cut at end", "yes".to_owned())), "{:?}", described);
+    assert!(described.contains(&("two-colour printing", "no".to_owned())), "{:?}", described);
+    let likely: Vec<Model> = state.evidence().guess_models()
+        .iter()
+        .take_while(|guess| guess.contradicting.is_empty())
+        .map(|guess| guess.model)
+        .collect();
+    assert_eq!(likely, vec![Model::Ql800, Model::Ql810W, Model::Ql820Nwb]);
+
+    // P-touch raster lines on die-cut labels, which take no feed margin and a fixed number of lines
+    let mut info = [0x8E, 0x0B, 62, 29, 0, 0, 0, 0, 0, 0];
+    info[4..8].copy_from_slice(&100u32.to_le_bytes());
+    let mut commands = vec![
+        command::Command::Invalidate,
+        command::Command::Initialize,
+        command::Command::Feed(35),
+        command::Command::PrintInformation(info),
+    ];
+    commands.extend(std::iter::repeat_n(command::Command::Raster(vec![0x0F; 90]), 100));
+    commands.push(command::Command::PrintFeed);
+
+    let mut linter = Linter::new(Model::Ql810W);
+    for command in &commands {
+        linter.observe(command);
+    }
+    let mut problems = linter.finish();
+    problems.sort();
+    let mut expected = vec![
+        LintProblem::LabelLengthMismatch { page_index: 0, lines: 100, expected: 271 },
+        LintProblem::MarginMismatch { dots: 35, expected: 0 },
+        LintProblem::ForeignRasterCommand { command: 'G', expected: 'g', count: 100 },
+    ];
+    expected.sort();
+    assert_eq!(problems, expected);
+
+    // the same labels in a size the model does not know
+    commands[3] = command::Command::PrintInformation([0x8E, 0x0B, 62, 30, 100, 0, 0, 0, 0, 0]);
+    let mut linter = Linter::new(Model::Ql810W);
+    for command in &commands {
+        linter.observe(command);
+    }
+    assert!(linter.finish().contains(&LintProblem::UnsupportedLabels { width_mm: 62, length_mm: 30 }));
+}
+
+#[test]
+fn raster_lines_beyond_the_limits_are_rejected_before_decompression() {
+    const LIMITS: Limits = Limits {
+        max_width: 560,
+        max_rows_per_page: 1000,
+        max_pages: 10,
+        max_decompressed_bytes: 1024,
+    };
+
+    // a single line of 64 KiB that would decompress to 4 MiB
+    let mut job = vec![0x00; 200];
+    job.extend(b"\x1B@M\x02G\xFE\xFF");
+    for _ in 0..0x7FFF {
+        job.extend([0x81, 0xFF]);
+    }
+    job.push(0x1A);
+    for lenient in [false, true] {
+        let reader = if lenient {
+            CommandReader::new_lenient(job.as_slice())
+        } else {
+            CommandReader::new(job.as_slice())
+        };
+        let result: Result<Vec<_>, _> = reader.expect("invalid print job header")
+            .with_limits(LIMITS)
+            .collect();
+        assert_eq!(result, Err(DecodeError::LineTooWide { page_index: 0, width: 0x7FFF * 128 * 8, limit: 560 }));
+    }
+
+    // lines that fit on their own but not all together
+    let mut job = vec![0x00; 200];
+    job.extend(b"\x1B@M\x02");
+    for _ in 0..20 {
+        job.extend(b"G\x02\x00\xBD\xFF");
+    }
+    job.push(0x1A);
+    let result: Result<Vec<_>, _> = CommandReader::new(job.as_slice())
+        .expect("invalid print job header")
+        .with_limits(LIMITS)
+        .collect();
+    assert_eq!(result, Err(DecodeError::DataTooLarge { limit: 1024 }));
+}
@@ -0,0 +1,141 @@
+//! Compares print jobs using `ptouch-diff`.
+
+
+use std::fs::File;
+use std::path::Path;
+use std::process::{Command, Output};
+
+
+const ROWS: u32 = 20;
+const ROW_BYTES: usize = 16;
+
+
+/// Builds a single-page print job with a black bar across rows 5 to 9 and the given extra pixels.
+fn print_job(pack_bits: bool, advanced_mode: u8, extra_pixels: &[(usize, usize)]) -> Vec<u8> {
+    let mut job = vec![0x00; 200];
+    job.extend(b"\x1B@");
+    job.extend(b"\x1Bia\x01");
+    job.extend(b"\x1BiK");
+    job.push(advanced_mode);
+    job.extend(b"\x1Biz\x84\x00\x0C\x00");
+    job.extend(ROWS.to_le_bytes());
+    job.extend(b"\x00\x00");
+    job.extend(if pack_bits { b"M\x02" } else { b"M\x00" });
+    for row in 0..usize::try_from(ROWS).unwrap() {
+        let mut data = [0u8; ROW_BYTES];
+        if (5..10).contains(&row) {
+            data[2..4].fill(0xFF);
+        }
+        for (x, y) in extra_pixels {
+            if *y == row {
+                data[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+
+        if !pack_bits {
+            job.push(b'G');
+            job.extend(u16::try_from(ROW_BYTES).unwrap().to_le_bytes());
+            job.extend(data);
+        } else if data.iter().all(|b| *b == 0x00) {
+            job.push(b'Z');
+        } else {
+            // a single literal run
+            job.push(b'G');
+            job.extend(u16::try_from(ROW_BYTES + 1).unwrap().to_le_bytes());
+            job.push(u8::try_from(ROW_BYTES - 1).unwrap());
+            job.extend(data);
+        }
+    }
+    job.push(0x1A);
+    job
+}
+
+fn run_diff(dir: &Path, old: &[u8], new: &[u8], extra_args: &[&str]) -> Output {
+    let old_path = dir.join("old.prn");
+    let new_path = dir.join("new.prn");
+    std::fs::write(&old_path, old).unwrap();
+    std::fs::write(&new_path, new).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_ptouch-diff"))
+        .args(extra_args)
+        .arg(&old_path)
+        .arg(&new_path)
+        .output()
+        .expect("failed to run ptouch-diff")
+}
+
+
+#[test]
+fn compression_does_not_count_as_a_difference() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_diff(
+        dir.path(),
+        &print_job(false, 0x00, &[(100, 15)]),
+        &print_job(true, 0x00, &[(100, 15)]),
+        &[],
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "no differences\n");
+}
+
+#[test]
+fn changed_settings_and_pixels_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let diff_image_path = dir.path().join("diff.png");
+    let output = run_diff(
+        dir.path(),
+        &print_job(true, 0x00, &[(100, 15)]),
+        &print_job(false, 0x40, &[(64, 12), (65, 12), (70, 13)]),
+        &["--diff-image", diff_image_path.to_str().unwrap()],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        concat!(
+            "high resolution: no -> yes\n",
+            "page 1: rows 12 to 13, columns 64 to 70: 3 pixels differ\n",
+            "page 1: rows 15 to 15, columns 100 to 100: 1 pixels differ\n",
+        ),
+    );
+
+    // one line per raster line plus one for the end of the page
+    let dec = png::Decoder::new(std::io::BufReader::new(File::open(&diff_image_path).unwrap()));
+    let mut reader = dec.read_info().unwrap();
+    let mut buf = vec![0u8; reader.output_buffer_size().unwrap()];
+    let info = reader.next_frame(&mut buf).unwrap();
+    assert_eq!((info.width, info.height), (128, ROWS + 1));
+    assert_eq!(info.bit_depth, png::BitDepth::Eight);
+    let pixel = |x: usize, y: usize| buf[y * info.line_size + x];
+    assert_eq!(pixel(0, 0), 0, "blank in both");
+    assert_eq!(pixel(16, 5), 1, "printed in both");
+    assert_eq!(pixel(100, 15), 2, "printed only in old");
+    assert_eq!(pixel(64, 12), 3, "printed only in new");
+    assert_eq!(pixel(0, 20), 4, "end of page");
+}
+
+#[test]
+fn differing_page_and_job_counts_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let one = print_job(true, 0x00, &[]);
+    let mut two = one.clone();
+    two.extend(&one);
+    let output = run_diff(dir.path(), &one, &two, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "jobs: 1 -> 2\n");
+
+    let output = run_diff(dir.path(), b"not a print job", &one, &[]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn missing_files_are_reported_as_trouble() {
+    let dir = tempfile::tempdir().unwrap();
+    let new_path = dir.path().join("new.prn");
+    std::fs::write(&new_path, print_job(true, 0x00, &[])).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-diff"))
+        .arg(dir.path().join("missing.prn"))
+        .arg(&new_path)
+        .output()
+        .expect("failed to run ptouch-diff");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing.prn: failed to read print job file"));
+}
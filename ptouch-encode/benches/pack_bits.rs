@@ -0,0 +1,99 @@
+//! Compares the PackBits encoder with the greedy encoder it replaced and with sending raster lines
+//! uncompressed, both in output size and in speed.
+//!
+//! The output sizes are printed before the timings.
+
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use ptouch_encode::pack_bits::pack_bits;
+
+
+/// The greedy encoder that was used before, with its run-length handling fixed, for comparison.
+fn greedy_pack_bits(bytes: &[u8]) -> Vec<u8> {
+    fn take_repeated(slice: &[u8]) -> usize {
+        slice.iter().take_while(|b| **b == slice[0]).count()
+    }
+
+    fn take_verbatim(slice: &[u8]) -> usize {
+        1 + slice.windows(2).take_while(|w| w[0] != w[1]).count()
+    }
+
+    let mut ret = Vec::with_capacity(2*bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let repeated = take_repeated(&bytes[i..]);
+        let verbatim = take_verbatim(&bytes[i..]);
+        if repeated > verbatim {
+            let count = repeated.min(128);
+            ret.push((1 - i16::try_from(count).unwrap()) as u8);
+            ret.push(bytes[i]);
+            i += count;
+        } else {
+            let count = verbatim.min(128);
+            ret.push(u8::try_from(count - 1).unwrap());
+            ret.extend(&bytes[i..i+count]);
+            i += count;
+        }
+    }
+    ret
+}
+
+/// A small xorshift generator, so that the inputs are the same in every run.
+fn pseudo_random_bytes(len: usize, mut state: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Raster lines that are typical of labels, plus some that are hard to compress.
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    let mut text_row = vec![0x00; 4];
+    text_row.extend([0x3C, 0x3C, 0x00, 0x7E, 0xFF, 0xFF, 0x18, 0x18]);
+    text_row.extend(vec![0x00; 4]);
+
+    let alternating_short_runs: Vec<u8> = (0..70u8)
+        .map(|i| if (i / 2) % 2 == 0 { 0xF0 } else { 0x0F })
+        .collect();
+
+    let mut mostly_literal_with_pairs = pseudo_random_bytes(70, 1);
+    for i in (0..70).step_by(5) {
+        mostly_literal_with_pairs[i + 1] = mostly_literal_with_pairs[i];
+    }
+
+    vec![
+        ("text row, 16 bytes", text_row),
+        ("full black, 70 bytes", vec![0xFF; 70]),
+        ("alternating pairs, 70 bytes", alternating_short_runs),
+        ("random with pairs, 70 bytes", mostly_literal_with_pairs),
+        ("random, 70 bytes", pseudo_random_bytes(70, 2)),
+        ("wide mixed, 512 bytes", [vec![0x00; 200], pseudo_random_bytes(112, 3), vec![0xFF; 200]].concat()),
+    ]
+}
+
+fn bench_pack_bits(c: &mut Criterion) {
+    println!("{:<30} {:>6} {:>8} {:>8}", "input", "raw", "greedy", "optimal");
+    for (name, input) in inputs() {
+        println!(
+            "{:<30} {:>6} {:>8} {:>8}",
+            name, input.len(), greedy_pack_bits(&input).len(), pack_bits(&input).len(),
+        );
+    }
+
+    for (name, input) in inputs() {
+        let mut group = c.benchmark_group(name);
+        group.bench_function("raw", |b| b.iter(|| black_box(&input).to_vec()));
+        group.bench_function("greedy", |b| b.iter(|| greedy_pack_bits(black_box(&input))));
+        group.bench_function("optimal", |b| b.iter(|| pack_bits(black_box(&input))));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_pack_bits);
+criterion_main!(benches);
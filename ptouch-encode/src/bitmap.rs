@@ -0,0 +1,163 @@
+use std::ops::Range;
+
+use crate::bits::{BitIteratorExt, ByteIteratorExt};
+use crate::orientation::{Orientation, Rotation};
+
+
+/// A black-and-white image.
+///
+/// A set pixel (`true`) is a marker; an unset pixel (`false`) is blank medium. Each row is stored
+/// as packed bits, most significant bit first.
+///
+/// When a bitmap is turned into a page, each row becomes one raster line and each column one pin
+/// of the print head, just like the rows and columns of an input PNG file.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Bitmap {
+    width: usize,
+    height: usize,
+    stride: usize,
+    data: Vec<u8>,
+}
+impl Bitmap {
+    /// Creates a new, blank bitmap of the given dimensions.
+    pub fn new(width: usize, height: usize) -> Self {
+        let stride = width.div_ceil(8);
+        Self {
+            width,
+            height,
+            stride,
+            data: vec![0u8; stride * height],
+        }
+    }
+
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        assert!(x < self.width && y < self.height);
+        (self.data[y * self.stride + x / 8] & (1 << (7 - (x % 8)))) != 0
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: bool) {
+        assert!(x < self.width && y < self.height);
+        let mask = 1 << (7 - (x % 8));
+        let byte = &mut self.data[y * self.stride + x / 8];
+        if value {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+
+    /// Appends a row to the bottom of the bitmap.
+    ///
+    /// The row is taken from the given bits; missing bits are blank and excess bits are ignored.
+    pub fn push_row<I: Iterator<Item = bool>>(&mut self, bits: I) {
+        let mut row: Vec<u8> = bits
+            .take(self.width)
+            .bytes_msb_first()
+            .collect();
+        row.resize(self.stride, 0x00);
+        self.data.extend(row);
+        self.height += 1;
+    }
+
+    /// Returns whether the given row contains no set pixels.
+    pub fn is_row_blank(&self, y: usize) -> bool {
+        assert!(y < self.height);
+        self.data[y*self.stride..(y+1)*self.stride]
+            .iter()
+            .all(|b| *b == 0x00)
+    }
+
+    /// Returns the range of rows between the first and the last row containing set pixels.
+    ///
+    /// Returns an empty range if the bitmap is blank.
+    pub fn content_rows(&self) -> Range<usize> {
+        let Some(first) = (0..self.height).find(|y| !self.is_row_blank(*y)) else {
+            return 0..0
+        };
+        let last = (0..self.height).rev().find(|y| !self.is_row_blank(*y)).unwrap();
+        first..last+1
+    }
+
+    /// Returns a copy of the given range of rows, with the given numbers of blank rows added
+    /// before and after them.
+    pub fn with_rows(&self, rows: Range<usize>, blank_before: usize, blank_after: usize) -> Self {
+        assert!(rows.end <= self.height);
+        let mut data = vec![0u8; blank_before * self.stride];
+        data.extend_from_slice(&self.data[rows.start*self.stride..rows.end*self.stride]);
+        data.resize(data.len() + blank_after * self.stride, 0x00);
+        Self {
+            width: self.width,
+            height: blank_before + rows.len() + blank_after,
+            stride: self.stride,
+            data,
+        }
+    }
+
+    /// Returns the pixels of the given row, left to right.
+    pub fn row_bits(&self, y: usize) -> impl Iterator<Item = bool> + '_ {
+        assert!(y < self.height);
+        self.data[y*self.stride..(y+1)*self.stride]
+            .iter()
+            .copied()
+            .bits_msb_first()
+            .take(self.width)
+    }
+
+    /// Returns a transformed copy of this bitmap.
+    pub fn transformed(&self, orientation: &Orientation) -> Self {
+        let (new_width, new_height) = orientation.transformed_size(self.width, self.height);
+        let mut ret = Self::new(new_width, new_height);
+        for y in 0..new_height {
+            for x in 0..new_width {
+                let (source_x, source_y) = orientation.source_coordinates(x, y, self.width, self.height);
+                if self.get(source_x, source_y) {
+                    ret.set(x, y, true);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Returns a copy of this bitmap rotated by 90° counterclockwise.
+    ///
+    /// The top row of this bitmap becomes the leftmost column of the result.
+    pub fn rotated_ccw(&self) -> Self {
+        self.transformed(&Orientation::rotated(Rotation::Clockwise270))
+    }
+
+    /// Returns a copy of this bitmap rotated by 180°.
+    pub fn rotated_180(&self) -> Self {
+        self.transformed(&Orientation::rotated(Rotation::Clockwise180))
+    }
+
+    /// Returns a copy of this bitmap mirrored along its vertical axis (left becomes right).
+    pub fn flipped_horizontally(&self) -> Self {
+        self.transformed(&Orientation {
+            flip_horizontally: true,
+            ..Orientation::default()
+        })
+    }
+
+    /// Copies the set pixels of another bitmap into this one, placing the top left corner of the
+    /// other bitmap at the given coordinates.
+    ///
+    /// Pixels falling outside of this bitmap are dropped.
+    pub fn draw(&mut self, other: &Bitmap, left: usize, top: usize) {
+        for y in 0..other.height {
+            if top + y >= self.height {
+                break;
+            }
+            for x in 0..other.width {
+                if left + x >= self.width {
+                    break;
+                }
+                if other.get(x, y) {
+                    self.set(left + x, top + y, true);
+                }
+            }
+        }
+    }
+}
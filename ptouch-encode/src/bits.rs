@@ -0,0 +1,66 @@
+pub trait ByteIteratorExt where Self : Iterator<Item = u8> + Sized {
+    fn bits_msb_first(self) -> BytesToBitsMsbFirst<Self>;
+}
+impl<I: Iterator<Item = u8>> ByteIteratorExt for I {
+    fn bits_msb_first(self) -> BytesToBitsMsbFirst<Self> {
+        BytesToBitsMsbFirst {
+            inner: self,
+            current_byte: 0,
+            current_bit: 0,
+        }
+    }
+}
+
+pub struct BytesToBitsMsbFirst<I: Iterator<Item = u8>> {
+    inner: I,
+    current_byte: u8,
+    current_bit: u8,
+}
+impl<I: Iterator<Item = u8>> Iterator for BytesToBitsMsbFirst<I> {
+    type Item = bool;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current_bit == 0 {
+            // pull the next byte (or fall out)
+            self.current_byte = self.inner.next()?;
+        }
+
+        // extract the next bit
+        let ret = (self.current_byte & (1 << (7 - self.current_bit))) != 0;
+        self.current_bit = (self.current_bit + 1) % 8;
+        Some(ret)
+    }
+}
+
+pub trait BitIteratorExt where Self : Iterator<Item = bool> + Sized {
+    fn bytes_msb_first(self) -> BitsToBytesMsbFirst<Self>;
+}
+impl<I: Iterator<Item = bool>> BitIteratorExt for I {
+    fn bytes_msb_first(self) -> BitsToBytesMsbFirst<Self> {
+        BitsToBytesMsbFirst {
+            inner: self,
+        }
+    }
+}
+
+pub struct BitsToBytesMsbFirst<I: Iterator<Item = bool>> {
+    inner: I,
+}
+impl<I: Iterator<Item = bool>> Iterator for BitsToBytesMsbFirst<I> {
+    type Item = u8;
+    fn next(&mut self) -> Option<Self::Item> {
+        let first_bit = self.inner.next()?;
+
+        let mut current_byte = 0;
+        if first_bit {
+            current_byte |= 1 << 7;
+        }
+
+        for i in (0..7).rev() {
+            let Some(next_bit) = self.inner.next() else { break };
+            if next_bit {
+                current_byte |= 1 << i;
+            }
+        }
+        Some(current_byte)
+    }
+}
@@ -0,0 +1,86 @@
+use clap::ValueEnum;
+
+use crate::bitmap::Bitmap;
+
+
+/// A layout for labeling a cable.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, ValueEnum)]
+pub enum CableLayout {
+    /// The middle of the label is wrapped around the cable and the two ends are stuck together,
+    /// forming a flag which carries the text on both sides.
+    Flag,
+
+    /// The label is wrapped once around the cable, with the text repeated so that it can be read
+    /// from all sides.
+    Wrap,
+}
+
+
+/// How the text on the second half of a flag label is oriented relative to the first half.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, ValueEnum)]
+pub enum FlagBack {
+    /// Rotated by 180°.
+    #[default] Rotated,
+
+    /// Mirrored along the length of the tape.
+    Mirrored,
+
+    /// Oriented the same way as on the first half.
+    Same,
+}
+
+
+/// Lays out a flag label.
+///
+/// The label consists of the front half of the flag, the part wrapped around the cable
+/// (`wrap_length` pixels long) and the back half of the flag. Each half is `half_length` pixels
+/// long and carries the text centered within it.
+///
+/// The text and the result are in reading orientation, i.e. their width runs along the tape.
+pub fn lay_out_flag(text: &Bitmap, wrap_length: usize, half_length: usize, back: FlagBack) -> Bitmap {
+    if text.width() > half_length {
+        panic!(
+            "text ({} pixels long) does not fit onto one half of the flag ({} pixels long)",
+            text.width(), half_length,
+        );
+    }
+
+    let back_text = match back {
+        FlagBack::Rotated => text.rotated_180(),
+        FlagBack::Mirrored => text.flipped_horizontally(),
+        FlagBack::Same => text.clone(),
+    };
+
+    let text_offset = (half_length - text.width()) / 2;
+    let mut label = Bitmap::new(2*half_length + wrap_length, text.height());
+    label.draw(text, text_offset, 0);
+    label.draw(&back_text, half_length + wrap_length + text_offset, 0);
+    label
+}
+
+
+/// Lays out a wrap-around label that is `length` pixels long.
+///
+/// The text is repeated as often as it fits, with at least `gap` pixels between two copies, and
+/// the copies are spread evenly along the label.
+///
+/// The text and the result are in reading orientation, i.e. their width runs along the tape.
+pub fn lay_out_wrap(text: &Bitmap, length: usize, gap: usize) -> Bitmap {
+    if text.width() > length {
+        panic!(
+            "text ({} pixels long) is longer than the circumference of the cable ({} pixels)",
+            text.width(), length,
+        );
+    }
+
+    let copy_count = ((length + gap) / (text.width() + gap)).max(1);
+    let slot_length = length / copy_count;
+
+    let mut label = Bitmap::new(length, text.height());
+    for copy_index in 0..copy_count {
+        let slot_start = copy_index * slot_length;
+        let text_offset = (slot_length - text.width()) / 2;
+        label.draw(text, slot_start + text_offset, 0);
+    }
+    label
+}
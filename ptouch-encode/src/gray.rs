@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::bitmap::Bitmap;
+use crate::orientation::Orientation;
+
+
+/// A grayscale image, stored as the amount of ink covering each pixel.
+///
+/// A coverage of 0.0 is blank medium and 1.0 is a full marker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrayImage {
+    width: usize,
+    height: usize,
+    coverage: Vec<f32>,
+}
+impl GrayImage {
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
+
+    fn get(&self, x: usize, y: usize) -> f32 {
+        self.coverage[y * self.width + x]
+    }
+
+    /// Loads a PNG file of any color type and bit depth.
+    ///
+    /// Colors are converted to their luminance and transparent pixels are composited onto white.
+    pub fn load_png(png_path: &Path) -> Self {
+        let f = File::open(png_path)
+            .expect("failed to open PNG file");
+        let f_buf = BufReader::new(f);
+        let mut dec = png::Decoder::new(f_buf);
+        dec.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = dec.read_info()
+            .expect("failed to decode PNG file");
+        let buf_size = reader.output_buffer_size()
+            .expect("PNG file is too large");
+        let mut buf = vec![0u8; buf_size];
+        let frame_info = reader.next_frame(&mut buf)
+            .expect("failed to read PNG image data");
+        if frame_info.bit_depth != png::BitDepth::Eight {
+            panic!("PNG image data has unexpected bit depth {:?}", frame_info.bit_depth);
+        }
+
+        let width: usize = frame_info.width.try_into().unwrap();
+        let height: usize = frame_info.height.try_into().unwrap();
+        let channels = frame_info.color_type.samples();
+        let mut coverage = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let line = &buf[y*frame_info.line_size..y*frame_info.line_size + width*channels];
+            for pixel in line.chunks_exact(channels) {
+                let (luminance, alpha) = match frame_info.color_type {
+                    png::ColorType::Grayscale => (f32::from(pixel[0]), 255.0),
+                    png::ColorType::GrayscaleAlpha => (f32::from(pixel[0]), f32::from(pixel[1])),
+                    png::ColorType::Rgb|png::ColorType::Rgba => {
+                        // Rec. 709 luma
+                        let luminance =
+                            0.2126 * f32::from(pixel[0])
+                            + 0.7152 * f32::from(pixel[1])
+                            + 0.0722 * f32::from(pixel[2]);
+                        let alpha = if channels == 4 { f32::from(pixel[3]) } else { 255.0 };
+                        (luminance, alpha)
+                    },
+                    png::ColorType::Indexed => unreachable!("indexed PNG has not been expanded"),
+                };
+                coverage.push((1.0 - luminance / 255.0) * (alpha / 255.0));
+            }
+        }
+
+        Self {
+            width,
+            height,
+            coverage,
+        }
+    }
+
+    /// Returns a transformed copy of this image.
+    pub fn transformed(&self, orientation: &Orientation) -> Self {
+        let (new_width, new_height) = orientation.transformed_size(self.width, self.height);
+        let mut coverage = Vec::with_capacity(new_width * new_height);
+        for y in 0..new_height {
+            for x in 0..new_width {
+                let (source_x, source_y) = orientation.source_coordinates(x, y, self.width, self.height);
+                coverage.push(self.get(source_x, source_y));
+            }
+        }
+        Self {
+            width: new_width,
+            height: new_height,
+            coverage,
+        }
+    }
+
+    /// Resamples the image to the given dimensions.
+    ///
+    /// Each target pixel receives the average coverage of the area of the source image that it
+    /// covers, which avoids the aliasing of nearest-neighbor sampling when scaling down.
+    pub fn resampled(&self, new_width: usize, new_height: usize) -> Self {
+        assert!(new_width > 0 && new_height > 0);
+
+        // the filter is separable; do the horizontal pass first, then the vertical one
+        let mut horizontal = Vec::with_capacity(new_width * self.height);
+        for y in 0..self.height {
+            area_average(self.width, new_width, |x| self.get(x, y), &mut horizontal);
+        }
+        let mut transposed = Vec::with_capacity(new_width * new_height);
+        for x in 0..new_width {
+            area_average(self.height, new_height, |y| horizontal[y * new_width + x], &mut transposed);
+        }
+
+        let mut coverage = vec![0.0; new_width * new_height];
+        for x in 0..new_width {
+            for y in 0..new_height {
+                coverage[y * new_width + x] = transposed[x * new_height + y];
+            }
+        }
+        Self {
+            width: new_width,
+            height: new_height,
+            coverage,
+        }
+    }
+
+    /// Converts the image into a bitmap using Floyd-Steinberg error diffusion.
+    pub fn dithered(&self) -> Bitmap {
+        let mut coverage = self.coverage.clone();
+        let mut bitmap = Bitmap::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let old_value = coverage[y * self.width + x];
+                let marked = old_value >= 0.5;
+                if marked {
+                    bitmap.set(x, y, true);
+                }
+                let error = old_value - if marked { 1.0 } else { 0.0 };
+
+                let mut spread = |dx: isize, dy: usize, weight: f32| {
+                    let Some(nx) = x.checked_add_signed(dx) else { return };
+                    let ny = y + dy;
+                    if nx < self.width && ny < self.height {
+                        coverage[ny * self.width + nx] += error * weight;
+                    }
+                };
+                spread(1, 0, 7.0/16.0);
+                spread(-1, 1, 3.0/16.0);
+                spread(0, 1, 5.0/16.0);
+                spread(1, 1, 1.0/16.0);
+            }
+        }
+        bitmap
+    }
+}
+
+
+/// Resamples a line of `old_len` values to `new_len` values by area averaging and appends the
+/// result to `output`.
+fn area_average<F: Fn(usize) -> f32>(old_len: usize, new_len: usize, value: F, output: &mut Vec<f32>) {
+    // each output sample covers `ratio` input samples
+    let ratio = old_len as f64 / new_len as f64;
+    for i in 0..new_len {
+        let start = i as f64 * ratio;
+        let end = start + ratio;
+
+        let mut sum = 0.0;
+        let mut pos = start;
+        while pos < end - 1e-9 {
+            let index = (pos.floor() as usize).min(old_len - 1);
+            let next_pos = ((index + 1) as f64).min(end);
+            sum += f64::from(value(index)) * (next_pos - pos);
+            pos = next_pos;
+        }
+        output.push((sum / ratio) as f32);
+    }
+}
@@ -0,0 +1,10 @@
+pub mod bitmap;
+pub mod bits;
+pub mod cable;
+pub mod font;
+pub mod gray;
+pub mod orientation;
+pub mod pack_bits;
+pub mod png_rows;
+pub mod serial;
+pub mod spill;
@@ -1,16 +1,41 @@
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
-use std::num::ParseIntError;
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Seek, Write};
+use std::num::{NonZeroU64, ParseIntError};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::str::FromStr;
 
-use clap::Parser;
-use png;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use ptouch_common::media::{LabelGeometry, MediaType, TubeGeometry};
+use ptouch_common::model::{CommandSet, Model, ModelProfile};
+use ptouch_decode::bundle::JobBundle;
+use ptouch_decode::command::{CommandReader, CompressionMode, RasterColor};
+use ptouch_decode::diff::PageEnd;
+use ptouch_decode::job::{JobState, Limits};
+use ptouch_decode::png_settings::PngSettings;
+use ptouch_decode::render::{RenderOptions, Renderer};
+
+use ptouch_encode::{cable, font};
+use ptouch_encode::bitmap::Bitmap;
+use ptouch_encode::bits::BitIteratorExt;
+use ptouch_encode::cable::{CableLayout, FlagBack};
+use ptouch_encode::gray::GrayImage;
+use ptouch_encode::orientation::{Orientation, Rotation, RotationOption};
+use ptouch_encode::pack_bits::pack_bits;
+use ptouch_encode::png_rows::{self, PngRowReader};
+use ptouch_encode::serial::{Checksum, SerialFormat, SerialRange};
+use ptouch_encode::spill::RowSpill;
 
 
 const ESC: u8 = 0x1B;
 
+/// The largest image, in pixels, that is loaded into memory in order to scale it or rotate it by
+/// 90°. Other images are streamed.
+const MAX_LOADED_PIXELS: usize = 256 * 1024 * 1024;
+
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 enum CutEvery {
@@ -20,7 +45,7 @@ enum CutEvery {
 impl FromStr for CutEvery {
     type Err = ParseIntError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() == 0 {
+        if s.is_empty() {
             Ok(Self::Unsupported)
         } else {
             let every = s.parse()?;
@@ -30,6 +55,269 @@ impl FromStr for CutEvery {
 }
 
 
+/// The size of die-cut labels, given as `WIDTHxLENGTH` in millimeters (e.g. `62x29`).
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+struct LabelSize {
+    width_mm: u8,
+    length_mm: u8,
+}
+impl FromStr for LabelSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, length) = s.split_once(['x', 'X'])
+            .ok_or_else(|| format!("label size {:?} is not given as WIDTHxLENGTH", s))?;
+        let width_mm = width.parse().map_err(|e| format!("invalid label width {:?}: {}", width, e))?;
+        let length_mm = length.parse().map_err(|e| format!("invalid label length {:?}: {}", length, e))?;
+        Ok(Self { width_mm, length_mm })
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, ValueEnum)]
+enum FitMode {
+    /// Keep the native size of the image; it must be a 1-bit PNG.
+    #[default] None,
+
+    /// Scale the image down if it is wider than the printable area.
+    Shrink,
+
+    /// Scale the image up or down to fill the printable area.
+    Fill,
+}
+
+
+/// Where the content of a page is placed along a label of fixed length.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, ValueEnum)]
+enum Alignment {
+    /// At the end of the label that is printed first.
+    Start,
+
+    /// In the middle of the label.
+    #[default]
+    #[value(alias = "centre")]
+    Center,
+
+    /// At the end of the label that is printed last.
+    End,
+}
+
+
+/// The source of the raster lines of a page.
+enum Page {
+    /// A page that has been assembled in memory.
+    Bitmap(Bitmap),
+
+    /// A page for two-colour printing that has been assembled in memory; both bitmaps have the
+    /// same dimensions.
+    TwoColor { black: Bitmap, red: Bitmap },
+
+    /// A page taken from a PNG image, which is read row by row while the print job is being
+    /// written, without ever holding the whole image in memory.
+    StreamedPng(StreamedPng),
+}
+impl Page {
+    pub fn width(&self) -> usize {
+        match self {
+            Self::Bitmap(bitmap) => bitmap.width(),
+            Self::TwoColor { black, .. } => black.width(),
+            Self::StreamedPng(png) => png.width,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self {
+            Self::Bitmap(bitmap) => bitmap.height(),
+            Self::TwoColor { black, .. } => black.height(),
+            Self::StreamedPng(png) => png.height(),
+        }
+    }
+
+    /// Returns the range of rows between the first and the last row containing markers of either
+    /// colour.
+    ///
+    /// Returns an empty range if the page is blank.
+    pub fn content_rows(&self) -> Range<usize> {
+        match self {
+            Self::Bitmap(bitmap) => bitmap.content_rows(),
+            Self::TwoColor { black, red } => match (black.content_rows(), red.content_rows()) {
+                (black_rows, red_rows) if red_rows.is_empty() => black_rows,
+                (black_rows, red_rows) if black_rows.is_empty() => red_rows,
+                (black_rows, red_rows) => black_rows.start.min(red_rows.start)..black_rows.end.max(red_rows.end),
+            },
+            Self::StreamedPng(png) => png.content_rows(),
+        }
+    }
+
+    /// Keeps only the given rows of the page and adds blank rows before and after them.
+    pub fn set_rows(&mut self, rows: Range<usize>, blank_before: usize, blank_after: usize) {
+        match self {
+            Self::Bitmap(bitmap) => {
+                *bitmap = bitmap.with_rows(rows, blank_before, blank_after);
+            },
+            Self::TwoColor { black, red } => {
+                *black = black.with_rows(rows.clone(), blank_before, blank_after);
+                *red = red.with_rows(rows, blank_before, blank_after);
+            },
+            Self::StreamedPng(png) => png.set_rows(rows, blank_before, blank_after),
+        }
+    }
+}
+
+
+/// A page taken from a PNG image without holding the image in memory.
+///
+/// Mirroring the image, cropping it and padding it with blank rows only ever needs one row at a
+/// time, so they are applied while the image is being read.
+struct StreamedPng {
+    path: PathBuf,
+    width: usize,
+
+    /// The rows of the PNG file that make up the image, e.g. one page of a rendering.
+    image_rows: Range<usize>,
+
+    /// Whether the image is read as black and red.
+    two_color: bool,
+
+    flip_horizontally: bool,
+    flip_vertically: bool,
+
+    /// The number of blank rows before `rows`.
+    blank_before: usize,
+
+    /// The rows of the mirrored image that are part of the page.
+    rows: Range<usize>,
+
+    /// The number of blank rows after `rows`.
+    blank_after: usize,
+}
+impl StreamedPng {
+    /// Takes the given rows of a PNG file as a page in the given orientation, which must not
+    /// contain a rotation by 90°.
+    pub fn new(path: PathBuf, width: usize, image_rows: Range<usize>, two_color: bool, orientation: &Orientation) -> Self {
+        // a rotation by 180° mirrors the image in both directions
+        let half_turn = match orientation.rotation {
+            Rotation::None => false,
+            Rotation::Clockwise180 => true,
+            Rotation::Clockwise90|Rotation::Clockwise270 => panic!("streamed images cannot be rotated by 90°"),
+        };
+        let height = image_rows.len();
+        Self {
+            path,
+            width,
+            image_rows,
+            two_color,
+            flip_horizontally: orientation.flip_horizontally != half_turn,
+            flip_vertically: orientation.flip_vertically != half_turn,
+            blank_before: 0,
+            rows: 0..height,
+            blank_after: 0,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        self.blank_before + self.rows.len() + self.blank_after
+    }
+
+    /// Keeps only the given rows of the page and adds blank rows before and after them.
+    pub fn set_rows(&mut self, rows: Range<usize>, blank_before: usize, blank_after: usize) {
+        assert!(rows.end <= self.height());
+
+        // the new rows consist of some of the blank rows before the image, some of its rows and
+        // some of the blank rows after it
+        let image_start = self.blank_before;
+        let image_end = self.blank_before + self.rows.len();
+        let kept_start = rows.start.clamp(image_start, image_end);
+        let kept_end = rows.end.clamp(image_start, image_end);
+        let kept_blank_before = rows.end.min(image_start).saturating_sub(rows.start);
+        let kept_blank_after = rows.end.saturating_sub(rows.start.max(image_end));
+
+        let first_row = self.rows.start - image_start;
+        self.rows = (first_row + kept_start)..(first_row + kept_end);
+        self.blank_before = blank_before + kept_blank_before;
+        self.blank_after = kept_blank_after + blank_after;
+    }
+
+    /// Reads the image and calls `row` with the black and, on two-colour pages, the red pixels of
+    /// each row within `rows`.
+    ///
+    /// The rows are passed in the order in which they are stored in the PNG file, which is from
+    /// the last to the first if the image is mirrored vertically.
+    pub fn read_rows<F: FnMut(Vec<bool>, Option<Vec<bool>>)>(&self, mut row: F) {
+        let mut reader = PngRowReader::open_with_colors(&self.path, self.two_color);
+        let file_rows = if self.flip_vertically {
+            (self.image_rows.end - self.rows.end)..(self.image_rows.end - self.rows.start)
+        } else {
+            (self.image_rows.start + self.rows.start)..(self.image_rows.start + self.rows.end)
+        };
+        for y in 0..file_rows.end {
+            let (mut black, mut red) = if self.two_color {
+                let pixels = reader.read_two_color_row()
+                    .expect("PNG file has fewer rows than announced");
+                if y < file_rows.start {
+                    continue;
+                }
+                let (black, red): (Vec<bool>, Vec<bool>) = pixels.unzip();
+                (black, Some(red))
+            } else {
+                let pixels = reader.read_row()
+                    .expect("PNG file has fewer rows than announced");
+                if y < file_rows.start {
+                    continue;
+                }
+                (pixels.collect(), None)
+            };
+            if self.flip_horizontally {
+                black.reverse();
+                if let Some(red) = &mut red {
+                    red.reverse();
+                }
+            }
+            row(black, red);
+        }
+    }
+
+    /// Returns the range of rows between the first and the last row containing markers of either
+    /// colour, reading the image to find out.
+    pub fn content_rows(&self) -> Range<usize> {
+        let mut index = 0;
+        let mut content: Option<Range<usize>> = None;
+        self.read_rows(|black, red| {
+            let blank = !black.contains(&true) && !red.is_some_and(|red| red.contains(&true));
+            if !blank {
+                let start = content.as_ref().map_or(index, |content| content.start);
+                content = Some(start..index+1);
+            }
+            index += 1;
+        });
+        let Some(content) = content else {
+            return 0..0
+        };
+
+        // from the order of the file to the order of the page
+        let content = if self.flip_vertically {
+            (self.rows.len() - content.end)..(self.rows.len() - content.start)
+        } else {
+            content
+        };
+        (self.blank_before + content.start)..(self.blank_before + content.end)
+    }
+}
+
+
+/// How raster lines are compressed.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, ValueEnum)]
+enum Compression {
+    /// Compress raster lines using PackBits.
+    #[default] PackBits,
+
+    /// Send raster lines uncompressed.
+    None,
+
+    /// Use PackBits if it makes the print job smaller; otherwise, send raster lines uncompressed.
+    Auto,
+}
+
+
 #[derive(Parser)]
 struct Opts {
     #[arg(short = 'c', long)]
@@ -56,18 +344,131 @@ struct Opts {
     #[arg(short = 'B', long)]
     pub dont_clear_print_buffer: bool,
 
+    #[arg(
+        long,
+        visible_alias = "two-colour",
+        conflicts_with = "draft",
+        help = concat!(
+            "Print in black and red onto two-colour media (e.g. on the QL-800). PNG images may then also",
+            " contain full red (#FF0000) pixels, which are printed in red. Each raster line is sent as one",
+            " black and one red transfer.",
+        ),
+    )]
+    pub two_color: bool,
+
     #[arg(short = 'e', long, default_value = "0")]
     pub cut_every: CutEvery,
 
-    #[arg(short = 'f', long, default_value = "0")]
-    pub feed: u16,
+    #[arg(
+        short = 'f',
+        long,
+        help = concat!(
+            "The feed margin before and after each page, in dots. Defaults to the margin that QL models",
+            " expect on the selected media, and to 0 on other models.",
+        ),
+    )]
+    pub feed: Option<u16>,
 
-    #[arg(short = 'w', long)]
-    pub width_mm: u8,
+    #[arg(
+        short = 'w',
+        long,
+        help = concat!(
+            "The width of the tape in millimeters. Required unless --tube, --die-cut or --bundle is given",
+            " or the PNG files have been rendered by ptouch-decode, which stores the media width in them.",
+        ),
+    )]
+    pub width_mm: Option<u8>,
 
     #[arg(short = 'x', long, default_value = "0")]
     pub extend_to_width_px: u16,
 
+    #[arg(
+        short = 'F',
+        long,
+        value_enum,
+        default_value_t,
+        help = concat!(
+            "How to fit PNG images to the printable area of the tape.",
+            " Scaled images may have any color type; they are resampled and dithered to black and white.",
+            " Unlike other images, which are read one row at a time, they are loaded into memory, which",
+            " limits them to 256 megapixels.",
+        ),
+    )]
+    pub fit: FitMode,
+
+    #[arg(
+        short = 'r',
+        long,
+        default_value = "0",
+        help = concat!(
+            "Rotate PNG images clockwise by 0, 90, 180 or 270 degrees before encoding them.",
+            " With \"auto\", images that are wider than they are long are rotated such that",
+            " their longer side runs along the tape. Images rotated by 90 or 270 degrees are loaded into",
+            " memory, which limits them to 256 megapixels.",
+        ),
+    )]
+    pub rotate: RotationOption,
+
+    #[arg(long, help = "Mirror PNG images horizontally (before rotating them).")]
+    pub flip_h: bool,
+
+    #[arg(long, help = "Mirror PNG images vertically (before rotating them).")]
+    pub flip_v: bool,
+
+    #[arg(
+        long,
+        help = concat!(
+            "Send the rows of each page from top to bottom.",
+            " By default, the bottom row is sent first.",
+        ),
+    )]
+    pub no_reverse: bool,
+
+    #[arg(
+        short = 'T',
+        long,
+        help = concat!(
+            "Remove blank rows from the beginning and end of each page, add the margin given by",
+            " --margin-mm, and pad the page to the shortest label length the printer supports.",
+        ),
+    )]
+    pub trim: bool,
+
+    #[arg(
+        long,
+        default_value = "0",
+        requires = "trim",
+        help = "The blank margin to leave at the beginning and end of each trimmed page, in millimeters.",
+    )]
+    pub margin_mm: f64,
+
+    #[arg(
+        short = 'L',
+        long,
+        help = concat!(
+            "Make each page exactly this long, in millimeters, by adding blank rows or cutting",
+            " rows off. Applied after --trim.",
+        ),
+    )]
+    pub length_mm: Option<f64>,
+
+    #[arg(
+        short = 'A',
+        long,
+        value_enum,
+        default_value_t,
+        help = "Where to place the content of each page when its length is set with --length-mm.",
+    )]
+    pub align: Alignment,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to compress raster lines. Some models or interfaces may require uncompressed lines.",
+    )]
+    pub compression: Compression,
+
     #[arg(
         short = '2',
         long,
@@ -78,271 +479,1156 @@ struct Opts {
     )]
     pub last_page_2: bool,
 
-    #[arg(required = true)]
-    pub png_paths: Vec<PathBuf>,
+    #[arg(
+        short = 'M',
+        long,
+        default_value = "PT-E550W",
+        help = "The printer model, which determines the physical dimensions of generated labels.",
+    )]
+    pub model: Model,
 
-    pub pt_path: PathBuf,
-}
+    #[arg(
+        short = 't',
+        long,
+        conflicts_with_all = ["width_mm", "half_cut"],
+        help = concat!(
+            "Print onto heat-shrink tube of the given diameter in millimeters (e.g. 8.8).",
+            " Sets the media type, media width and special tape flag, and places the image",
+            " within the printable area of the tube. Shorter pages are padded to the minimum",
+            " length of the tube.",
+        ),
+    )]
+    pub tube: Option<String>,
 
+    #[arg(
+        long,
+        value_name = "WIDTHxLENGTH",
+        conflicts_with_all = ["width_mm", "tube", "length_mm"],
+        help = concat!(
+            "Print onto die-cut labels of the given size in millimeters (e.g. 62x29) on a QL model.",
+            " Each page is padded to the length of a label and placed within its printable area.",
+        ),
+    )]
+    pub die_cut: Option<LabelSize>,
 
-fn pack_bits(bytes: &[u8]) -> Vec<u8> {
-    fn take_repeated(slice: &[u8]) -> &[u8] {
-        let mut i = 0;
-        let b = match slice.get(i) {
-            Some(bb) => bb,
-            None => return &[],
-        };
-        i += 1;
+    #[arg(
+        long,
+        help = concat!(
+            "Append one page for each serial number in the given range.",
+            " The range is given as START..END (excluding END) or START..=END (including END).",
+        ),
+    )]
+    pub serial: Option<SerialRange>,
 
-        while let Some(b2) = slice.get(i) {
-            if b2 == b {
-                i += 1;
-            } else {
-                break;
+    #[arg(long, default_value = "1", help = "The difference between consecutive serial numbers.")]
+    pub serial_step: NonZeroU64,
+
+    #[arg(long, default_value = "", help = "Text to print before each serial number.")]
+    pub serial_prefix: String,
+
+    #[arg(long, default_value = "", help = "Text to print after each serial number.")]
+    pub serial_suffix: String,
+
+    #[arg(long, default_value = "0", help = "Pad serial numbers with leading zeroes to at least this many digits.")]
+    pub serial_digits: usize,
+
+    #[arg(long, value_enum, default_value_t, help = "Check digit to append to each serial number.")]
+    pub serial_checksum: Checksum,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = concat!(
+            "Height of the area (in pixels across the tape) into which generated text is fitted.",
+            " If 0, the value of --extend-to-width-px is used.",
+        ),
+    )]
+    pub text_height_px: u16,
+
+    #[arg(
+        long,
+        value_enum,
+        requires_all = ["cable_diameter_mm", "cable_text"],
+        help = "Append a page containing a cable label with the given layout.",
+    )]
+    pub cable_layout: Option<CableLayout>,
+
+    #[arg(long, help = "The diameter of the cable to label, in millimeters.")]
+    pub cable_diameter_mm: Option<f64>,
+
+    #[arg(long, help = "The text of the cable label.")]
+    pub cable_text: Option<String>,
+
+    #[arg(
+        long,
+        help = concat!(
+            "The length of each half of a cable flag, in millimeters.",
+            " By default, the halves are just long enough for the text and a margin.",
+        ),
+    )]
+    pub flag_length_mm: Option<f64>,
+
+    #[arg(long, value_enum, default_value_t, help = "How the text on the back of a cable flag is oriented.")]
+    pub flag_back: FlagBack,
+
+    #[arg(
+        long,
+        value_name = "PNG",
+        help = concat!(
+            "Instead of writing a print job file, render the print job into this PNG file the way",
+            " ptouch-decode does, with feed margins, cut marks and the physical resolution of the model.",
+        ),
+    )]
+    pub preview: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = concat!(
+            "Rebuild the print job from a bundle written by ptouch-decode --bundle instead of encoding",
+            " PNG files. All settings are taken from the bundle; only the print job file is given as PATH.",
+        ),
+    )]
+    pub bundle: Option<PathBuf>,
+
+    #[arg(
+        required_unless_present = "preview",
+        value_name = "PATH",
+        help = concat!(
+            "The PNG files to encode, one per page, followed by the path of the print job file to write",
+            " (unless --preview is given).",
+        ),
+    )]
+    pub paths: Vec<PathBuf>,
+}
+impl Opts {
+    /// Takes the settings stored in a PNG file rendered by `ptouch-decode` for the options that
+    /// have not been given on the command line.
+    pub fn apply_png_settings(&mut self, settings: &PngSettings, matches: &ArgMatches) {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if !given("auto_cut") && let Some(auto_cut) = settings.auto_cut {
+            self.auto_cut = auto_cut;
+        }
+        if !given("mirror_print") && let Some(mirror_print) = settings.mirror_print {
+            self.mirror_print = mirror_print;
+        }
+        if !given("draft") && let Some(draft) = settings.draft {
+            self.draft = draft;
+        }
+        if !given("no_chain") && let Some(no_chain) = settings.no_chain {
+            self.no_chain = no_chain;
+        }
+        if !given("hi_res") && let Some(hi_res) = settings.hi_res {
+            self.hi_res = hi_res;
+        }
+        if !given("dont_clear_print_buffer") && let Some(dont_clear_print_buffer) = settings.dont_clear_print_buffer {
+            self.dont_clear_print_buffer = dont_clear_print_buffer;
+        }
+        if !given("cut_every") && let Some(cut_every) = settings.cut_every {
+            self.cut_every = CutEvery::Every(cut_every);
+        }
+        if !given("feed") && let Some(feed) = settings.feed {
+            self.feed = Some(feed);
+        }
+        // die-cut labels are given by their width and length
+        if self.tube.is_none() && !given("width_mm") && !given("die_cut")
+            && let (Some(width_mm), Some(length_mm)) = (settings.media_width, settings.label_length)
+        {
+            self.die_cut = Some(LabelSize { width_mm, length_mm });
+        }
+        // tube does not go with half cuts or a tape width, and always uses the special tape flag
+        if self.tube.is_none() && self.die_cut.is_none() {
+            if !given("special_tape") && let Some(special_tape) = settings.special_tape {
+                self.special_tape = special_tape;
             }
+            if !given("half_cut") && let Some(half_cut) = settings.half_cut {
+                self.half_cut = half_cut;
+            }
+            if !given("width_mm") && let Some(media_width) = settings.media_width {
+                self.width_mm = Some(media_width);
+            }
+        }
+        // renderings list the raster lines in the order in which they are sent
+        if !given("no_reverse") && settings.pages.is_some() {
+            self.no_reverse = true;
         }
+    }
 
-        &slice[..i]
+    pub fn png_paths(&self) -> &[PathBuf] {
+        if self.bundle.is_some() {
+            &[]
+        } else if self.preview.is_some() {
+            &self.paths
+        } else {
+            &self.paths[..self.paths.len()-1]
+        }
     }
 
-    fn take_verbatim(slice: &[u8]) -> &[u8] {
-        let mut i = 0;
-        let mut prev_b = match slice.get(i) {
-            Some(pb) => pb,
-            None => return &[],
-        };
-        i += 1;
+    /// The path of the print job file to write, unless a preview is rendered instead.
+    pub fn pt_path(&self) -> Option<&Path> {
+        if self.preview.is_some() {
+            None
+        } else {
+            Some(&self.paths[self.paths.len()-1])
+        }
+    }
 
-        while let Some(next_b) = slice.get(i) {
-            if prev_b != next_b {
-                i += 1;
-                prev_b = next_b;
-            } else {
-                break;
-            }
+    pub fn serial_format(&self) -> SerialFormat {
+        SerialFormat {
+            prefix: self.serial_prefix.clone(),
+            suffix: self.serial_suffix.clone(),
+            min_digits: self.serial_digits,
+            checksum: self.serial_checksum,
         }
+    }
 
-        &slice[..i]
+    pub fn profile(&self) -> &'static ModelProfile {
+        self.model.profile()
     }
 
-    let mut ret = Vec::with_capacity(2*bytes.len());
-    let mut i = 0;
-    while i < bytes.len() {
-        let repeated_slice = take_repeated(&bytes[i..]);
-        let verbatim_slice = take_verbatim(&bytes[i..]);
-        if repeated_slice.len() > verbatim_slice.len() {
-            assert!(repeated_slice.len() > 1);
+    /// How much wider generated text must be rendered to compensate for the resolution along the
+    /// tape.
+    pub fn text_stretch(&self) -> usize {
+        let profile = self.profile();
+        (profile.feed_dpi(self.hi_res) / profile.dpi).into()
+    }
 
-            i += repeated_slice.len();
+    pub fn text_height_px(&self) -> usize {
+        if self.text_height_px > 0 {
+            self.text_height_px.into()
+        } else {
+            self.printable_pins()
+        }
+    }
+
+    /// Returns the transformation to apply to a PNG image of the given dimensions.
+    pub fn orientation(&self, width: usize, height: usize) -> Orientation {
+        Orientation {
+            flip_horizontally: self.flip_h,
+            flip_vertically: self.flip_v,
+            rotation: self.rotate.resolve(width, height),
+        }
+    }
 
-            // can't do more than 128
-            let repeat_count = repeated_slice.len().min(128);
-            let repeat_byte_i16: i16 = 1 - i16::try_from(repeat_count).unwrap();
-            let repeat_byte_i8: i8 = repeat_byte_i16.try_into().unwrap();
-            let repeat_bytes = repeat_byte_i8.to_ne_bytes();
+    /// Returns the shortest label length supported on the selected tape or tube.
+    pub fn min_length_mm(&self) -> u8 {
+        match self.tube() {
+            Some(tube) => tube.min_length_mm,
+            None => self.profile().min_length_mm,
+        }
+    }
 
-            ret.push(repeat_bytes[0]);
-            ret.push(repeated_slice[0]);
+    /// Returns the number of pins that can print onto the selected tape, tube or labels.
+    ///
+    /// If the selected model does not know the tape width, `extend_to_width_px` is used instead.
+    pub fn printable_pins(&self) -> usize {
+        let pins = if let Some(tube) = self.tube() {
+            tube.printable_pins
+        } else if let Some(label) = self.label() {
+            label.printable_pins
+        } else if let Some(tape) = self.width_mm.and_then(|w| self.profile().tape(w)) {
+            tape.printable_pins
         } else {
-            assert!(verbatim_slice.len() > 0);
+            self.extend_to_width_px
+        };
+        if pins == 0 {
+            panic!("the printable area of the tape is unknown; pass --extend-to-width-px");
+        }
+        pins.into()
+    }
 
-            i += verbatim_slice.len();
+    pub fn tube(&self) -> Option<&'static TubeGeometry> {
+        let size = self.tube.as_ref()?;
+        match self.profile().tube(size) {
+            Some(tube) => Some(tube),
+            None => panic!("the {} does not support heat-shrink tube of size {:?}", self.model, size),
+        }
+    }
 
-            let verbatim_count = verbatim_slice.len().min(128);
-            let verbatim_byte_i8: i8 = (verbatim_count - 1).try_into().unwrap();
-            let verbatim_bytes = verbatim_byte_i8.to_ne_bytes();
+    pub fn label(&self) -> Option<&'static LabelGeometry> {
+        let size = self.die_cut?;
+        match self.profile().label(size.width_mm, size.length_mm) {
+            Some(label) => Some(label),
+            None => panic!("the {} does not support {}x{} mm die-cut labels", self.model, size.width_mm, size.length_mm),
+        }
+    }
 
-            ret.push(verbatim_bytes[0]);
-            ret.extend(verbatim_slice);
+    pub fn media_width_mm(&self) -> u8 {
+        if let Some(tube) = self.tube() {
+            tube.width_mm
+        } else if let Some(label) = self.label() {
+            label.width_mm
+        } else {
+            self.width_mm.unwrap()
         }
     }
-    ret
+
+    /// Returns the feed margin in dots, which QL models expect to match the media.
+    pub fn feed_dots(&self) -> u16 {
+        self.feed
+            .or_else(|| self.profile().required_feed_dots(self.die_cut.is_some()))
+            .unwrap_or(0)
+    }
+
+    /// Whether raster lines are sent the way QL models expect them, i.e. using `g` and always as
+    /// wide as the print head.
+    pub fn ql_raster(&self) -> bool {
+        self.profile().command_set == CommandSet::Ql
+    }
+
+    /// Returns how many blank pixels to add before and after each raster line of the given width.
+    ///
+    /// On heat-shrink tube, lines are centered within the printable area of the tube and padded to
+    /// the width of the print head. On QL models, they are centered within the printable area of
+    /// the roll or label and padded to the width of the print head as well. Otherwise, they are
+    /// centered within `extend_to_width_px` pins if they are narrower.
+    pub fn line_padding(&self, width: usize) -> (usize, usize) {
+        // lines that already span the print head (e.g. in renderings of QL jobs) are kept as they are
+        if self.ql_raster() && width != usize::from(self.profile().head_pins) {
+            let (margin_pins, printable_pins) = match self.label() {
+                Some(label) => (label.margin_pins, label.printable_pins),
+                None => {
+                    let width_mm = self.media_width_mm();
+                    let tape = self.profile().tape(width_mm)
+                        .unwrap_or_else(|| panic!("the {} does not support {} mm rolls", self.model, width_mm));
+                    (tape.margin_pins, tape.printable_pins)
+                },
+            };
+            let printable_pins = usize::from(printable_pins);
+            if width > printable_pins {
+                panic!("raster lines are {} pixels wide but the media only has {} printable pins", width, printable_pins);
+            }
+            let extend_front = usize::from(margin_pins) + (printable_pins - width) / 2;
+            let extend_rear = usize::from(self.profile().head_pins) - extend_front - width;
+            return (extend_front, extend_rear);
+        }
+        if let Some(tube) = self.tube() {
+            // like QL lines, tube lines span the whole print head so that the printer finds the
+            // image within the printable area of the tube
+            let extend_front = usize::from(tube.margin_pins) + usize::from(tube.printable_pins).saturating_sub(width) / 2;
+            let extend_rear = usize::from(self.profile().head_pins).saturating_sub(extend_front + width);
+            return (extend_front, extend_rear);
+        }
+        let extend_to = usize::from(self.extend_to_width_px);
+        if extend_to > width {
+            let total_extend = extend_to - width;
+            let extend_front = total_extend / 2;
+            let extend_back = if total_extend.is_multiple_of(2) {
+                extend_front
+            } else {
+                extend_front + 1
+            };
+            (extend_front, extend_back)
+        } else {
+            (0, 0)
+        }
+    }
+}
+
+
+fn lay_out_cable_label(opts: &Opts, layout: CableLayout) -> Bitmap {
+    // margin between the text and the ends of a flag half, as well as between copies of the text
+    const CABLE_TEXT_MARGIN_MM: f64 = 2.0;
+
+    let profile = opts.profile();
+    let diameter_mm = opts.cable_diameter_mm.unwrap();
+    if diameter_mm.is_nan() || diameter_mm <= 0.0 {
+        panic!("cable diameter must be positive");
+    }
+    let circumference = profile.mm_to_lines(std::f64::consts::PI * diameter_mm, opts.hi_res);
+    let margin = profile.mm_to_lines(CABLE_TEXT_MARGIN_MM, opts.hi_res);
+
+    let text_height = opts.text_height_px();
+    let scale = font::scale_for_height(text_height);
+    let text = font::render_text(opts.cable_text.as_ref().unwrap(), scale, opts.text_stretch(), text_height);
+
+    let label = match layout {
+        CableLayout::Flag => {
+            let half_length = match opts.flag_length_mm {
+                Some(mm) => profile.mm_to_lines(mm, opts.hi_res),
+                None => text.width() + 2*margin,
+            };
+            cable::lay_out_flag(&text, circumference, half_length, opts.flag_back)
+        },
+        CableLayout::Wrap => cable::lay_out_wrap(&text, circumference, margin),
+    };
+
+    // the layout is in reading orientation; turn it so that it runs along the tape
+    label.rotated_ccw()
 }
 
-trait ByteIteratorExt where Self : Iterator<Item = u8> + Sized {
-    fn bits_msb_first(self) -> BytesToBitsMsbFirst<Self>;
+/// Removes the blank rows from the beginning and end of a page, adds the margin back and pads the
+/// page to the minimum label length.
+fn trim_page(opts: &Opts, page: &mut Page) {
+    let content_rows = page.content_rows();
+    let profile = opts.profile();
+    let margin = profile.mm_to_lines(opts.margin_mm, opts.hi_res);
+    let min_length = profile.mm_to_lines(opts.min_length_mm().into(), opts.hi_res);
+
+    let length = content_rows.len() + 2*margin;
+    let (blank_before, blank_after) = if length < min_length {
+        // center the content within the minimum length
+        let extra = min_length - length;
+        (margin + extra / 2, margin + extra - extra / 2)
+    } else {
+        (margin, margin)
+    };
+    page.set_rows(content_rows, blank_before, blank_after);
 }
-impl<I: Iterator<Item = u8>> ByteIteratorExt for I {
-    fn bits_msb_first(self) -> BytesToBitsMsbFirst<Self> {
-        BytesToBitsMsbFirst {
-            inner: self,
-            current_byte: 0,
-            current_bit: 0,
-        }
+
+/// Pads or crops a page to exactly the given number of rows.
+///
+/// The alignment refers to the order in which the rows are printed.
+fn set_page_length(opts: &Opts, page: &mut Page, length: usize) {
+    // convert the alignment from printing order to bitmap order
+    let top_aligned = match (opts.align, opts.no_reverse) {
+        (Alignment::Center, _) => None,
+        (Alignment::Start, true)|(Alignment::End, false) => Some(true),
+        (Alignment::Start, false)|(Alignment::End, true) => Some(false),
+    };
+
+    let height = page.height();
+    if height <= length {
+        let extra = length - height;
+        let (blank_before, blank_after) = match top_aligned {
+            Some(true) => (0, extra),
+            Some(false) => (extra, 0),
+            None => (extra / 2, extra - extra / 2),
+        };
+        page.set_rows(0..height, blank_before, blank_after);
+    } else {
+        let excess = height - length;
+        let first_row = match top_aligned {
+            Some(true) => 0,
+            Some(false) => excess,
+            None => excess / 2,
+        };
+        page.set_rows(first_row..first_row+length, 0, 0);
     }
 }
 
-struct BytesToBitsMsbFirst<I: Iterator<Item = u8>> {
-    inner: I,
-    current_byte: u8,
-    current_bit: u8,
+/// Ensures that a page fits onto the given heat-shrink tube.
+fn check_tube_page(tube: &TubeGeometry, page_index: usize, page: &Page) {
+    if page.width() > usize::from(tube.printable_pins) {
+        panic!(
+            "page at index {} is {} pixels wide but heat-shrink tube of size {} only has {} printable pins",
+            page_index, page.width(), tube.diameter, tube.printable_pins,
+        );
+    }
 }
-impl<I: Iterator<Item = u8>> Iterator for BytesToBitsMsbFirst<I> {
-    type Item = bool;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current_bit == 0 {
-            // pull the next byte (or fall out)
-            self.current_byte = self.inner.next()?;
-        }
 
-        // extract the next bit
-        let ret = (self.current_byte & (1 << (7 - self.current_bit))) != 0;
-        self.current_bit = (self.current_bit + 1) % 8;
-        Some(ret)
+/// Pads a page that is shorter than the given heat-shrink tube allows to its minimum length.
+fn pad_tube_page(opts: &Opts, tube: &TubeGeometry, page: &mut Page) {
+    let min_lines = opts.profile().mm_to_lines(tube.min_length_mm.into(), opts.hi_res);
+    if page.height() < min_lines {
+        set_page_length(opts, page, min_lines);
     }
 }
 
-trait BitIteratorExt where Self : Iterator<Item = bool> + Sized {
-    fn bytes_msb_first(self) -> BitsToBytesMsbFirst<Self>;
+/// Loads a PNG file and scales it to the printable area according to the fit mode.
+///
+/// The scale keeps the aspect ratio of the image, taking into account that the resolution along
+/// the tape may differ from the one across it.
+fn load_fitted_png(opts: &Opts, png_path: &Path) -> Bitmap {
+    let mut image = GrayImage::load_png(png_path);
+    let orientation = opts.orientation(image.width(), image.height());
+    if !orientation.is_identity() {
+        image = image.transformed(&orientation);
+    }
+    let target_width = opts.printable_pins() as f64;
+    let width_scale = match opts.fit {
+        FitMode::None => 1.0,
+        FitMode::Shrink => (target_width / image.width() as f64).min(1.0),
+        FitMode::Fill => target_width / image.width() as f64,
+    };
+    let profile = opts.profile();
+    let height_scale = width_scale * f64::from(profile.feed_dpi(opts.hi_res)) / f64::from(profile.dpi);
+
+    let new_width = ((image.width() as f64 * width_scale).round() as usize).max(1);
+    let new_height = ((image.height() as f64 * height_scale).round() as usize).max(1);
+    image
+        .resampled(new_width, new_height)
+        .dithered()
 }
-impl<I: Iterator<Item = bool>> BitIteratorExt for I {
-    fn bytes_msb_first(self) -> BitsToBytesMsbFirst<Self> {
-        BitsToBytesMsbFirst {
-            inner: self,
+
+/// Loads the given rows of a PNG file into memory as a page and applies the orientation options
+/// to it.
+fn load_png_page(opts: &Opts, png_path: &Path, image_rows: Range<usize>) -> Page {
+    let mut reader = PngRowReader::open_with_colors(png_path, opts.two_color);
+    let mut black = Bitmap::new(reader.width(), 0);
+    let mut red = Bitmap::new(reader.width(), 0);
+    for y in 0..image_rows.end {
+        let pixels = reader.read_two_color_row()
+            .expect("PNG file has fewer rows than announced");
+        if y >= image_rows.start {
+            let (black_bits, red_bits): (Vec<bool>, Vec<bool>) = pixels.unzip();
+            black.push_row(black_bits.into_iter());
+            red.push_row(red_bits.into_iter());
         }
     }
+
+    let orientation = opts.orientation(black.width(), black.height());
+    let orient = |bitmap: Bitmap| if orientation.is_identity() {
+        bitmap
+    } else {
+        bitmap.transformed(&orientation)
+    };
+    if opts.two_color {
+        Page::TwoColor { black: orient(black), red: orient(red) }
+    } else {
+        Page::Bitmap(orient(black))
+    }
 }
 
-struct BitsToBytesMsbFirst<I: Iterator<Item = bool>> {
-    inner: I,
+fn load_png(png_path: &Path) -> Bitmap {
+    let mut reader = PngRowReader::open(png_path);
+    let mut bitmap = Bitmap::new(reader.width(), 0);
+    while let Some(bits) = reader.read_row() {
+        bitmap.push_row(bits);
+    }
+    bitmap
 }
-impl<I: Iterator<Item = bool>> Iterator for BitsToBytesMsbFirst<I> {
-    type Item = u8;
-    fn next(&mut self) -> Option<Self::Item> {
-        let first_bit = self.inner.next()?;
 
-        let mut current_byte = 0;
-        if first_bit {
-            current_byte |= 1 << 7;
+/// Pads a raster line with `extend_front` and `extend_rear` blank pixels and converts it into
+/// bytes.
+fn padded_row_bytes<I: Iterator<Item = bool>>(bits: I, extend_front: usize, extend_rear: usize) -> Vec<u8> {
+    // create the padding pixels
+    let front_extension_bits = std::iter::repeat_n(false, extend_front);
+    let rear_extension_bits = std::iter::repeat_n(false, extend_rear);
+
+    let complete_bits = front_extension_bits
+        .chain(bits)
+        .chain(rear_extension_bits);
+
+    complete_bits
+        .bytes_msb_first()
+        .collect()
+}
+
+/// Pads a raster line with `extend_front` and `extend_rear` blank pixels and converts it into
+/// bytes, which are compressed using PackBits if `compress` is true.
+///
+/// Lines without any markers are returned as empty vectors.
+fn pack_row<I: Iterator<Item = bool>>(bits: I, extend_front: usize, extend_rear: usize, compress: bool) -> Vec<u8> {
+    let complete_bytes = padded_row_bytes(bits, extend_front, extend_rear);
+
+    if complete_bytes.iter().all(|b| *b == 0x00) {
+        Vec::new()
+    } else if compress {
+        pack_bits(&complete_bytes)
+    } else {
+        complete_bytes
+    }
+}
+
+/// Converts a bitmap into the raster lines of a page, in the order in which they are sent to the
+/// printer.
+///
+/// `extend_front` and `extend_rear` blank pixels are added before and after each line, and lines
+/// are compressed using PackBits if `compress` is true. Unless `--no-reverse` is given, the bottom
+/// row of the bitmap is sent first.
+fn bitmap_to_rows(opts: &Opts, bitmap: &Bitmap, extend_front: usize, extend_rear: usize, compress: bool) -> Vec<RasterLine> {
+    let mut rows: Vec<RasterLine> = (0..bitmap.height())
+        .map(|y| mono_raster_line(opts.ql_raster(), bitmap.row_bits(y), extend_front, extend_rear, compress))
+        .collect();
+
+    // flip the rows
+    if !opts.no_reverse {
+        rows.reverse();
+    }
+
+    rows
+}
+
+/// Packs a black raster line like [`pack_row`] or, if it is sent the way QL models expect it,
+/// like [`pack_plane`].
+fn mono_raster_line<I: Iterator<Item = bool>>(ql_raster: bool, bits: I, extend_front: usize, extend_rear: usize, compress: bool) -> RasterLine {
+    if ql_raster {
+        RasterLine::Ql(pack_plane(bits, extend_front, extend_rear, compress))
+    } else {
+        RasterLine::Mono(pack_row(bits, extend_front, extend_rear, compress))
+    }
+}
+
+/// Pads one colour of a two-colour raster line or a raster line for a QL model like [`pack_row`],
+/// but returns blank lines as well, since those are never sent using `Z`.
+fn pack_plane<I: Iterator<Item = bool>>(bits: I, extend_front: usize, extend_rear: usize, compress: bool) -> Vec<u8> {
+    let complete_bytes = padded_row_bytes(bits, extend_front, extend_rear);
+    if compress {
+        pack_bits(&complete_bytes)
+    } else {
+        complete_bytes
+    }
+}
+
+/// Decides whether compressing the raster lines of the given pages using PackBits makes the print
+/// job smaller.
+///
+/// Streamed pages are read an additional time to find out.
+fn packbits_saves_space(opts: &Opts, pages: &[Page]) -> bool {
+    let mut packed_size = 0;
+    let mut raw_size = 0;
+    let mut add_row = |bytes: Vec<u8>, blank_sent_as_z: bool| {
+        // blank black lines are sent as "Z" either way
+        if !blank_sent_as_z || bytes.iter().any(|b| *b != 0x00) {
+            packed_size += pack_bits(&bytes).len();
+            raw_size += bytes.len();
         }
+    };
+
+    // QL models do not take "Z"
+    let blank_sent_as_z = !opts.ql_raster();
+    for page in pages {
+        let (extend_front, extend_rear) = opts.line_padding(page.width());
+        match page {
+            Page::Bitmap(bitmap) => {
+                for y in 0..bitmap.height() {
+                    add_row(padded_row_bytes(bitmap.row_bits(y), extend_front, extend_rear), blank_sent_as_z);
+                }
+            },
+            Page::TwoColor { black, red } => {
+                for y in 0..black.height() {
+                    // both colours are sent even if they are blank
+                    add_row(padded_row_bytes(black.row_bits(y), extend_front, extend_rear), false);
+                    add_row(padded_row_bytes(red.row_bits(y), extend_front, extend_rear), false);
+                }
+            },
+            Page::StreamedPng(png) => {
+                let mut add_line = |black: Vec<bool>, red: Option<Vec<bool>>| match red {
+                    Some(red) => {
+                        add_row(padded_row_bytes(black.into_iter(), extend_front, extend_rear), false);
+                        add_row(padded_row_bytes(red.into_iter(), extend_front, extend_rear), false);
+                    },
+                    None => add_row(padded_row_bytes(black.into_iter(), extend_front, extend_rear), blank_sent_as_z),
+                };
+                png.read_rows(&mut add_line);
+                for _ in 0..png.blank_before + png.blank_after {
+                    add_line(vec![false; png.width], png.two_color.then(|| vec![false; png.width]));
+                }
+            },
+        }
+    }
+    packed_size < raw_size
+}
+
+/// The (possibly compressed) data of a raster line as it is sent to the printer.
+enum RasterLine {
+    /// A black raster line (`G`, or `Z` if it is empty).
+    Mono(Vec<u8>),
+
+    /// A black raster line for a QL model (`g`).
+    Ql(Vec<u8>),
 
-        for i in (0..7).rev() {
-            let Some(next_bit) = self.inner.next() else { break };
-            if next_bit {
-                current_byte |= 1 << i;
+    /// The black and the red data of a raster line for two-colour printing (`w`).
+    TwoColor { black: Vec<u8>, red: Vec<u8> },
+}
+
+
+/// Writes a single raster line.
+fn write_raster_line<W: Write>(out: &mut W, row: RasterLine) {
+    match row {
+        RasterLine::Mono(row) if row.is_empty() => {
+            out.write_all(b"Z")
+                .expect("failed to write empty row");
+        },
+        RasterLine::Mono(row) => {
+            let data_length: u16 = row.len().try_into().unwrap();
+            let data_length_bytes = data_length.to_le_bytes();
+            out.write_all(&[b'G', data_length_bytes[0], data_length_bytes[1]])
+                .expect("failed to write row metadata");
+            out.write_all(&row)
+                .expect("failed to write row data");
+        },
+        RasterLine::Ql(row) => {
+            // unlike with "G", the length is big-endian
+            let data_length: u16 = row.len().try_into().unwrap();
+            let data_length_bytes = data_length.to_be_bytes();
+            out.write_all(&[b'g', data_length_bytes[0], data_length_bytes[1]])
+                .expect("failed to write row metadata");
+            out.write_all(&row)
+                .expect("failed to write row data");
+        },
+        RasterLine::TwoColor { black, red } => {
+            for (color, data) in [(RasterColor::Black, black), (RasterColor::Red, red)] {
+                let data_length: u8 = data.len().try_into()
+                    .expect("two-colour raster line is too long");
+                out.write_all(&[b'w', color.as_byte(), data_length])
+                    .expect("failed to write two-colour row metadata");
+                out.write_all(&data)
+                    .expect("failed to write two-colour row data");
             }
+        },
+    }
+}
+
+/// Writes a page consisting of `row_count` raster lines, which `rows` yields in the order in which
+/// they are sent to the printer.
+fn write_page<W, I>(out: &mut W, opts: &Opts, page_index: usize, page_count: usize, row_count: usize, rows: I)
+    where
+        W: Write,
+        I: IntoIterator<Item = RasterLine>,
+{
+    announce_page(out, opts, page_index, page_count, row_count);
+
+    let mut rows_written = 0;
+    for row in rows {
+        write_raster_line(out, row);
+        rows_written += 1;
+    }
+    if rows_written != row_count {
+        panic!("page at index {} announced {} rows but contains {}", page_index, row_count, rows_written);
+    }
+
+    end_page(out, page_index, page_count);
+}
+
+/// Writes the page information (`ESC i z`) of a page consisting of `row_count` raster lines.
+fn announce_page<W: Write>(out: &mut W, opts: &Opts, page_index: usize, page_count: usize, row_count: usize) {
+    let page_byte = if page_index == page_count - 1 && opts.last_page_2 {
+        // last (or single) page
+        2
+    } else if page_index == 0 {
+        // first page
+        0
+    } else {
+        // middle page
+        1
+    };
+
+    // media width is given, printer recovery is on
+    let mut validity_byte = 0x04 | 0x80;
+    let mut media_length_byte = 0x00; // "endless"
+    let media_type_byte = if opts.tube.is_some() {
+        // media type is given as well
+        validity_byte |= 0x02;
+        MediaType::HeatShrinkTube2To1.as_byte()
+    } else if let Some(label) = opts.label() {
+        // media type and length are given as well
+        validity_byte |= 0x02 | 0x08;
+        media_length_byte = label.length_mm;
+        MediaType::DieCutLabels.as_byte()
+    } else if opts.ql_raster() {
+        // media type is given as well
+        validity_byte |= 0x02;
+        MediaType::ContinuousTape.as_byte()
+    } else {
+        // ignored because 0x02 presence flag is missing
+        0x00
+    };
+
+    let line_count_u32: u32 = row_count.try_into().unwrap();
+    let line_count_bytes = line_count_u32.to_le_bytes();
+    out.write_all(&[
+        ESC, b'i', b'z',
+        validity_byte,
+        media_type_byte,
+        opts.media_width_mm(),
+        media_length_byte,
+        line_count_bytes[0],
+        line_count_bytes[1],
+        line_count_bytes[2],
+        line_count_bytes[3],
+        page_byte,
+        0, // always zero
+    ])
+        .expect("failed to write page info");
+}
+
+/// Writes the command that prints a page, which also feeds the tape if it is the last one.
+fn end_page<W: Write>(out: &mut W, page_index: usize, page_count: usize) {
+    if page_index == page_count - 1 {
+        // print and feed
+        out.write_all(&[0x1A])
+            .expect("failed to write print-and-feed command");
+    } else {
+        // print
+        out.write_all(&[0x0C])
+            .expect("failed to write print command");
+    };
+}
+
+/// Writes a page whose rows are read from a PNG file as the page is written.
+///
+/// If the rows of the file are stored in the opposite order from the one in which they are sent,
+/// they are first spilled into a temporary file, which is then read backwards.
+fn write_streamed_png_page<W: Write>(out: &mut W, opts: &Opts, page_index: usize, page_count: usize, png: &StreamedPng, compress: bool) {
+    let (extend_front, extend_rear) = opts.line_padding(png.width);
+    let ql_raster = opts.ql_raster();
+    let to_line = |black: Vec<bool>, red: Option<Vec<bool>>| match red {
+        Some(red) => RasterLine::TwoColor {
+            black: pack_plane(black.into_iter(), extend_front, extend_rear, compress),
+            red: pack_plane(red.into_iter(), extend_front, extend_rear, compress),
+        },
+        None => mono_raster_line(ql_raster, black.into_iter(), extend_front, extend_rear, compress),
+    };
+    let blank_line = || to_line(vec![false; png.width], png.two_color.then(|| vec![false; png.width]));
+
+    // unless --no-reverse is given, the last row of the page is sent first
+    let (blank_sent_first, blank_sent_last) = if opts.no_reverse {
+        (png.blank_before, png.blank_after)
+    } else {
+        (png.blank_after, png.blank_before)
+    };
+    let file_order_is_sent_order = png.flip_vertically != opts.no_reverse;
+
+    announce_page(out, opts, page_index, page_count, png.height());
+    for _ in 0..blank_sent_first {
+        write_raster_line(out, blank_line());
+    }
+    if file_order_is_sent_order {
+        png.read_rows(|black, red| write_raster_line(out, to_line(black, red)));
+    } else {
+        let mut spill = RowSpill::new();
+        png.read_rows(|black, red| match to_line(black, red) {
+            RasterLine::Mono(data)|RasterLine::Ql(data) => spill.push(&data),
+            RasterLine::TwoColor { black, red } => {
+                spill.push(&black);
+                spill.push(&red);
+            },
+        });
+        let mut spilled_rows = spill.into_reversed();
+        while let Some(data) = spilled_rows.next() {
+            let line = if png.two_color {
+                // the red part of each line comes back first
+                let black = spilled_rows.next()
+                    .expect("temporary file is truncated");
+                RasterLine::TwoColor { black, red: data }
+            } else if ql_raster {
+                RasterLine::Ql(data)
+            } else {
+                RasterLine::Mono(data)
+            };
+            write_raster_line(out, line);
         }
-        Some(current_byte)
     }
+    for _ in 0..blank_sent_last {
+        write_raster_line(out, blank_line());
+    }
+    end_page(out, page_index, page_count);
 }
 
+/// Renders the print job in the given file into a PNG file using the rendering of
+/// `ptouch-decode`.
+fn write_preview(opts: &Opts, job_file: &mut File, preview_path: &Path) {
+    // the job has just been generated, so there is nothing to protect against
+    let limits = Limits {
+        max_width: usize::MAX,
+        max_rows_per_page: usize::MAX,
+        max_pages: usize::MAX,
+        max_decompressed_bytes: usize::MAX,
+    };
+
+    // first pass: collect the settings and dimensions
+    job_file.rewind()
+        .expect("failed to rewind print job file");
+    let mut job = JobState::new(limits);
+    let commands = CommandReader::new(BufReader::new(&*job_file))
+        .expect("failed to read back print job");
+    for command in commands {
+        let command = command
+            .expect("failed to read back print job");
+        job.apply(&command)
+            .expect("generated print job is invalid");
+    }
 
-fn main() -> ExitCode {
-    let opts = Opts::parse();
-    if opts.png_paths.len() == 0 {
-        panic!("at least one PNG file must be given");
+    // second pass: render
+    let options = RenderOptions {
+        feed_margins: true,
+        cut_marks: true,
+        scale: Some(opts.profile()),
+        embed_settings: true,
+    };
+    let mut renderer = Renderer::new(options, &job);
+    if !renderer.has_image() {
+        eprintln!("warning: the print job contains no pixels; no preview has been written");
+        return;
     }
+    let preview_file = File::create(preview_path)
+        .expect("failed to create preview file");
+    let mut png_stream_wr = renderer.start_png(BufWriter::new(preview_file));
+    job_file.rewind()
+        .expect("failed to rewind print job file");
+    let commands = CommandReader::new(BufReader::new(&*job_file))
+        .expect("failed to read back print job");
+    for command in commands {
+        let command = command
+            .expect("failed to read back print job");
+        for row in renderer.rows(&command) {
+            png_stream_wr.write_all(&row)
+                .expect("failed to write into PNG stream");
+        }
+    }
+    png_stream_wr.finish()
+        .expect("failed to finish PNG encoding");
+}
 
-    let mut pages = Vec::new();
-    let mut width = None;
-    for (png_index, png_path) in opts.png_paths.iter().enumerate() {
-        let f = File::open(png_path)
-            .expect("failed to open PNG file");
-        let f_buf = BufReader::new(f);
-        let dec = png::Decoder::new(f_buf);
-        let mut reader = dec.read_info()
-            .expect("failed to decode PNG file");
-        if let Some(w) = width {
-            if reader.info().width != w {
-                panic!("PNG at index {} has different width {} (index 0: width {})", png_index, reader.info().width, w);
-            }
-        } else {
-            width = Some(reader.info().width);
+
+/// Creates the print job file or, if a preview is rendered instead, a temporary file.
+fn create_output_file(opts: &Opts) -> File {
+    match opts.pt_path() {
+        Some(pt_path) => File::create(pt_path)
+            .expect("failed to create output file"),
+        // a preview is rendered from the print job as it would have been written
+        None => tempfile::tempfile()
+            .expect("failed to create temporary file"),
+    }
+}
+
+/// Writes the print job described by a bundle.
+fn write_bundle_job<W: Write>(out: &mut W, bundle_dir: &Path, bundle: &JobBundle) {
+    let invalidate_length = usize::try_from(bundle.invalidate_length).unwrap();
+    out.write_all(&vec![0u8; invalidate_length])
+        .expect("failed to write invalidate bytes");
+    out.write_all(&[ESC, b'@'])
+        .expect("failed to write reset");
+    if let Some(notification) = bundle.auto_status_notification {
+        out.write_all(&[ESC, b'i', b'!', notification])
+            .expect("failed to write status notification setting");
+    }
+    out.write_all(&[ESC, b'i', b'a', 0x01])
+        .expect("failed to write switch-to-raster-mode");
+    if let Some(mode) = bundle.mode {
+        out.write_all(&[ESC, b'i', b'M', mode.as_byte()])
+            .expect("failed to write options");
+    }
+    if let Some(advanced_mode) = bundle.advanced_mode {
+        out.write_all(&[ESC, b'i', b'K', advanced_mode.as_byte()])
+            .expect("failed to write settings");
+    }
+    if let Some(cut_every) = bundle.cut_every {
+        out.write_all(&[ESC, b'i', b'A', cut_every])
+            .expect("failed to write cut-every setting");
+    }
+    if let Some(feed) = bundle.feed {
+        let feed_buf = feed.to_le_bytes();
+        out.write_all(&[ESC, b'i', b'd', feed_buf[0], feed_buf[1]])
+            .expect("failed to write feed setting");
+    }
+    let compress = bundle.compression == Some(CompressionMode::PackBits);
+    if let Some(compression) = bundle.compression {
+        let compression_mode = if compression == CompressionMode::PackBits { 0x02 } else { 0x00 };
+        out.write_all(&[b'M', compression_mode])
+            .expect("failed to write compression instruction");
+    }
+
+    for page in &bundle.pages {
+        let rows: Vec<RasterLine> = match (&page.image, &page.red_image) {
+            (Some(image_name), Some(red_image_name)) => {
+                let black = load_png(&bundle_dir.join(image_name));
+                let red = load_png(&bundle_dir.join(red_image_name));
+                if black.width() != red.width() || black.height() != red.height() {
+                    panic!("the images {} and {} of a two-colour page differ in size", image_name, red_image_name);
+                }
+                (0..black.height())
+                    .map(|y| RasterLine::TwoColor {
+                        black: pack_plane(black.row_bits(y), 0, 0, compress),
+                        red: pack_plane(red.row_bits(y), 0, 0, compress),
+                    })
+                    .collect()
+            },
+            (Some(image_name), None) => {
+                let bitmap = load_png(&bundle_dir.join(image_name));
+                (0..bitmap.height())
+                    .map(|y| mono_raster_line(bundle.ql_raster, bitmap.row_bits(y), 0, 0, compress))
+                    .collect()
+            },
+            (None, _) if page.two_color => (0..page.blank_rows)
+                .map(|_| RasterLine::TwoColor { black: Vec::new(), red: Vec::new() })
+                .collect(),
+            (None, _) if bundle.ql_raster => (0..page.blank_rows).map(|_| RasterLine::Ql(Vec::new())).collect(),
+            (None, _) => (0..page.blank_rows).map(|_| RasterLine::Mono(Vec::new())).collect(),
+        };
+
+        if let Some(information) = &page.information {
+            out.write_all(&[ESC, b'i', b'z'])
+                .expect("failed to write page info");
+            out.write_all(&information.to_bytes(rows.len()))
+                .expect("failed to write page info");
         }
-        if reader.info().bit_depth != png::BitDepth::One {
-            panic!("PNG bit depth is not 1");
+        for row in rows {
+            write_raster_line(out, row);
         }
-        let flip_bits = match reader.info().color_type {
-            png::ColorType::Grayscale => {
-                // PNG: 1 = white, 0 = black
-                // P-Touch: 0 = no marker, 1 = marker
-                // => flip
-                true
-            },
-            png::ColorType::Indexed => {
-                let palette = reader.info().palette.as_ref()
-                    .expect("image does not have a palette");
-                if palette.len() != 6 {
-                    panic!("image's palette has {} entries; expected 6 (2xRGB)", palette.len());
+        match page.end {
+            PageEnd::Print => out.write_all(&[0x0C])
+                .expect("failed to write print command"),
+            PageEnd::PrintFeed => out.write_all(&[0x1A])
+                .expect("failed to write print-and-feed command"),
+            PageEnd::Unprinted => {},
+        }
+    }
+}
+
+
+fn main() -> ExitCode {
+    let matches = Opts::command().get_matches();
+    let mut opts = Opts::from_arg_matches(&matches)
+        .unwrap_or_else(|e| e.exit());
+    if let Some(png_settings) = opts.png_paths()
+        .iter()
+        .map(|png_path| png_rows::read_png_settings(png_path))
+        .find(|png_settings| *png_settings != PngSettings::default())
+    {
+        opts.apply_png_settings(&png_settings, &matches);
+    }
+    if opts.width_mm.is_none() && opts.tube.is_none() && opts.die_cut.is_none() && opts.bundle.is_none() {
+        Opts::command()
+            .error(ErrorKind::MissingRequiredArgument, "--width-mm is required unless the PNG files store the media width")
+            .exit();
+    }
+    if opts.two_color && !opts.profile().two_color {
+        Opts::command()
+            .error(ErrorKind::ArgumentConflict, format!("the {} cannot print in two colours; --two-color needs a model such as the QL-800", opts.model))
+            .exit();
+    }
+
+    if let Some(bundle_dir) = &opts.bundle {
+        let bundle = JobBundle::read(bundle_dir)
+            .expect("failed to read bundle");
+        let mut out_file = create_output_file(&opts);
+        let mut out_buffy = BufWriter::new(&mut out_file);
+        write_bundle_job(&mut out_buffy, bundle_dir, &bundle);
+        out_buffy.flush()
+            .expect("failed to flush output file");
+        drop(out_buffy);
+
+        if let Some(preview_path) = &opts.preview {
+            write_preview(&opts, &mut out_file, preview_path);
+        }
+        return ExitCode::SUCCESS;
+    }
+    if opts.png_paths().is_empty() && opts.serial.is_none() && opts.cable_layout.is_none() {
+        panic!("at least one PNG file, a serial number range or a cable layout must be given");
+    }
+
+    let mut pages = Vec::new();
+    for png_path in opts.png_paths() {
+        // only look at the header for now
+        let reader = PngRowReader::open_with_colors(png_path, opts.two_color);
+        let (width, height) = (reader.width(), reader.height());
+        let image_rows: Vec<Range<usize>> = match &reader.settings().pages {
+            Some(page_rows) => {
+                // a rendering by ptouch-decode; take each page from its rows
+                if opts.fit != FitMode::None {
+                    panic!("images rendered by ptouch-decode cannot be scaled");
                 }
-                if &**palette == &[0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF] {
-                    // same as grayscale; flip
-                    true
-                } else if &**palette == &[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00] {
-                    // pre-flipped
-                    false
-                } else {
-                    panic!("image's palette contains other colors than full black and full white");
+                if let Some(rows) = page_rows.iter().find(|rows| rows.end > height) {
+                    panic!("page rows {:?} are outside of the image {}", rows, png_path.display());
                 }
+                page_rows.clone()
             },
-            ct => panic!("image has invalid color type {:?}", ct),
+            None => std::iter::once(0..height).collect(),
         };
+        if opts.two_color && opts.fit != FitMode::None {
+            panic!("images for two-colour printing cannot be scaled");
+        }
 
-        let mut rows = Vec::new();
-        loop {
-            let ols = reader.output_line_size(width.unwrap())
-                .expect("failed to obtain output line size");
-            let mut buf = vec![0u8; ols];
-            let row_opt = reader.read_row(&mut buf)
-                .expect("failed to read row");
-            if row_opt.is_none() {
-                break;
+        for rows in image_rows {
+            let orientation = opts.orientation(width, rows.len());
+            let quarter_turn = matches!(orientation.rotation, Rotation::Clockwise90|Rotation::Clockwise270);
+            if opts.fit == FitMode::None && !quarter_turn {
+                pages.push(Page::StreamedPng(StreamedPng::new(png_path.clone(), width, rows, opts.two_color, &orientation)));
+                continue;
             }
 
-            let width_usize: usize = width.unwrap().try_into().unwrap();
-            let extend_to_usize: usize = opts.extend_to_width_px.into();
-            let (extend_front, extend_rear) = if extend_to_usize > width_usize {
-                let total_extend = extend_to_usize - width_usize;
-                let extend_front = total_extend / 2;
-                let extend_back = if total_extend % 2 == 0 {
-                    extend_front
-                } else {
-                    extend_front + 1
-                };
-                (extend_front, extend_back)
-            } else {
-                (0, 0)
+            // scaling and turning images by 90° needs all of their pixels at once
+            if width.saturating_mul(rows.len()) > MAX_LOADED_PIXELS {
+                Opts::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!(
+                            "{} is {}x{} pixels, but only images of up to {} pixels can be scaled or rotated by 90 or 270 degrees",
+                            png_path.display(), width, rows.len(), MAX_LOADED_PIXELS,
+                        ),
+                    )
+                    .exit();
+            }
+            let page = match opts.fit {
+                FitMode::None => load_png_page(&opts, png_path, rows),
+                FitMode::Shrink|FitMode::Fill => Page::Bitmap(load_fitted_png(&opts, png_path)),
             };
+            pages.push(page);
+        }
+    }
 
-            // create the padding pixels
-            let front_extension_bits = std::iter::repeat_n(false, extend_front);
-            let rear_extension_bits = std::iter::repeat_n(false, extend_rear);
-
-            let flipped_bits = buf
-                // turn the row into bits
-                .iter()
-                .copied()
-                .bits_msb_first()
-                // take only what you need from it
-                .take(width_usize)
-                // flip if necessary
-                .map(|bit| if flip_bits { !bit } else { bit });
-
-            let complete_bits = front_extension_bits
-                .chain(flipped_bits)
-                .chain(rear_extension_bits);
-
-            let complete_bytes: Vec<u8> = complete_bits
-                .bytes_msb_first()
-                .collect();
-
-            if complete_bytes.iter().all(|b| *b == 0x00) {
-                rows.push(vec![]);
-            } else {
-                let packed = pack_bits(&complete_bytes);
-                rows.push(packed);
+    if let Some(serial_range) = opts.serial {
+        let serial_format = opts.serial_format();
+        let text_height = opts.text_height_px();
+        let scale = font::scale_for_height(text_height);
+        for value in serial_range.values(opts.serial_step) {
+            let text = serial_format.format(value);
+
+            // text is rendered in reading orientation; turn it so that it runs along the tape
+            let rendered = font::render_text(&text, scale, opts.text_stretch(), text_height);
+            pages.push(Page::Bitmap(rendered.rotated_ccw()));
+        }
+    }
+
+    if let Some(cable_layout) = opts.cable_layout {
+        pages.push(Page::Bitmap(lay_out_cable_label(&opts, cable_layout)));
+    }
+
+    if opts.two_color {
+        // pages without red (e.g. serial numbers) are sent in two colours as well
+        for page in &mut pages {
+            if let Page::Bitmap(bitmap) = page {
+                let red = Bitmap::new(bitmap.width(), bitmap.height());
+                let black = std::mem::replace(bitmap, Bitmap::new(0, 0));
+                *page = Page::TwoColor { black, red };
             }
         }
+    }
 
-        // flip the rows
-        rows.reverse();
+    if opts.trim {
+        if opts.margin_mm.is_nan() || opts.margin_mm < 0.0 {
+            panic!("margin must not be negative");
+        }
+        for page in &mut pages {
+            trim_page(&opts, page);
+        }
+    }
 
-        pages.push(rows);
+    if let Some(length_mm) = opts.length_mm {
+        if length_mm.is_nan() || length_mm < f64::from(opts.min_length_mm()) {
+            panic!("label length must be at least {} mm", opts.min_length_mm());
+        }
+        let length = opts.profile().mm_to_lines(length_mm, opts.hi_res);
+        for page in &mut pages {
+            set_page_length(&opts, page, length);
+        }
+    }
+
+    if let Some(label) = opts.label() {
+        // each page takes exactly one label
+        let length = usize::from(label.printable_lines) * if opts.hi_res { 2 } else { 1 };
+        for (page_index, page) in pages.iter_mut().enumerate() {
+            if page.height() > length {
+                panic!(
+                    "page at index {} is {} lines long but {}x{} mm labels only take {} lines",
+                    page_index, page.height(), label.width_mm, label.length_mm, length,
+                );
+            }
+            set_page_length(&opts, page, length);
+        }
+    }
+
+    if let Some(tube) = opts.tube() {
+        // the printer refuses to print shorter pieces of tube
+        for page in &mut pages {
+            pad_tube_page(&opts, tube, page);
+        }
+    }
+
+    for (page_index, page) in pages.iter().enumerate() {
+        if page.width() != pages[0].width() {
+            panic!("page at index {} has different width {} (index 0: width {})", page_index, page.width(), pages[0].width());
+        }
+        if let Some(tube) = opts.tube() {
+            check_tube_page(tube, page_index, page);
+        }
     }
 
     // let's go
-    let mut out_file = File::create(&opts.pt_path)
-        .expect("failed to create output file");
+    let mut out_file = create_output_file(&opts);
     let mut out_buffy = BufWriter::new(&mut out_file);
 
     // 350 bytes invalidate
@@ -373,7 +1659,8 @@ fn main() -> ExitCode {
 
     // all the other settings
     let mut setting_byte = 0u8;
-    if opts.draft {
+    // the same bit means draft on P-touch models and two-colour printing on QL models
+    if opts.draft || opts.two_color {
         setting_byte |= 0x01;
     }
     if opts.half_cut {
@@ -382,7 +1669,7 @@ fn main() -> ExitCode {
     if opts.no_chain {
         setting_byte |= 0x08;
     }
-    if opts.special_tape {
+    if opts.special_tape || opts.tube.is_some() {
         setting_byte |= 0x10;
     }
     if opts.hi_res {
@@ -399,71 +1686,53 @@ fn main() -> ExitCode {
             .expect("failed to write cut-every setting");
     }
 
-    let feed_buf = opts.feed.to_le_bytes();
+    let feed_buf = opts.feed_dots().to_le_bytes();
     out_buffy.write_all(&[ESC, b'i', b'd', feed_buf[0], feed_buf[1]])
         .expect("failed to write feed setting");
 
-    const SET_PACKBITS_COMPRESSION: &[u8] = &[b'M', 0x02];
-    out_buffy.write_all(&SET_PACKBITS_COMPRESSION)
+    let compress = match opts.compression {
+        Compression::PackBits => true,
+        Compression::None => false,
+        Compression::Auto => packbits_saves_space(&opts, &pages),
+    };
+    let compression_mode = if compress { 0x02 } else { 0x00 };
+    out_buffy.write_all(&[b'M', compression_mode])
         .expect("failed to write compression instruction");
 
-    for (page_index, page_rows) in pages.iter().enumerate() {
-        let page_byte = if page_index == pages.len() - 1 && opts.last_page_2 {
-            // last (or single) page
-            2
-        } else if page_index == 0 {
-            // first page
-            0
-        } else {
-            // middle page
-            1
-        };
-
-        let line_count_usize = page_rows.len();
-        let line_count_u32: u32 = line_count_usize.try_into().unwrap();
-        let line_count_bytes = line_count_u32.to_le_bytes();
-        out_buffy.write_all(&[
-            ESC, b'i', b'z',
-            0x04 | 0x80, // media width is given, printer recovery is on
-            0x00, // media type (ignored because 0x02 presence flag is missing)
-            opts.width_mm,
-            0x00, // length ("endless")
-            line_count_bytes[0],
-            line_count_bytes[1],
-            line_count_bytes[2],
-            line_count_bytes[3],
-            page_byte,
-            0, // always zero
-        ])
-            .expect("failed to write page info");
-
-        for row in page_rows {
-            if row.len() == 0 {
-                out_buffy.write_all(&[b'Z'])
-                    .expect("failed to write empty row");
-            } else {
-                let data_length: u16 = row.len().try_into().unwrap();
-                let data_length_bytes = data_length.to_le_bytes();
-                out_buffy.write_all(&[b'G', data_length_bytes[0], data_length_bytes[1]])
-                    .expect("failed to write row metadata");
-                out_buffy.write_all(row)
-                    .expect("failed to write row data");
-            }
+    // pages are converted one at a time so that only one of them is held in compressed form
+    for (page_index, page) in pages.iter().enumerate() {
+        match page {
+            Page::Bitmap(bitmap) => {
+                let (extend_front, extend_rear) = opts.line_padding(bitmap.width());
+                let rows = bitmap_to_rows(&opts, bitmap, extend_front, extend_rear, compress);
+                write_page(&mut out_buffy, &opts, page_index, pages.len(), rows.len(), rows);
+            },
+            Page::TwoColor { black, red } => {
+                let (extend_front, extend_rear) = opts.line_padding(black.width());
+                let mut rows: Vec<RasterLine> = (0..black.height())
+                    .map(|y| RasterLine::TwoColor {
+                        black: pack_plane(black.row_bits(y), extend_front, extend_rear, compress),
+                        red: pack_plane(red.row_bits(y), extend_front, extend_rear, compress),
+                    })
+                    .collect();
+                if !opts.no_reverse {
+                    rows.reverse();
+                }
+                write_page(&mut out_buffy, &opts, page_index, pages.len(), rows.len(), rows);
+            },
+            Page::StreamedPng(png) => {
+                write_streamed_png_page(&mut out_buffy, &opts, page_index, pages.len(), png, compress);
+            },
         }
-
-        if page_index == pages.len() - 1 {
-            // print and feed
-            out_buffy.write_all(&[0x1A])
-                .expect("failed to write print-and-feed command");
-        } else {
-            // print
-            out_buffy.write_all(&[0x0C])
-                .expect("failed to write print command");
-        };
     }
 
     out_buffy.flush()
         .expect("failed to flush output file");
+    drop(out_buffy);
+
+    if let Some(preview_path) = &opts.preview {
+        write_preview(&opts, &mut out_file, preview_path);
+    }
 
     ExitCode::SUCCESS
 }
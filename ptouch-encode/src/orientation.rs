@@ -0,0 +1,102 @@
+use std::str::FromStr;
+
+
+/// A clockwise rotation by a multiple of 90°.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Rotation {
+    #[default] None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+
+/// The rotation requested on the command line.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum RotationOption {
+    Fixed(Rotation),
+
+    /// Rotate the image such that its longer side runs along the tape.
+    Auto,
+}
+impl RotationOption {
+    /// Decides on the rotation of an image with the given dimensions.
+    ///
+    /// As with every page, the width of the image runs across the tape and its height along it.
+    pub fn resolve(&self, width: usize, height: usize) -> Rotation {
+        match self {
+            Self::Fixed(rotation) => *rotation,
+            Self::Auto => if width > height {
+                // same direction as generated text
+                Rotation::Clockwise270
+            } else {
+                Rotation::None
+            },
+        }
+    }
+}
+impl FromStr for RotationOption {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Self::Fixed(Rotation::None)),
+            "90" => Ok(Self::Fixed(Rotation::Clockwise90)),
+            "180" => Ok(Self::Fixed(Rotation::Clockwise180)),
+            "270" => Ok(Self::Fixed(Rotation::Clockwise270)),
+            "auto" => Ok(Self::Auto),
+            other => Err(format!("invalid rotation {:?}; expected 0, 90, 180, 270 or auto", other)),
+        }
+    }
+}
+
+
+/// A combination of mirroring and rotation applied to an image.
+///
+/// The image is first mirrored, then rotated.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Orientation {
+    /// Mirror the image along its vertical axis (left becomes right).
+    pub flip_horizontally: bool,
+
+    /// Mirror the image along its horizontal axis (top becomes bottom).
+    pub flip_vertically: bool,
+
+    pub rotation: Rotation,
+}
+impl Orientation {
+    pub fn rotated(rotation: Rotation) -> Self {
+        Self {
+            rotation,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the dimensions of an image of the given dimensions once it has been transformed.
+    pub fn transformed_size(&self, width: usize, height: usize) -> (usize, usize) {
+        match self.rotation {
+            Rotation::None|Rotation::Clockwise180 => (width, height),
+            Rotation::Clockwise90|Rotation::Clockwise270 => (height, width),
+        }
+    }
+
+    /// Returns which pixel of the original image (of the given dimensions) ends up at the given
+    /// coordinates of the transformed image.
+    pub fn source_coordinates(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        // undo the rotation
+        let (mirrored_x, mirrored_y) = match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Clockwise90 => (y, height - 1 - x),
+            Rotation::Clockwise180 => (width - 1 - x, height - 1 - y),
+            Rotation::Clockwise270 => (width - 1 - y, x),
+        };
+
+        // undo the mirroring
+        let source_x = if self.flip_horizontally { width - 1 - mirrored_x } else { mirrored_x };
+        let source_y = if self.flip_vertically { height - 1 - mirrored_y } else { mirrored_y };
+        (source_x, source_y)
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+
+
+/// The longest run (of repeated or literal bytes) that a single PackBits instruction can encode.
+const MAX_RUN: usize = 128;
+
+
+/// One PackBits instruction.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+enum Instruction {
+    /// Copy this many bytes verbatim.
+    Literal(usize),
+
+    /// Repeat the next byte this many times.
+    Repeat(usize),
+}
+impl Instruction {
+    fn input_len(&self) -> usize {
+        match self {
+            Self::Literal(len)|Self::Repeat(len) => *len,
+        }
+    }
+
+    fn output_len(&self) -> usize {
+        match self {
+            Self::Literal(len) => 1 + len,
+            Self::Repeat(_) => 2,
+        }
+    }
+}
+
+
+/// Compresses data using PackBits.
+///
+/// The output is as short as possible. The instructions are chosen by dynamic programming over all
+/// prefixes of the input:
+///
+/// * A literal instruction may end at a position if it starts at most 128 bytes before it; the
+///   best starting point is the minimum of a sliding window, which keeps the whole computation
+///   linear.
+/// * A repeat instruction is only considered at its maximum length (the length of the run of equal
+///   bytes, up to 128) since any shorter repeat leaves equal bytes behind that cost at least as
+///   much to encode afterwards.
+pub fn pack_bits(bytes: &[u8]) -> Vec<u8> {
+    // run_lengths[i] = how often bytes[i] repeats starting at i (capped at MAX_RUN)
+    let mut run_lengths = vec![0usize; bytes.len()];
+    for i in (0..bytes.len()).rev() {
+        run_lengths[i] = if i + 1 < bytes.len() && bytes[i] == bytes[i + 1] {
+            (run_lengths[i + 1] + 1).min(MAX_RUN)
+        } else {
+            1
+        };
+    }
+
+    // best[i] = (shortest output for bytes[..i], last instruction of that output)
+    let mut best: Vec<(usize, Option<Instruction>)> = vec![(usize::MAX, None); bytes.len() + 1];
+    best[0] = (0, None);
+
+    // candidate starting points of a literal ending at the current position, ordered by position,
+    // with strictly increasing best[start] - start (the cost of a literal from start to end is
+    // best[start] - start + 1 + end)
+    let mut literal_starts: VecDeque<usize> = VecDeque::with_capacity(MAX_RUN + 1);
+
+    for end in 0..=bytes.len() {
+        if end > 0 {
+            // literal instructions ending here
+            while literal_starts.front().is_some_and(|start| end - start > MAX_RUN) {
+                literal_starts.pop_front();
+            }
+            let start = *literal_starts.front().unwrap();
+            let literal = Instruction::Literal(end - start);
+            let literal_cost = best[start].0 + literal.output_len();
+            if literal_cost < best[end].0 {
+                best[end] = (literal_cost, Some(literal));
+            }
+        }
+
+        if end == bytes.len() {
+            break;
+        }
+
+        // this position is now final; it may start a literal...
+        while literal_starts.back().is_some_and(|start| best[*start].0 + end >= best[end].0 + start) {
+            literal_starts.pop_back();
+        }
+        literal_starts.push_back(end);
+
+        // ... or a repeat
+        let run_length = run_lengths[end];
+        if run_length >= 2 {
+            let repeat = Instruction::Repeat(run_length);
+            let repeat_cost = best[end].0 + repeat.output_len();
+            if repeat_cost < best[end + run_length].0 {
+                best[end + run_length] = (repeat_cost, Some(repeat));
+            }
+        }
+    }
+
+    // walk back from the end to find the chosen instructions
+    let mut instructions = Vec::new();
+    let mut pos = bytes.len();
+    while pos > 0 {
+        let instruction = best[pos].1
+            .expect("no instruction leads to this position");
+        instructions.push(instruction);
+        pos -= instruction.input_len();
+    }
+    instructions.reverse();
+
+    let mut ret = Vec::with_capacity(best[bytes.len()].0);
+    let mut pos = 0;
+    for instruction in instructions {
+        match instruction {
+            Instruction::Literal(len) => {
+                let verbatim_byte_i8: i8 = (len - 1).try_into().unwrap();
+                ret.push(verbatim_byte_i8.to_ne_bytes()[0]);
+                ret.extend(&bytes[pos..pos+len]);
+            },
+            Instruction::Repeat(len) => {
+                let repeat_byte_i16: i16 = 1 - i16::try_from(len).unwrap();
+                let repeat_byte_i8: i8 = repeat_byte_i16.try_into().unwrap();
+                ret.push(repeat_byte_i8.to_ne_bytes()[0]);
+                ret.push(bytes[pos]);
+            },
+        }
+        pos += instruction.input_len();
+    }
+    ret
+}
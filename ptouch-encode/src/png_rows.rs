@@ -0,0 +1,178 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use ptouch_decode::png_settings::PngSettings;
+use ptouch_decode::render::PIXEL_RED;
+
+
+/// Reads the settings that `ptouch-decode` stored in the text chunks of a PNG file.
+///
+/// Images without such text chunks return empty settings.
+pub fn read_png_settings(png_path: &Path) -> PngSettings {
+    let f = File::open(png_path)
+        .expect("failed to open PNG file");
+    let dec = png::Decoder::new(BufReader::new(f));
+    let reader = dec.read_info()
+        .expect("failed to decode PNG file");
+    png_settings(reader.info())
+}
+
+fn png_settings(info: &png::Info) -> PngSettings {
+    let utf8_texts: Vec<(&str, String)> = info.utf8_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), chunk.get_text().expect("failed to decode PNG text chunk")))
+        .collect();
+    let chunks = info.uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str()))
+        .chain(utf8_texts.iter().map(|(keyword, text)| (*keyword, text.as_str())));
+    match PngSettings::from_text_chunks(chunks) {
+        Ok(settings) => settings,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+
+/// What is printed for a pixel.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+enum Ink {
+    Blank,
+    Black,
+    Red,
+}
+
+
+/// Reads the rows of a black-and-white PNG file one at a time.
+///
+/// The image is either 1-bit grayscale or has a palette of black and white. Images for two-colour
+/// printing may also contain red. Images rendered by `ptouch-decode` may also contain other colors
+/// outside of their pages, which are read as blank.
+pub struct PngRowReader {
+    reader: png::Reader<BufReader<File>>,
+    width: u32,
+    height: u32,
+    bits_per_pixel: usize,
+
+    /// What is printed for each sample value.
+    inks: Vec<Ink>,
+
+    settings: PngSettings,
+    buf: Vec<u8>,
+}
+impl PngRowReader {
+    pub fn open(png_path: &Path) -> Self {
+        Self::open_with_colors(png_path, false)
+    }
+
+    /// Opens an image for two-colour printing, whose palette may also contain red (`#FF0000`).
+    pub fn open_two_color(png_path: &Path) -> Self {
+        Self::open_with_colors(png_path, true)
+    }
+
+    /// Opens an image like [`open_two_color`](Self::open_two_color) if `two_color` is true and like
+    /// [`open`](Self::open) otherwise.
+    pub fn open_with_colors(png_path: &Path, two_color: bool) -> Self {
+        let f = File::open(png_path)
+            .expect("failed to open PNG file");
+        let f_buf = BufReader::new(f);
+        let dec = png::Decoder::new(f_buf);
+        let reader = dec.read_info()
+            .expect("failed to decode PNG file");
+        let width = reader.info().width;
+        let height = reader.info().height;
+        let settings = png_settings(reader.info());
+        let is_rendering = settings.pages.is_some();
+        let bit_depth = reader.info().bit_depth;
+        let is_indexed = reader.info().color_type == png::ColorType::Indexed;
+        if bit_depth != png::BitDepth::One && !((is_rendering || two_color) && is_indexed) {
+            panic!("PNG bit depth is not 1");
+        }
+        let inks = match reader.info().color_type {
+            png::ColorType::Grayscale => {
+                // PNG: 1 = white, 0 = black
+                // P-Touch: 0 = no marker, 1 = marker
+                vec![Ink::Black, Ink::Blank]
+            },
+            png::ColorType::Indexed => {
+                let palette = reader.info().palette.as_ref()
+                    .expect("image does not have a palette");
+                if palette.len() != 6 && !is_rendering && !two_color {
+                    panic!("image's palette has {} entries; expected 6 (2xRGB)", palette.len());
+                }
+                palette
+                    .chunks_exact(3)
+                    .enumerate()
+                    .map(|(index, color)| match color {
+                        [0x00, 0x00, 0x00] => Ink::Black,
+                        [0xFF, 0xFF, 0xFF] => Ink::Blank,
+                        // renderings draw red ink in a color of its own, since their print command
+                        // lines are red
+                        _ if is_rendering && two_color && index == usize::from(PIXEL_RED) => Ink::Red,
+                        _ if is_rendering => Ink::Blank,
+                        [0xFF, 0x00, 0x00] if two_color => Ink::Red,
+                        _ if two_color => panic!("image's palette contains other colors than full black, full white and full red"),
+                        _ => panic!("image's palette contains other colors than full black and full white"),
+                    })
+                    .collect()
+            },
+            ct => panic!("image has invalid color type {:?}", ct),
+        };
+        let ols = reader.output_line_size(width)
+            .expect("failed to obtain output line size");
+
+        Self {
+            reader,
+            width,
+            height,
+            bits_per_pixel: bit_depth as usize,
+            inks,
+            settings,
+            buf: vec![0u8; ols],
+        }
+    }
+
+    pub fn width(&self) -> usize { self.width.try_into().unwrap() }
+    pub fn height(&self) -> usize { self.height.try_into().unwrap() }
+
+    /// The settings stored in the text chunks of the image.
+    pub fn settings(&self) -> &PngSettings { &self.settings }
+
+    /// Reads the next row and returns its pixels (`true` being a marker), or `None` once all rows
+    /// have been read.
+    ///
+    /// Red pixels of two-colour images are read as blank.
+    pub fn read_row(&mut self) -> Option<impl Iterator<Item = bool> + '_> {
+        let inks = self.read_inks()?;
+        Some(inks.map(|ink| ink == Ink::Black))
+    }
+
+    /// Reads the next row of a two-colour image and returns whether each pixel is black and
+    /// whether it is red, or `None` once all rows have been read.
+    pub fn read_two_color_row(&mut self) -> Option<impl Iterator<Item = (bool, bool)> + '_> {
+        let inks = self.read_inks()?;
+        Some(inks.map(|ink| (ink == Ink::Black, ink == Ink::Red)))
+    }
+
+    fn read_inks(&mut self) -> Option<impl Iterator<Item = Ink> + '_> {
+        let row_opt = self.reader.read_row(&mut self.buf)
+            .expect("failed to read row");
+        row_opt?;
+
+        let width = self.width();
+        let bits_per_pixel = self.bits_per_pixel;
+        let inks = &self.inks;
+        let pixels = self.buf
+            // turn the row into samples
+            .iter()
+            .flat_map(move |byte| (0..8 / bits_per_pixel).map(move |index| {
+                let shift = 8 - bits_per_pixel * (index + 1);
+                usize::from(byte >> shift) & ((1 << bits_per_pixel) - 1)
+            }))
+            // take only what you need from it
+            .take(width)
+            // look up what is printed for the sample
+            .map(move |sample| inks.get(sample).copied().unwrap_or(Ink::Blank));
+        Some(pixels)
+    }
+}
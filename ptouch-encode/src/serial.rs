@@ -0,0 +1,122 @@
+use std::fmt;
+use std::num::NonZeroU64;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+
+
+/// A range of serial numbers, given as `START..END` (exclusive) or `START..=END` (inclusive).
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SerialRange {
+    pub first: u64,
+    pub last: u64,
+}
+impl SerialRange {
+    /// Returns the serial numbers in this range, advancing by `step` each time.
+    pub fn values(&self, step: NonZeroU64) -> impl Iterator<Item = u64> {
+        (self.first..=self.last).step_by(step.get().try_into().unwrap())
+    }
+}
+impl FromStr for SerialRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start_str, end_str, inclusive) = if let Some((start, end)) = s.split_once("..=") {
+            (start, end, true)
+        } else if let Some((start, end)) = s.split_once("..") {
+            (start, end, false)
+        } else {
+            return Err(format!("serial range {:?} is neither START..END nor START..=END", s));
+        };
+        let first: u64 = start_str.parse()
+            .map_err(|e| format!("invalid range start {:?}: {}", start_str, e))?;
+        let end: u64 = end_str.parse()
+            .map_err(|e| format!("invalid range end {:?}: {}", end_str, e))?;
+        let last = if inclusive {
+            end
+        } else if end > first {
+            end - 1
+        } else {
+            return Err(format!("serial range {:?} is empty", s));
+        };
+        if last < first {
+            return Err(format!("serial range {:?} is empty", s));
+        }
+        Ok(Self { first, last })
+    }
+}
+impl fmt::Display for SerialRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..={}", self.first, self.last)
+    }
+}
+
+
+/// The check digit algorithm appended to each serial number.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, ValueEnum)]
+pub enum Checksum {
+    /// No check digit.
+    #[default] None,
+
+    /// Luhn algorithm (as used by payment card numbers).
+    Luhn,
+
+    /// Modulo 10 with alternating weights 3 and 1 (as used by EAN/UPC/GTIN).
+    Mod10,
+}
+impl Checksum {
+    /// Calculates the check digit for the given string of decimal digits.
+    ///
+    /// Returns `None` if no check digit is to be appended.
+    pub fn check_digit(&self, digits: &str) -> Option<u8> {
+        // both algorithms weigh the digits starting from the rightmost one,
+        // which is the one next to the check digit
+        let values = digits
+            .bytes()
+            .rev()
+            .map(|b| {
+                assert!(b.is_ascii_digit());
+                u32::from(b - b'0')
+            });
+        match self {
+            Self::None => None,
+            Self::Luhn => {
+                let sum: u32 = values
+                    .enumerate()
+                    .map(|(i, value)| if i % 2 == 0 {
+                        let doubled = 2 * value;
+                        if doubled > 9 { doubled - 9 } else { doubled }
+                    } else {
+                        value
+                    })
+                    .sum();
+                Some(((10 - (sum % 10)) % 10).try_into().unwrap())
+            },
+            Self::Mod10 => {
+                let sum: u32 = values
+                    .enumerate()
+                    .map(|(i, value)| if i % 2 == 0 { 3 * value } else { value })
+                    .sum();
+                Some(((10 - (sum % 10)) % 10).try_into().unwrap())
+            },
+        }
+    }
+}
+
+
+/// How serial numbers are turned into label text.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SerialFormat {
+    pub prefix: String,
+    pub suffix: String,
+    pub min_digits: usize,
+    pub checksum: Checksum,
+}
+impl SerialFormat {
+    pub fn format(&self, value: u64) -> String {
+        let mut digits = format!("{:0width$}", value, width = self.min_digits);
+        if let Some(check_digit) = self.checksum.check_digit(&digits) {
+            digits.push(char::from(b'0' + check_digit));
+        }
+        format!("{}{}{}", self.prefix, digits, self.suffix)
+    }
+}
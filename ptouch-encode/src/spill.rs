@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+
+
+/// The largest record (row data plus length suffix) that can be stored.
+const MAX_RECORD_LENGTH: usize = (u16::MAX as usize) + 2;
+
+/// How much of the spill file is read at once when reading it backwards.
+const READ_WINDOW: usize = 4 * MAX_RECORD_LENGTH;
+
+
+/// Temporarily stores the raster lines of a page in an anonymous file so that they can be read
+/// back in reverse order without keeping them in memory.
+///
+/// Each row is stored as its data followed by its length as a little-endian `u16`, which allows
+/// walking the file from its end.
+pub struct RowSpill {
+    writer: BufWriter<File>,
+    row_count: usize,
+}
+impl RowSpill {
+    pub fn new() -> Self {
+        let file = tempfile::tempfile()
+            .expect("failed to create temporary file");
+        Self {
+            writer: BufWriter::new(file),
+            row_count: 0,
+        }
+    }
+
+    pub fn push(&mut self, row: &[u8]) {
+        let length: u16 = row.len().try_into()
+            .expect("row is too long");
+        self.writer.write_all(row)
+            .expect("failed to write row to temporary file");
+        self.writer.write_all(&length.to_le_bytes())
+            .expect("failed to write row to temporary file");
+        self.row_count += 1;
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.row_count
+    }
+
+    /// Returns the stored rows, last row first.
+    pub fn into_reversed(self) -> ReversedRows {
+        let mut file = self.writer.into_inner()
+            .expect("failed to flush temporary file");
+        let end = file.seek(SeekFrom::End(0))
+            .expect("failed to seek in temporary file");
+        ReversedRows {
+            file,
+            window: Vec::with_capacity(READ_WINDOW),
+            window_start: end,
+            remaining_rows: self.row_count,
+        }
+    }
+}
+impl Default for RowSpill {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Reads the rows stored in a [`RowSpill`] from last to first.
+pub struct ReversedRows {
+    file: File,
+
+    /// The part of the file just before the rows that have already been returned.
+    window: Vec<u8>,
+
+    /// The offset within the file at which `window` begins.
+    window_start: u64,
+
+    remaining_rows: usize,
+}
+impl ReversedRows {
+    /// Ensures that the window contains at least `count` bytes, reading more of the file from
+    /// before the window if necessary.
+    fn ensure_window(&mut self, count: usize) {
+        if self.window.len() >= count {
+            return;
+        }
+        let read_length = (READ_WINDOW as u64).min(self.window_start);
+        let new_start = self.window_start - read_length;
+        let mut new_window = vec![0u8; read_length.try_into().unwrap()];
+        self.file.seek(SeekFrom::Start(new_start))
+            .expect("failed to seek in temporary file");
+        self.file.read_exact(&mut new_window)
+            .expect("failed to read from temporary file");
+        new_window.extend_from_slice(&self.window);
+        self.window = new_window;
+        self.window_start = new_start;
+        assert!(self.window.len() >= count, "temporary file is truncated");
+    }
+}
+impl Iterator for ReversedRows {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining_rows == 0 {
+            return None;
+        }
+
+        self.ensure_window(2);
+        let length_pos = self.window.len() - 2;
+        let length = usize::from(u16::from_le_bytes([self.window[length_pos], self.window[length_pos + 1]]));
+        self.window.truncate(length_pos);
+
+        self.ensure_window(length);
+        let row = self.window.split_off(self.window.len() - length);
+
+        self.remaining_rows -= 1;
+        Some(row)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining_rows, Some(self.remaining_rows))
+    }
+}
@@ -0,0 +1,752 @@
+//! Encodes images with `ptouch-encode`, decodes the resulting print jobs again and checks that the
+//! pixels and settings survive.
+
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use ptouch_common::model::Model;
+use ptouch_decode::bundle::{JobBundle, SETTINGS_FILE_NAME};
+use ptouch_decode::command::{self, CommandReader};
+use ptouch_decode::diff::{self, DecodedJob};
+use ptouch_decode::job::{JobSettings, JobState, Limits};
+use ptouch_decode::lint::{LintProblem, Linter};
+use ptouch_encode::pack_bits::pack_bits;
+
+
+/// An image as rows of pixels, `true` being a marker.
+type Image = Vec<Vec<bool>>;
+
+
+const LIMITS: Limits = Limits {
+    max_width: 4096,
+    max_rows_per_page: 1_000_000,
+    max_pages: 1000,
+    max_decompressed_bytes: 256 * 1024 * 1024,
+};
+
+
+/// A small xorshift generator, so that the random images are the same in every run.
+struct Random(u64);
+impl Random {
+    fn next_bool(&mut self) -> bool {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 & 1 != 0
+    }
+}
+
+fn random_image(width: usize, height: usize, seed: u64) -> Image {
+    let mut random = Random(seed);
+    (0..height)
+        .map(|_| (0..width).map(|_| random.next_bool()).collect())
+        .collect()
+}
+
+fn filled_image(width: usize, height: usize, value: bool) -> Image {
+    vec![vec![value; width]; height]
+}
+
+fn write_png(path: &Path, image: &Image) {
+    let width = image[0].len();
+    let file = File::create(path)
+        .expect("failed to create PNG file");
+    let mut enc = png::Encoder::new(BufWriter::new(file), width.try_into().unwrap(), image.len().try_into().unwrap());
+    enc.set_color(png::ColorType::Grayscale);
+    enc.set_depth(png::BitDepth::One);
+    let mut wr = enc.write_header()
+        .expect("failed to write PNG header");
+    let mut data = Vec::new();
+    for row in image {
+        let mut packed = vec![0xFFu8; width.div_ceil(8)];
+        for (x, pixel) in row.iter().enumerate() {
+            if *pixel {
+                // black is 0 in grayscale PNGs
+                packed[x / 8] &= !(0x80 >> (x % 8));
+            }
+        }
+        data.extend(packed);
+    }
+    wr.write_image_data(&data)
+        .expect("failed to write PNG data");
+}
+
+fn encode(args: &[String], images: &[Image], dir: &Path) -> PathBuf {
+    let mut png_paths = Vec::new();
+    for (i, image) in images.iter().enumerate() {
+        let png_path = dir.join(format!("page{}.png", i));
+        write_png(&png_path, image);
+        png_paths.push(png_path);
+    }
+    let job_path = dir.join("job.prn");
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .args(args)
+        .args(&png_paths)
+        .arg(&job_path)
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success(), "ptouch-encode failed with arguments {:?}", args);
+    job_path
+}
+
+/// Decodes a print job into its settings and pages, each of which is a list of raster lines in the
+/// order in which they were sent.
+fn decode(job_path: &Path) -> (JobSettings, Vec<Vec<Vec<u8>>>) {
+    let data = std::fs::read(job_path)
+        .expect("failed to read print job");
+    let mut job = JobState::new(LIMITS);
+    let mut pages = Vec::new();
+    let mut rows = Vec::new();
+    for command in CommandReader::new(data.as_slice()).expect("invalid print job header") {
+        let command = command.expect("invalid command");
+        job.apply(&command).expect("command rejected");
+        match command {
+            command::Command::Raster(data) => rows.push(data),
+            command::Command::ZeroRaster => rows.push(Vec::new()),
+            command::Command::Print|command::Command::PrintFeed => pages.push(std::mem::take(&mut rows)),
+            _ => {},
+        }
+    }
+    assert!(rows.is_empty(), "raster lines after the last print command");
+    (*job.settings(), pages)
+}
+
+/// Checks a print job against the constraints of the given model and returns the problems found.
+fn lint(job_path: &Path, model: Model) -> Vec<LintProblem> {
+    let data = std::fs::read(job_path)
+        .expect("failed to read print job");
+    let mut linter = Linter::new(model);
+    for command in CommandReader::new(data.as_slice()).expect("invalid print job header") {
+        linter.observe(&command.expect("invalid command"));
+    }
+    linter.finish()
+}
+
+/// Extracts `width` pixels starting at `offset` from each raster line and returns them in image
+/// order.
+fn page_pixels(rows: &[Vec<u8>], offset: usize, width: usize, reversed: bool) -> Image {
+    let mut image: Image = rows
+        .iter()
+        .map(|row| (offset..offset+width)
+            .map(|x| row.get(x / 8).is_some_and(|b| b & (0x80 >> (x % 8)) != 0))
+            .collect()
+        )
+        .collect();
+    if reversed {
+        image.reverse();
+    }
+    image
+}
+
+/// Checks that all pixels outside the `width` pixels starting at `offset` are blank.
+fn padding_is_blank(rows: &[Vec<u8>], offset: usize, width: usize) -> bool {
+    rows.iter().all(|row| {
+        (0..row.len()*8)
+            .filter(|x| *x < offset || *x >= offset + width)
+            .all(|x| row[x / 8] & (0x80 >> (x % 8)) == 0)
+    })
+}
+
+
+#[test]
+fn pack_bits_round_trips_long_runs() {
+    let mut inputs = vec![
+        vec![0x00; 129],
+        vec![0xFF; 300],
+        (0..=255u8).cycle().take(400).collect::<Vec<u8>>(),
+        Vec::new(),
+        vec![0xAA],
+    ];
+    let mut mixed = vec![0x11; 200];
+    mixed.extend((0..200u8).map(|i| i.wrapping_mul(7)));
+    mixed.extend(vec![0x22; 129]);
+    inputs.push(mixed);
+
+    for input in inputs {
+        let packed = pack_bits(&input);
+        let unpacked = command::unpack_bits(&packed)
+            .expect("failed to unpack packed data");
+        assert_eq!(unpacked, input);
+    }
+}
+
+#[test]
+fn pack_bits_output_is_optimal() {
+    // three repeats beat one long literal
+    assert_eq!(pack_bits(&[1, 1, 2, 2, 3, 3]).len(), 6);
+    // a short repeat within a literal is not worth splitting the literal for
+    assert_eq!(pack_bits(&[1, 2, 2, 3]).len(), 5);
+    // 129 equal bytes need a repeat and a single-byte literal
+    assert_eq!(pack_bits(&[7; 129]).len(), 4);
+    assert_eq!(pack_bits(&[]).len(), 0);
+}
+
+#[test]
+fn unpack_bits_rejects_truncated_data() {
+    assert!(command::unpack_bits(&[0x7F]).is_err());
+    assert!(command::unpack_bits(&[0x02, 0x01, 0x02]).is_err());
+    assert!(command::unpack_bits(&[0x81]).is_err());
+    assert_eq!(command::unpack_bits(&[0x80]).unwrap(), Vec::<u8>::new());
+    assert_eq!(command::unpack_bits(&[0x81, 0x55]).unwrap(), vec![0x55; 128]);
+}
+
+#[test]
+fn pixels_and_settings_survive_every_flag_combination() {
+    const FLAGS: [&str; 10] = [
+        "--auto-cut",
+        "--mirror-print",
+        "--draft",
+        "--half-cut",
+        "--no-chain",
+        "--special-tape",
+        "--hi-res",
+        "--dont-clear-print-buffer",
+        "--last-page-2",
+        "--no-reverse",
+    ];
+
+    let dir = tempfile::tempdir().unwrap();
+    let images = vec![
+        random_image(128, 40, 0x1234_5678),
+        filled_image(128, 10, false),
+        filled_image(128, 20, true),
+        random_image(128, 33, 0x9ABC_DEF0),
+    ];
+
+    for mask in 0..(1u32 << FLAGS.len()) {
+        let flag_set = |i: usize| mask & (1 << i) != 0;
+        let cut_every = mask % 5;
+        let feed = mask * 3;
+        let mut args = vec![
+            "--width-mm".to_owned(), "24".to_owned(),
+            "--cut-every".to_owned(), cut_every.to_string(),
+            "--feed".to_owned(), feed.to_string(),
+        ];
+        for (i, flag) in FLAGS.iter().enumerate() {
+            if flag_set(i) {
+                args.push((*flag).to_owned());
+            }
+        }
+
+        let job_path = encode(&args, &images, dir.path());
+        let (settings, pages) = decode(&job_path);
+
+        assert_eq!(settings.auto_cut, Some(flag_set(0)), "{:?}", args);
+        assert_eq!(settings.mirror_print, Some(flag_set(1)), "{:?}", args);
+        assert_eq!(settings.draft, Some(flag_set(2)), "{:?}", args);
+        assert_eq!(settings.half_cut, Some(flag_set(3)), "{:?}", args);
+        assert_eq!(settings.no_chain, Some(flag_set(4)), "{:?}", args);
+        assert_eq!(settings.special_tape, Some(flag_set(5)), "{:?}", args);
+        assert_eq!(settings.hi_res, Some(flag_set(6)), "{:?}", args);
+        assert_eq!(settings.dont_clean_print_buffer, Some(flag_set(7)), "{:?}", args);
+        assert_eq!(settings.cut_each_n_labels, Some(u8::try_from(cut_every).unwrap()), "{:?}", args);
+        assert_eq!(settings.feed_amount, Some(u16::try_from(feed).unwrap()), "{:?}", args);
+        assert_eq!(settings.media_width, Some(24), "{:?}", args);
+        assert_eq!(settings.printer_recovery, Some(true), "{:?}", args);
+        assert_eq!(settings.raster_number, Some(33), "{:?}", args);
+
+        assert_eq!(pages.len(), images.len(), "{:?}", args);
+        let reversed = !flag_set(9);
+        for (page, image) in pages.iter().zip(&images) {
+            assert_eq!(&page_pixels(page, 0, 128, reversed), image, "{:?}", args);
+        }
+    }
+}
+
+#[test]
+fn mirrored_trimmed_and_padded_pages_keep_their_pixels() {
+    const FLAGS: [&str; 4] = ["--flip-h", "--flip-v", "--rotate=180", "--no-reverse"];
+
+    let dir = tempfile::tempdir().unwrap();
+    let content = random_image(64, 60, 0x0F0F_0F0F);
+    let mut image = filled_image(64, 5, false);
+    image.extend(content.iter().cloned());
+    image.extend(filled_image(64, 7, false));
+
+    for mask in 0..(1u32 << FLAGS.len()) {
+        let flag_set = |i: usize| mask & (1 << i) != 0;
+        let mut args: Vec<String> = ["-w", "12", "--trim", "--length-mm", "20", "--align", "start"]
+            .map(String::from)
+            .to_vec();
+        for (i, flag) in FLAGS.iter().enumerate() {
+            if flag_set(i) {
+                args.push((*flag).to_owned());
+            }
+        }
+
+        let job_path = encode(&args, std::slice::from_ref(&image), dir.path());
+        let (_settings, pages) = decode(&job_path);
+
+        // a rotation by 180° mirrors the image both ways
+        let mut expected = content.clone();
+        if flag_set(0) != flag_set(2) {
+            for row in &mut expected {
+                row.reverse();
+            }
+        }
+        if flag_set(1) != flag_set(2) {
+            expected.reverse();
+        }
+
+        // 20 mm at 180 dpi, starting with the content
+        assert_eq!(pages[0].len(), 142, "{:?}", args);
+        assert_eq!(page_pixels(&pages[0][..60], 0, 64, !flag_set(3)), expected, "{:?}", args);
+        assert!(padding_is_blank(&pages[0][60..], 0, 0), "{:?}", args);
+    }
+}
+
+#[test]
+fn every_compression_mode_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    let images = vec![
+        random_image(128, 30, 5),
+        filled_image(128, 5, true),
+    ];
+    for compression in ["pack-bits", "none", "auto"] {
+        let args = ["-w", "24", "--compression", compression].map(String::from);
+        let job_path = encode(&args, &images, dir.path());
+        let (_settings, pages) = decode(&job_path);
+
+        for (page, image) in pages.iter().zip(&images) {
+            assert_eq!(&page_pixels(page, 0, 128, true), image, "{:?}", args);
+        }
+        if compression == "none" {
+            // uncompressed lines are always complete
+            assert!(pages.iter().flatten().all(|row| row.len() == 16));
+        }
+    }
+}
+
+#[test]
+fn narrow_images_are_centered() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = random_image(37, 25, 42);
+    let args = ["-w", "12", "-x", "128"].map(String::from);
+    let job_path = encode(&args, std::slice::from_ref(&image), dir.path());
+    let (_settings, pages) = decode(&job_path);
+
+    // (128 - 37) / 2 = 45 blank pixels before the image
+    assert_eq!(page_pixels(&pages[0], 45, 37, true), image);
+    assert!(padding_is_blank(&pages[0], 45, 37));
+}
+
+#[test]
+fn long_runs_survive_wide_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut wide_random = random_image(2048, 5, 7);
+    wide_random[2] = vec![true; 2048];
+    let images = vec![
+        filled_image(2048, 3, true),
+        wide_random,
+        filled_image(2048, 4, false),
+    ];
+    let args = ["-w", "24", "--no-reverse"].map(String::from);
+    let job_path = encode(&args, &images, dir.path());
+    let (_settings, pages) = decode(&job_path);
+
+    assert_eq!(pages.len(), images.len());
+    for (page, image) in pages.iter().zip(&images) {
+        assert_eq!(&page_pixels(page, 0, 2048, false), image);
+    }
+}
+
+#[test]
+fn tube_jobs_place_the_image_within_the_printable_area() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = random_image(48, 200, 99);
+    let args = ["-t", "8.8"].map(String::from);
+    let job_path = encode(&args, std::slice::from_ref(&image), dir.path());
+    let (settings, pages) = decode(&job_path);
+
+    assert_eq!(settings.media_type, Some(0x11));
+    assert_eq!(settings.media_width, Some(9));
+    assert_eq!(settings.special_tape, Some(true));
+
+    // every line spans the 128 pins of the print head...
+    assert!(pages[0].iter().all(|row| row.len() == 16));
+
+    // ...and the 8.8 mm tube starts 40 pins into it
+    assert_eq!(page_pixels(&pages[0], 40, 48, true), image);
+    assert!(padding_is_blank(&pages[0], 40, 48));
+}
+
+#[test]
+fn tube_jobs_pass_linting() {
+    let dir = tempfile::tempdir().unwrap();
+    let job_path = dir.path().join("job.prn");
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .args(["--serial", "1..=2", "--tube", "8.8", "--trim"])
+        .arg(&job_path)
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success());
+
+    let problems = lint(&job_path, Model::PtE550W);
+    assert!(problems.is_empty(), "{:?}", problems);
+}
+
+#[test]
+fn short_tube_pages_are_padded_to_the_minimum_length() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = random_image(48, 20, 98);
+    let args = ["-t", "8.8"].map(String::from);
+    let job_path = encode(&args, std::slice::from_ref(&image), dir.path());
+    let (_settings, pages) = decode(&job_path);
+
+    // 25 mm at 180 dpi, with the image in the middle
+    assert_eq!(pages[0].len(), 177);
+    assert_eq!(page_pixels(&pages[0][79..99], 40, 48, true), image);
+    assert!(padding_is_blank(&pages[0][..79], 0, 0));
+    assert!(padding_is_blank(&pages[0][99..], 0, 0));
+
+    let problems = lint(&job_path, Model::PtE550W);
+    assert!(problems.is_empty(), "{:?}", problems);
+}
+
+#[test]
+fn preview_shows_the_raster_lines_margins_and_cuts_of_the_job() {
+    let dir = tempfile::tempdir().unwrap();
+    let images = vec![random_image(70, 30, 5), random_image(70, 30, 6)];
+    let args = ["-w", "12", "-x", "128", "-c", "-R", "-f", "7"].map(String::from);
+    let job_path = encode(&args, &images, dir.path());
+    let (_settings, pages) = decode(&job_path);
+
+    let preview_path = dir.path().join("preview.png");
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .args(&args)
+        .arg("--preview")
+        .arg(&preview_path)
+        .arg(dir.path().join("page0.png"))
+        .arg(dir.path().join("page1.png"))
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success(), "ptouch-encode --preview failed");
+
+    let dec = png::Decoder::new(std::io::BufReader::new(File::open(&preview_path).unwrap()));
+    let mut reader = dec.read_info().unwrap();
+    let pixel_dims = reader.info().pixel_dims.expect("no physical scale in preview");
+    // 180 dpi across the tape, 360 dpi along it
+    assert_eq!((pixel_dims.xppu, pixel_dims.yppu), (7087, 14173));
+    let mut buf = vec![0u8; reader.output_buffer_size().unwrap()];
+    let info = reader.next_frame(&mut buf).unwrap();
+    assert_eq!(info.bit_depth, png::BitDepth::Four);
+    let pixel = |x: usize, y: usize| (buf[y * info.line_size + x / 2] >> (4 - 4 * (x % 2))) & 0x0F;
+
+    // each page: 14 rows of margin (7 dots at high resolution), the raster lines, another margin,
+    // the print line and the cut line
+    let page_height = 14 + 30 + 14 + 2;
+    assert_eq!((info.width, info.height), (128, 2 * u32::try_from(page_height).unwrap()));
+    for (page_index, page) in pages.iter().enumerate() {
+        let top = page_index * page_height;
+        for y in (0..14).chain(44..58) {
+            assert_eq!(pixel(0, top + y), 4, "feed margin at row {} of page {}", y, page_index);
+        }
+        for (y, row) in page.iter().enumerate() {
+            for x in 0..128 {
+                let black = row.get(x / 8).is_some_and(|b| b & (0x80 >> (x % 8)) != 0);
+                assert_eq!(pixel(x, top + 14 + y), u8::from(black), "pixel {} of row {} of page {}", x, y, page_index);
+            }
+        }
+        let print_pixel = if page_index == 0 { 2 } else { 3 };
+        assert_eq!(pixel(0, top + 58), print_pixel, "print line of page {}", page_index);
+        assert_eq!(pixel(0, top + 59), 5, "cut line of page {}", page_index);
+    }
+}
+
+#[test]
+fn bundles_rebuild_equivalent_jobs() {
+    let dir = tempfile::tempdir().unwrap();
+    let images = vec![random_image(60, 25, 7), filled_image(60, 10, false), random_image(60, 40, 8)];
+    let args = ["-w", "24", "-x", "128", "-c", "-H", "-C", "-f", "14"].map(String::from);
+    let job_path = encode(&args, &images, dir.path());
+    let read_jobs = |path: &Path| {
+        let data = std::fs::read(path)
+            .expect("failed to read print job");
+        let commands = CommandReader::new(data.as_slice())
+            .expect("invalid print job header");
+        let mut jobs = DecodedJob::read_all(commands, LIMITS, false)
+            .expect("failed to decode print job");
+        assert_eq!(jobs.len(), 1);
+        jobs.remove(0)
+    };
+    let original = read_jobs(&job_path);
+
+    let bundle_dir = dir.path().join("bundle");
+    JobBundle::write(&original, &bundle_dir)
+        .expect("failed to write bundle");
+    let bundle = JobBundle::read(&bundle_dir)
+        .expect("failed to read bundle");
+    assert_eq!(bundle.pages.len(), 3);
+    assert_eq!(bundle.feed, Some(14));
+
+    // edit a setting as a user would
+    let settings_path = bundle_dir.join(SETTINGS_FILE_NAME);
+    let json = std::fs::read_to_string(&settings_path).unwrap();
+    assert!(json.contains("\"feed\": 14,"));
+    std::fs::write(&settings_path, json.replace("\"feed\": 14,", "\"feed\": 28,")).unwrap();
+
+    let rebuilt_path = dir.path().join("rebuilt.prn");
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .arg("--bundle")
+        .arg(&bundle_dir)
+        .arg(&rebuilt_path)
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success(), "ptouch-encode --bundle failed");
+    let rebuilt = read_jobs(&rebuilt_path);
+
+    let setting_differences: Vec<_> = diff::diff_settings(&original.settings, &rebuilt.settings)
+        .into_iter()
+        .map(|difference| difference.name)
+        .collect();
+    assert_eq!(setting_differences.len(), 1, "settings differ: {:?}", setting_differences);
+    assert_eq!(rebuilt.settings.feed_amount, Some(28));
+    assert_eq!(rebuilt.invalidate_length, original.invalidate_length);
+    assert_eq!(rebuilt.compression, original.compression);
+    assert_eq!(rebuilt.pages, original.pages);
+}
+
+#[test]
+fn blank_two_colour_pages_are_rebuilt_in_two_colours() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut job = vec![0x00; 350];
+    job.extend(b"\x1B@");
+    job.extend(b"\x1Bia\x01");
+    job.extend(b"\x1BiK\x01");
+    job.extend(b"M\x00");
+    for _ in 0..3 {
+        job.extend(b"w\x01\x00w\x02\x00");
+    }
+    job.push(0x1A);
+    let job_path = dir.path().join("job.prn");
+    std::fs::write(&job_path, &job).unwrap();
+
+    let commands = CommandReader::new(job.as_slice())
+        .expect("invalid print job header");
+    let decoded = DecodedJob::read_all(commands, LIMITS, false)
+        .expect("failed to decode print job");
+    let bundle_dir = dir.path().join("bundle");
+    let bundle = JobBundle::write(&decoded[0], &bundle_dir)
+        .expect("failed to write bundle");
+    assert_eq!(bundle.pages[0].image, None);
+    assert!(bundle.pages[0].two_color);
+
+    let rebuilt_path = dir.path().join("rebuilt.prn");
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .arg("--bundle")
+        .arg(&bundle_dir)
+        .arg(&rebuilt_path)
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success(), "ptouch-encode --bundle failed");
+    assert_eq!(std::fs::read(&rebuilt_path).unwrap(), job);
+}
+
+#[test]
+fn rendered_images_are_encoded_with_their_stored_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    let images = vec![random_image(70, 30, 9), random_image(70, 45, 10)];
+    let args = ["-w", "12", "-x", "128", "-c", "-H", "-R", "-C", "-e", "3", "-f", "7"].map(String::from);
+    let job_path = encode(&args, &images, dir.path());
+    let (settings, pages) = decode(&job_path);
+
+    // the preview is rendered like ptouch-decode does and stores the settings of the job
+    let preview_path = dir.path().join("preview.png");
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .args(&args)
+        .arg("--preview")
+        .arg(&preview_path)
+        .arg(dir.path().join("page0.png"))
+        .arg(dir.path().join("page1.png"))
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success(), "ptouch-encode --preview failed");
+
+    // no options: everything comes from the image
+    let rebuilt_path = dir.path().join("rebuilt.prn");
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .arg(&preview_path)
+        .arg(&rebuilt_path)
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success(), "ptouch-encode of the preview failed");
+    let (rebuilt_settings, rebuilt_pages) = decode(&rebuilt_path);
+    assert_eq!(rebuilt_settings, settings);
+    assert_eq!(rebuilt_pages, pages);
+
+    // options on the command line win over the stored settings
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .args(["-f", "20"])
+        .arg(&preview_path)
+        .arg(&rebuilt_path)
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success(), "ptouch-encode of the preview failed");
+    let (rebuilt_settings, _rebuilt_pages) = decode(&rebuilt_path);
+    assert_eq!(rebuilt_settings.feed_amount, Some(20));
+    assert_eq!(rebuilt_settings.half_cut, Some(true));
+}
+
+#[test]
+fn three_colour_images_are_sent_as_black_and_red_planes() {
+    let dir = tempfile::tempdir().unwrap();
+    let black = random_image(128, 30, 11);
+    let red = random_image(128, 30, 12);
+
+    // an indexed image with white, black and red, where black wins over red
+    let png_path = dir.path().join("two-colour.png");
+    let file = File::create(&png_path)
+        .expect("failed to create PNG file");
+    let mut enc = png::Encoder::new(BufWriter::new(file), 128, 30);
+    enc.set_color(png::ColorType::Indexed);
+    enc.set_depth(png::BitDepth::Eight);
+    enc.set_palette(vec![0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00]);
+    let mut wr = enc.write_header()
+        .expect("failed to write PNG header");
+    let data: Vec<u8> = black.iter().flatten()
+        .zip(red.iter().flatten())
+        .map(|(black, red)| if *black { 1 } else if *red { 2 } else { 0 })
+        .collect();
+    wr.write_image_data(&data)
+        .expect("failed to write PNG data");
+    wr.finish()
+        .expect("failed to finish PNG file");
+
+    let job_path = dir.path().join("job.prn");
+    let status = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .args(["-M", "QL-800", "-w", "62", "--two-colour"])
+        .arg(&png_path)
+        .arg(&job_path)
+        .status()
+        .expect("failed to run ptouch-encode");
+    assert!(status.success(), "ptouch-encode --two-colour failed");
+
+    let data = std::fs::read(&job_path)
+        .expect("failed to read print job");
+    let mut job = JobState::new(LIMITS);
+    let mut black_rows = Vec::new();
+    let mut red_rows = Vec::new();
+    for command in CommandReader::new(data.as_slice()).expect("invalid print job header") {
+        let command = command.expect("invalid command");
+        job.apply(&command).expect("command rejected");
+        match command {
+            command::Command::TwoColorRaster { black, red } => {
+                black_rows.push(black);
+                red_rows.push(red);
+            },
+            command::Command::Raster(_)|command::Command::ZeroRaster => panic!("black-only raster line in two-colour job"),
+            _ => {},
+        }
+    }
+
+    // the two-colour flag shares its bit with draft mode
+    assert_eq!(job.settings().draft, Some(true));
+    let red_without_black: Image = black.iter()
+        .zip(&red)
+        .map(|(black_row, red_row)| black_row.iter().zip(red_row).map(|(black, red)| *red && !*black).collect())
+        .collect();
+    // centered within the 696 printable pins after 12 margin pins
+    let offset = 12 + (696 - 128) / 2;
+    assert_eq!(page_pixels(&black_rows, offset, 128, true), black);
+    assert_eq!(page_pixels(&red_rows, offset, 128, true), red_without_black);
+}
+
+#[test]
+fn two_colour_printing_is_refused_on_p_touch_models() {
+    let dir = tempfile::tempdir().unwrap();
+    let png_path = dir.path().join("page.png");
+    write_png(&png_path, &random_image(64, 30, 15));
+    let job_path = dir.path().join("job.prn");
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .args(["-M", "PT-E550W", "-w", "24", "--two-colour"])
+        .arg(&png_path)
+        .arg(&job_path)
+        .output()
+        .expect("failed to run ptouch-encode");
+    assert_eq!(output.status.code(), Some(2), "ptouch-encode --two-colour succeeded on a P-touch model");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("the PT-E550W cannot print in two colours"));
+    assert!(!job_path.exists());
+}
+
+#[test]
+fn a_serial_step_of_zero_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let job_path = dir.path().join("job.prn");
+    let output = Command::new(env!("CARGO_BIN_EXE_ptouch-encode"))
+        .args(["-w", "12", "--serial", "1..3", "--serial-step", "0"])
+        .arg(&job_path)
+        .output()
+        .expect("failed to run ptouch-encode");
+    assert_eq!(output.status.code(), Some(2), "ptouch-encode --serial-step 0 succeeded");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--serial-step"));
+    assert!(!job_path.exists());
+}
+
+/// Decodes a print job for a QL model, checking that every raster line is sent using `g`.
+fn decode_ql(job_path: &Path) -> (JobSettings, Vec<Vec<Vec<u8>>>) {
+    let data = std::fs::read(job_path)
+        .expect("failed to read print job");
+    let mut job = JobState::new(LIMITS);
+    let mut pages = Vec::new();
+    let mut rows = Vec::new();
+    for command in CommandReader::new(data.as_slice()).expect("invalid print job header") {
+        let command = command.expect("invalid command");
+        job.apply(&command).expect("command rejected");
+        match command {
+            command::Command::QlRaster(data) => rows.push(data),
+            command::Command::Raster(_)|command::Command::ZeroRaster => panic!("P-touch raster line in QL job"),
+            command::Command::Print|command::Command::PrintFeed => pages.push(std::mem::take(&mut rows)),
+            _ => {},
+        }
+    }
+    (*job.settings(), pages)
+}
+
+#[test]
+fn ql_jobs_span_the_print_head_on_continuous_rolls() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = random_image(200, 160, 13);
+    let args = ["-M", "QL-800", "-w", "62", "--compression", "none"].map(String::from);
+    let job_path = encode(&args, std::slice::from_ref(&image), dir.path());
+
+    let (settings, pages) = decode_ql(&job_path);
+    assert_eq!(settings.media_type, Some(0x0A));
+    assert_eq!(settings.media_width, Some(62));
+    assert_eq!(settings.feed_amount, Some(35));
+    assert_eq!(pages.len(), 1);
+    assert!(pages[0].iter().all(|row| row.len() == 90));
+
+    // centered within the 696 printable pins after 12 margin pins
+    let offset = 12 + (696 - 200) / 2;
+    assert_eq!(page_pixels(&pages[0], offset, 200, true), image);
+    assert!(padding_is_blank(&pages[0], offset, 200));
+}
+
+#[test]
+fn ql_jobs_fill_die_cut_labels() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = random_image(300, 100, 14);
+    let args = ["-M", "QL-810W", "--die-cut", "29x42", "--no-reverse"].map(String::from);
+    let job_path = encode(&args, &[image.clone(), image.clone()], dir.path());
+
+    let (settings, pages) = decode_ql(&job_path);
+    assert_eq!(settings.media_type, Some(0x0B));
+    assert_eq!(settings.media_width, Some(29));
+    assert_eq!(settings.media_length, Some(42));
+    assert_eq!(settings.feed_amount, Some(0));
+    assert_eq!(pages.len(), 2);
+    for rows in &pages {
+        // the page is padded to the 425 lines of a label, with the image in the middle
+        assert_eq!(rows.len(), 425);
+        assert!(rows.iter().all(|row| row.len() == 90));
+        let offset = 6 + (306 - 300) / 2;
+        let first_row = (425 - 100) / 2;
+        assert_eq!(page_pixels(&rows[first_row..first_row+100], offset, 300, false), image);
+        assert!(padding_is_blank(&rows[first_row..first_row+100], offset, 300));
+        assert!(padding_is_blank(&rows[..first_row], 0, 0));
+        assert!(padding_is_blank(&rows[first_row+100..], 0, 0));
+    }
+}